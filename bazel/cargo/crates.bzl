@@ -31,6 +31,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//bazel/cargo/remote:BUILD.autocfg-1.0.1.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__bencher__0_1_5",
+        url = "https://crates.io/api/v1/crates/bencher/0.1.5/download",
+        type = "tar.gz",
+        sha256 = "7dfdb4953a096c551ce9ace855a604d702e6e62d77fac690575ae347571717f5",
+        strip_prefix = "bencher-0.1.5",
+        build_file = Label("//bazel/cargo/remote:BUILD.bencher-0.1.5.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__bitflags__1_2_1",
@@ -213,12 +223,12 @@ def raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "raze__proc_macro2__1_0_26",
-        url = "https://crates.io/api/v1/crates/proc-macro2/1.0.26/download",
+        name = "raze__proc_macro2__1_0_107",
+        url = "https://crates.io/api/v1/crates/proc-macro2/1.0.107/download",
         type = "tar.gz",
-        sha256 = "a152013215dca273577e18d2bf00fa862b89b24169fb78c4c95aeb07992c9cec",
-        strip_prefix = "proc-macro2-1.0.26",
-        build_file = Label("//bazel/cargo/remote:BUILD.proc-macro2-1.0.26.bazel"),
+        sha256 = "985e7ec9bb745e6ce6535b544d84d6cd6f7ad8bd711c398938ae983b91a766d9",
+        strip_prefix = "proc-macro2-1.0.107",
+        build_file = Label("//bazel/cargo/remote:BUILD.proc-macro2-1.0.107.bazel"),
     )
 
     maybe(
@@ -233,12 +243,12 @@ def raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "raze__quote__1_0_9",
-        url = "https://crates.io/api/v1/crates/quote/1.0.9/download",
+        name = "raze__quote__1_0_47",
+        url = "https://crates.io/api/v1/crates/quote/1.0.47/download",
         type = "tar.gz",
-        sha256 = "c3d0b9745dc2debf507c8422de05d7226cc1f0644216dfdfead988f9b1ab32a7",
-        strip_prefix = "quote-1.0.9",
-        build_file = Label("//bazel/cargo/remote:BUILD.quote-1.0.9.bazel"),
+        sha256 = "1fbf4db142a473a8d80c26bbf18454ed458bf8d26c8219c331daecfdbd079001",
+        strip_prefix = "quote-1.0.47",
+        build_file = Label("//bazel/cargo/remote:BUILD.quote-1.0.47.bazel"),
     )
 
     maybe(
@@ -283,12 +293,32 @@ def raze_fetch_remote_crates():
 
     maybe(
         http_archive,
-        name = "raze__serde__1_0_125",
-        url = "https://crates.io/api/v1/crates/serde/1.0.125/download",
+        name = "raze__serde__1_0_229",
+        url = "https://crates.io/api/v1/crates/serde/1.0.229/download",
         type = "tar.gz",
-        sha256 = "558dc50e1a5a5fa7112ca2ce4effcb321b0300c0d4ccf0776a9f60cd89031171",
-        strip_prefix = "serde-1.0.125",
-        build_file = Label("//bazel/cargo/remote:BUILD.serde-1.0.125.bazel"),
+        sha256 = "4148590afebada386688f18773da617792bf2ef03ffc1e4cbd2b1d45b023e0ba",
+        strip_prefix = "serde-1.0.229",
+        build_file = Label("//bazel/cargo/remote:BUILD.serde-1.0.229.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__serde_core__1_0_229",
+        url = "https://crates.io/api/v1/crates/serde_core/1.0.229/download",
+        type = "tar.gz",
+        sha256 = "67dca2c9c51e58a4791a4b1ed58308b39c64224d349a935ab5039aa360942a48",
+        strip_prefix = "serde_core-1.0.229",
+        build_file = Label("//bazel/cargo/remote:BUILD.serde_core-1.0.229.bazel"),
+    )
+
+    maybe(
+        http_archive,
+        name = "raze__serde_derive__1_0_229",
+        url = "https://crates.io/api/v1/crates/serde_derive/1.0.229/download",
+        type = "tar.gz",
+        sha256 = "e7a5d71263a5a7d47b41f6b3f06ba276f10cc18b0931f1799f710578e2309348",
+        strip_prefix = "serde_derive-1.0.229",
+        build_file = Label("//bazel/cargo/remote:BUILD.serde_derive-1.0.229.bazel"),
     )
 
     maybe(
@@ -301,6 +331,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//bazel/cargo/remote:BUILD.syn-1.0.69.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__syn__3_0_9",
+        url = "https://crates.io/api/v1/crates/syn/3.0.9/download",
+        type = "tar.gz",
+        sha256 = "d78c8dee4c7bf0e14673097256fed6142ce9d3b85a408189d07482442145823b",
+        strip_prefix = "syn-3.0.9",
+        build_file = Label("//bazel/cargo/remote:BUILD.syn-3.0.9.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__time__0_1_43",
@@ -361,6 +401,16 @@ def raze_fetch_remote_crates():
         build_file = Label("//bazel/cargo/remote:BUILD.unicode-bidi-0.3.5.bazel"),
     )
 
+    maybe(
+        http_archive,
+        name = "raze__unicode_ident__1_0_27",
+        url = "https://crates.io/api/v1/crates/unicode-ident/1.0.27/download",
+        type = "tar.gz",
+        sha256 = "a2c754d6c33795a1c324727428e5a7dedb5b06195f9890bdbcba760d3e246563",
+        strip_prefix = "unicode-ident-1.0.27",
+        build_file = Label("//bazel/cargo/remote:BUILD.unicode-ident-1.0.27.bazel"),
+    )
+
     maybe(
         http_archive,
         name = "raze__unicode_normalization__0_1_17",
@@ -0,0 +1,69 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares reading a header value into a fresh allocation against reading it into
+//! a reused buffer, i.e. `get_map_value` against `get_map_value_into`.
+
+use bencher::{benchmark_group, benchmark_main, black_box, Bencher};
+use proxy_wasm_experimental as proxy_wasm;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::types::MapType;
+
+/// `Status::Ok` on the wire.
+const STATUS_OK: u32 = 0;
+
+const VALUE: &[u8] = b"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+extern "C" {
+    fn proxy_on_memory_allocate(size: usize) -> *mut u8;
+}
+
+/// Hands over a header value the way a host does, i.e. in memory allocated
+/// through the plugin.
+#[no_mangle]
+extern "C" fn proxy_get_header_map_value(
+    _map_type: MapType,
+    _key_data: *const u8,
+    _key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> u32 {
+    unsafe {
+        *return_value_data = proxy_on_memory_allocate(VALUE.len());
+        std::ptr::copy_nonoverlapping(VALUE.as_ptr(), *return_value_data, VALUE.len());
+        *return_value_size = VALUE.len();
+    }
+    STATUS_OK
+}
+
+fn get_map_value(b: &mut Bencher) {
+    b.iter(|| {
+        let value = hostcalls::get_map_value(MapType::HttpRequestHeaders, "traceparent").unwrap();
+        black_box(value)
+    });
+}
+
+fn get_map_value_into(b: &mut Bencher) {
+    let mut buf = Vec::new();
+    b.iter(|| {
+        let found =
+            hostcalls::get_map_value_into(MapType::HttpRequestHeaders, "traceparent", &mut buf)
+                .unwrap();
+        black_box((found, buf.len()))
+    });
+}
+
+benchmark_group!(benches, get_map_value, get_map_value_into);
+benchmark_main!(benches);
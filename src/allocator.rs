@@ -12,17 +12,48 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cell::Cell;
+use std::mem::MaybeUninit;
+
 #[cfg(feature = "wee-alloc")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+thread_local! {
+    static LANDING: Cell<Option<(*mut u8, usize)>> = const { Cell::new(None) };
+}
+
+/// Lets the next host allocation land in the spare capacity of a given buffer, if it fits,
+/// instead of allocating fresh memory.
+///
+/// Only the first allocation made while `f` runs is considered, and only if the buffer
+/// has any capacity at all. The caller must compare the pointer returned by the host against
+/// the buffer's own before taking ownership of it.
+pub(crate) fn with_landing_buffer<F, R>(buf: &mut Vec<u8>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    buf.clear();
+    if buf.capacity() > 0 {
+        LANDING.with(|landing| landing.set(Some((buf.as_mut_ptr(), buf.capacity()))));
+    }
+    let result = f();
+    LANDING.with(|landing| landing.set(None));
+    result
+}
+
 #[cfg_attr(
     all(target_arch = "wasm32", target_os = "unknown"),
     export_name = "malloc"
 )]
 #[no_mangle]
 pub extern "C" fn proxy_on_memory_allocate(size: usize) -> *mut u8 {
-    let mut vec: Vec<u8> = Vec::with_capacity(size);
+    if let Some((data, capacity)) = LANDING.with(|landing| landing.take()) {
+        if size <= capacity {
+            return data;
+        }
+    }
+    let mut vec: Vec<MaybeUninit<u8>> = Vec::with_capacity(size);
     unsafe {
         vec.set_len(size);
     }
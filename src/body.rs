@@ -0,0 +1,467 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for working with HTTP bodies.
+
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::dispatcher;
+use crate::error::{BufferLimitError, Result};
+use crate::hostcalls;
+use crate::types::{BufferType, ByteString, StreamType};
+
+/// Default limit on the number of bytes a body helper reads or accumulates.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+static MAX_BODY_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_BODY_SIZE);
+
+/// Sets the limit on the number of bytes a body helper reads or accumulates.
+///
+/// The limit applies to the helpers that read a whole body, i.e. [`get_whole_buffer`],
+/// [`BodyBuffer::new`], [`BufferEditor::commit`] and
+/// [`HttpContext::try_get_buffered_request_body`]. Reads at an explicit offset and size,
+/// e.g. [`hostcalls::get_buffer`], and reads of the VM or plugin configuration are not limited.
+///
+/// Defaults to [`DEFAULT_MAX_BODY_SIZE`].
+///
+/// [`get_whole_buffer`]: fn.get_whole_buffer.html
+/// [`BodyBuffer::new`]: struct.BodyBuffer.html#method.new
+/// [`BufferEditor::commit`]: struct.BufferEditor.html#method.commit
+/// [`HttpContext::try_get_buffered_request_body`]: ../traits/trait.HttpContext.html#method.try_get_buffered_request_body
+/// [`hostcalls::get_buffer`]: ../hostcalls/fn.get_buffer.html
+/// [`DEFAULT_MAX_BODY_SIZE`]: constant.DEFAULT_MAX_BODY_SIZE.html
+pub fn set_max_body_size(max_size: usize) {
+    MAX_BODY_SIZE.store(max_size, Ordering::Relaxed);
+}
+
+/// Returns the limit set via [`set_max_body_size`].
+///
+/// [`set_max_body_size`]: fn.set_max_body_size.html
+pub fn max_body_size() -> usize {
+    MAX_BODY_SIZE.load(Ordering::Relaxed)
+}
+
+/// Reads the whole content of a given buffer, e.g. a buffered request body.
+///
+/// `size` is the value passed by the host into the corresponding callback.
+/// If it exceeds [`max_body_size`], a [`BufferLimitError`] is returned
+/// without reading anything from the host.
+///
+/// [`max_body_size`]: fn.max_body_size.html
+/// [`BufferLimitError`]: ../error/struct.BufferLimitError.html
+pub fn get_whole_buffer(buffer_type: BufferType, size: usize) -> Result<Option<ByteString>> {
+    check_size(max_body_size(), size)?;
+    hostcalls::get_buffer(buffer_type, 0, size)
+}
+
+/// Reads the whole content of a given buffer whose size is not known upfront.
+///
+/// Reads at most one byte past [`max_body_size`] from the host, and returns
+/// a [`BufferLimitError`] if the buffer turns out to be larger than that.
+///
+/// [`max_body_size`]: fn.max_body_size.html
+/// [`BufferLimitError`]: ../error/struct.BufferLimitError.html
+pub(crate) fn get_whole_buffer_capped(buffer_type: BufferType) -> Result<Option<ByteString>> {
+    read_capped(max_body_size(), |max_size| {
+        hostcalls::get_buffer(buffer_type, 0, max_size)
+    })
+}
+
+fn read_capped<F>(max_size: usize, read: F) -> Result<Option<ByteString>>
+where
+    F: FnOnce(usize) -> Result<Option<ByteString>>,
+{
+    let data = read(max_size.saturating_add(1))?;
+    if let Some(ref data) = data {
+        check_size(max_size, data.len())?;
+    }
+    Ok(data)
+}
+
+fn check_size(max_size: usize, size: usize) -> Result<()> {
+    if size > max_size {
+        return Err(BufferLimitError::new(max_size, size).into());
+    }
+    Ok(())
+}
+
+/// Accumulates a body that arrives in chunks across multiple
+/// `on_http_request_body` / `on_http_response_body` calls.
+///
+/// The buffer is bounded: appending data beyond [`max_size`] fails
+/// with [`BufferLimitError`] and leaves the buffer unchanged.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::body::BodyBuffer;
+/// use proxy_wasm::traits::*;
+/// use proxy_wasm::types::*;
+///
+/// struct SignatureCheck {
+///     body: BodyBuffer,
+/// }
+///
+/// impl Context for SignatureCheck {}
+///
+/// impl HttpContext for SignatureCheck {
+///     fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+///         if self.body.append_from_host(BufferType::HttpRequestBody, body_size).is_err() {
+///             self.send_http_response(413, vec![], None);
+///             return Action::Pause;
+///         }
+///         if let Some(body) = self.body.take(end_of_stream) {
+///             // validate the complete body
+///         }
+///         Action::Continue
+///     }
+/// }
+/// ```
+///
+/// [`max_size`]: struct.BodyBuffer.html#method.max_size
+/// [`BufferLimitError`]: ../error/struct.BufferLimitError.html
+#[derive(Debug)]
+pub struct BodyBuffer {
+    data: Vec<u8>,
+    max_size: usize,
+    consumed: usize,
+}
+
+impl BodyBuffer {
+    /// Creates an empty buffer limited to [`max_body_size`] bytes.
+    ///
+    /// [`max_body_size`]: fn.max_body_size.html
+    pub fn new() -> Self {
+        Self::with_max_size(max_body_size())
+    }
+
+    /// Creates an empty buffer limited to `max_size` bytes.
+    pub fn with_max_size(max_size: usize) -> Self {
+        BodyBuffer {
+            data: Vec::new(),
+            max_size,
+            consumed: 0,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Appends a chunk of data.
+    pub fn append<B>(&mut self, chunk: B) -> Result<()>
+    where
+        B: AsRef<[u8]>,
+    {
+        let chunk = chunk.as_ref();
+        self.check_size(chunk.len())?;
+        self.data.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// Reads the data of a given buffer that hasn't been appended yet and appends it.
+    ///
+    /// `size` is the value passed by the host into the body callback.
+    /// The limit is checked before the data is read from the host.
+    ///
+    /// After a body callback returns [`Action::Pause`], the host keeps the data it handed out
+    /// and `size` covers the whole body received so far, so only the data past what has been
+    /// appended by the previous call is read.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
+    pub fn append_from_host(&mut self, buffer_type: BufferType, size: usize) -> Result<()> {
+        let buffered = match buffer_type {
+            BufferType::HttpRequestBody => dispatcher::http_body_buffered(StreamType::Request),
+            BufferType::HttpResponseBody => dispatcher::http_body_buffered(StreamType::Response),
+            _ => false,
+        };
+        self.append_with(size, buffered, |start, max_size| {
+            hostcalls::get_buffer(buffer_type, start, max_size)
+        })
+    }
+
+    fn append_with<F>(&mut self, size: usize, buffered: bool, read: F) -> Result<()>
+    where
+        F: FnOnce(usize, usize) -> Result<Option<ByteString>>,
+    {
+        let start = if buffered { self.consumed.min(size) } else { 0 };
+        if size == start {
+            return Ok(());
+        }
+        self.check_size(size - start)?;
+        if let Some(chunk) = read(start, size - start)? {
+            self.append(chunk)?;
+        }
+        self.consumed = size;
+        Ok(())
+    }
+
+    /// Returns the complete body once `end_of_stream` is `true`,
+    /// leaving the buffer empty.
+    ///
+    /// Returns `None` while the body is still incomplete.
+    pub fn take(&mut self, end_of_stream: bool) -> Option<ByteString> {
+        if end_of_stream {
+            self.consumed = 0;
+            Some(std::mem::take(&mut self.data).into())
+        } else {
+            None
+        }
+    }
+
+    /// Discards accumulated data.
+    pub fn clear(&mut self) {
+        self.data.clear()
+    }
+
+    fn check_size(&self, additional: usize) -> Result<()> {
+        check_size(self.max_size, self.data.len().saturating_add(additional))
+    }
+}
+
+impl Default for BodyBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stages edits to a buffer in terms of offsets into its current content,
+/// and applies them without the caller having to track how each edit shifts the next ones.
+///
+/// Hosts can't replace a range in the middle of a buffer, see [`hostcalls::set_buffer`],
+/// so [`commit`] reads the whole buffer, applies the edits locally and writes the result
+/// back in a single call. Edits must not overlap, and every offset refers to the content
+/// as it was before any edit.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::body::BufferEditor;
+/// use proxy_wasm::types::BufferType;
+///
+/// # fn action(body: &[u8]) -> proxy_wasm::error::Result<()> {
+/// let mut editor = BufferEditor::new(BufferType::HttpResponseBody);
+/// for (start, _) in body.windows(6).enumerate().filter(|(_, w)| w == b"secret") {
+///     editor.replace(start..start + 6, "******");
+/// }
+/// editor.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`hostcalls::set_buffer`]: ../hostcalls/fn.set_buffer.html
+/// [`commit`]: #method.commit
+#[derive(Debug)]
+pub struct BufferEditor {
+    buffer_type: BufferType,
+    edits: Vec<(Range<usize>, ByteString)>,
+}
+
+impl BufferEditor {
+    pub fn new(buffer_type: BufferType) -> Self {
+        BufferEditor {
+            buffer_type,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Replaces a given range with a value of any length.
+    pub fn replace<V>(&mut self, range: Range<usize>, value: V) -> &mut Self
+    where
+        V: Into<ByteString>,
+    {
+        self.edits.push((range, value.into()));
+        self
+    }
+
+    /// Inserts a value at a given offset.
+    pub fn insert<V>(&mut self, offset: usize, value: V) -> &mut Self
+    where
+        V: Into<ByteString>,
+    {
+        self.replace(offset..offset, value)
+    }
+
+    /// Removes a given range.
+    pub fn remove(&mut self, range: Range<usize>) -> &mut Self {
+        self.replace(range, ByteString::new())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Applies staged edits to the buffer and clears them.
+    ///
+    /// Returns an error without touching the buffer if any two edits overlap,
+    /// a range ends before it starts or lies past the end of the buffer.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.edits.is_empty() {
+            return Ok(());
+        }
+        let mut content: Vec<u8> = get_whole_buffer_capped(self.buffer_type)?
+            .map(Vec::from)
+            .unwrap_or_default();
+        let len = content.len();
+        self.apply(&mut content)?;
+        hostcalls::set_buffer(self.buffer_type, 0, len, content)
+    }
+
+    fn apply(&mut self, content: &mut Vec<u8>) -> Result<()> {
+        if let Some((range, _)) = self.edits.iter().find(|(range, _)| range.start > range.end) {
+            return Err(format!("buffer edit at {:?} has an invalid range", range).into());
+        }
+        self.edits
+            .sort_by_key(|(range, _)| (range.start, range.end));
+        for pair in self.edits.windows(2) {
+            let (first, second) = (&pair[0].0, &pair[1].0);
+            if first.end > second.start {
+                return Err(format!("buffer edits at {:?} and {:?} overlap", first, second).into());
+            }
+        }
+        if let Some((range, _)) = self
+            .edits
+            .last()
+            .filter(|(range, _)| range.end > content.len())
+        {
+            return Err(format!(
+                "buffer edit at {:?} is out of bounds of a buffer of {} bytes",
+                range,
+                content.len()
+            )
+            .into());
+        }
+        // Applied from the end backwards, so that earlier offsets are not shifted.
+        for (range, value) in self.edits.drain(..).rev() {
+            content.splice(range, value.as_bytes().iter().copied());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_editor() {
+        let mut body = b"token=abc; user=bob".to_vec();
+        let mut editor = BufferEditor::new(BufferType::HttpResponseBody);
+        editor
+            .replace(16..19, "alice")
+            .replace(6..9, "***")
+            .insert(0, "> ")
+            .remove(9..11);
+        editor.apply(&mut body).unwrap();
+        assert_eq!(body, b"> token=***user=alice");
+        assert!(editor.is_empty());
+
+        editor.replace(0..4, "a").replace(2..6, "b");
+        let err = editor.apply(&mut body).unwrap_err();
+        assert_eq!(err.to_string(), "buffer edits at 0..4 and 2..6 overlap");
+
+        let mut body = b"abc".to_vec();
+        let mut editor = BufferEditor::new(BufferType::HttpResponseBody);
+        editor.insert(0, "x").replace(2..4, "y");
+        let err = editor.apply(&mut body).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "buffer edit at 2..4 is out of bounds of a buffer of 3 bytes"
+        );
+        assert_eq!(body, b"abc");
+    }
+
+    #[test]
+    fn test_body_buffer_take() {
+        let mut buffer = BodyBuffer::new();
+        buffer.append("hello, ").unwrap();
+        assert_eq!(buffer.take(false), None);
+        buffer.append("world").unwrap();
+        assert_eq!(buffer.take(true), Some("hello, world".into()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_body_buffer_append_paused() {
+        let body = b"hello, world";
+        let read = |start: usize, max_size: usize| Ok(Some(body[start..start + max_size].into()));
+        let mut buffer = BodyBuffer::new();
+        // the first callback pauses, so the host hands out both chunks the second time
+        buffer.append_with(7, false, read).unwrap();
+        buffer.append_with(12, true, read).unwrap();
+        assert_eq!(buffer.take(true), Some("hello, world".into()));
+
+        // after continuing, the host only hands out the latest chunk
+        let mut buffer = BodyBuffer::new();
+        buffer
+            .append_with(7, false, |_, max_size| read(0, max_size))
+            .unwrap();
+        buffer
+            .append_with(5, false, |_, max_size| read(7, max_size))
+            .unwrap();
+        assert_eq!(buffer.take(true), Some("hello, world".into()));
+
+        // the limit applies to the new data only
+        let mut buffer = BodyBuffer::with_max_size(12);
+        buffer.append_with(7, false, read).unwrap();
+        buffer.append_with(12, true, read).unwrap();
+        assert_eq!(buffer.len(), 12);
+    }
+
+    #[test]
+    fn test_get_whole_buffer_limit() {
+        let size = max_body_size() + 1;
+        let err = get_whole_buffer(BufferType::HttpRequestBody, size).unwrap_err();
+        let err = err.downcast_ref::<BufferLimitError>().unwrap();
+        assert_eq!(err.size(), size);
+    }
+
+    #[test]
+    fn test_read_capped() {
+        let read = |max_size: usize| Ok(Some(b"0123456789"[..max_size.min(10)].into()));
+        assert_eq!(read_capped(10, read).unwrap(), Some("0123456789".into()));
+
+        let err = read_capped(4, read).unwrap_err();
+        let err = err.downcast_ref::<BufferLimitError>().unwrap();
+        assert_eq!((err.limit(), err.size()), (4, 5));
+
+        assert_eq!(read_capped(4, |_| Ok(None)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_body_buffer_limit() {
+        let mut buffer = BodyBuffer::with_max_size(8);
+        buffer.append("12345").unwrap();
+
+        let err = buffer.append("6789").unwrap_err();
+        let err = err.downcast_ref::<BufferLimitError>().unwrap();
+        assert_eq!(err.limit(), 8);
+        assert_eq!(err.size(), 9);
+
+        assert_eq!(buffer.len(), 5);
+        buffer.append("678").unwrap();
+        assert_eq!(buffer.take(true), Some("12345678".into()));
+    }
+}
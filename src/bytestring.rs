@@ -12,10 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::borrow::{Cow, ToOwned};
+use alloc::string::{FromUtf8Error, String};
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::cmp;
+use core::convert::TryInto;
+use core::fmt;
 use core::hash;
 use core::ops;
-use std::fmt;
-use std::string::FromUtf8Error;
+use core::slice::SliceIndex;
+use core::str::FromStr;
 
 /// Represents a borrowed string value that is not necessarily UTF-8 encoded,
 /// e.g. an HTTP header value.
@@ -38,6 +45,177 @@ impl ByteStr {
     pub fn as_bytes(&self) -> &[u8] {
         &self.bytes
     }
+
+    /// Returns the value as `&str` if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.bytes).ok()
+    }
+
+    /// Returns the value as a `String`, replacing invalid UTF-8 sequences
+    /// with `U+FFFD REPLACEMENT CHARACTER`, the same way `Display` does.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let value: ByteString = vec![b'a', 0xff, b'b'].into();
+    /// assert_eq!(value.to_string_lossy(), "a\u{FFFD}b");
+    /// ```
+    pub fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+
+    /// Decodes percent-encoded sequences, e.g. of a path segment.
+    ///
+    /// The result is not necessarily UTF-8. Malformed sequences, e.g. `%` not followed
+    /// by two hex digits, are kept as is. Unlike query parsing, `+` is not decoded as a space.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let segment: ByteString = "caf%C3%A9%2Fmenu".into();
+    /// assert_eq!(segment.percent_decode(), "café/menu");
+    /// ```
+    #[cfg(feature = "percent-encoding")]
+    pub fn percent_decode(&self) -> ByteString {
+        percent_decode(&self.bytes, false).into()
+    }
+
+    /// Percent-encodes bytes that are not allowed by a given set, e.g. to build a query
+    /// parameter of a `location` header.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::{ByteString, EncodeSet};
+    ///
+    /// let next: ByteString = "/cart?id=1&x=y z".into();
+    /// let location = format!("/login?next={}", next.percent_encode(EncodeSet::Component));
+    /// assert_eq!(location, "/login?next=%2Fcart%3Fid%3D1%26x%3Dy%20z");
+    /// ```
+    #[cfg(feature = "percent-encoding")]
+    pub fn percent_encode(&self, set: EncodeSet) -> ByteString {
+        percent_encode(&self.bytes, set).into()
+    }
+
+    /// Returns an owned copy of a given sub-range.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let authorization: ByteString = "Bearer abc.def".into();
+    /// let token = authorization.slice(7..);
+    /// assert_eq!(token, "abc.def");
+    /// ```
+    pub fn slice<R>(&self, range: R) -> ByteString
+    where
+        R: SliceIndex<[u8], Output = [u8]>,
+    {
+        self.bytes[range].into()
+    }
+
+    /// Decodes the value as an 8-byte little-endian unsigned integer,
+    /// e.g. an integer property such as `response.code`.
+    ///
+    /// Returns `None` if the value is not exactly 8 bytes long.
+    pub fn to_u64_le(&self) -> Option<u64> {
+        self.bytes.try_into().ok().map(u64::from_le_bytes)
+    }
+
+    /// Decodes the value as an 8-byte little-endian signed integer,
+    /// e.g. an integer property such as `request.size`.
+    ///
+    /// Returns `None` if the value is not exactly 8 bytes long.
+    pub fn to_i64_le(&self) -> Option<i64> {
+        self.bytes.try_into().ok().map(i64::from_le_bytes)
+    }
+
+    /// Parses the value as a number, e.g. a `content-length` header.
+    ///
+    /// Leading and trailing ASCII whitespace is ignored.
+    /// Returns `None` if the value is not valid UTF-8 or cannot be parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let value: ByteString = " 1024 ".into();
+    /// assert_eq!(value.parse_int::<u64>(), Some(1024));
+    /// ```
+    pub fn parse_int<T: FromStr>(&self) -> Option<T> {
+        self.as_str()?
+            .trim_matches(|c: char| c.is_ascii_whitespace())
+            .parse()
+            .ok()
+    }
+
+    /// Splits the value on the first occurrence of a given byte, excluding the byte itself.
+    ///
+    /// Returns `None` if the byte does not occur in the value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let authorization: ByteString = "Bearer abc.def".into();
+    /// let (scheme, credentials) = authorization.split_once(b' ').unwrap();
+    /// assert_eq!(scheme, "Bearer");
+    /// assert_eq!(credentials, "abc.def");
+    /// ```
+    pub fn split_once(&self, delimiter: u8) -> Option<(&ByteStr, &ByteStr)> {
+        let position = self.bytes.iter().position(|&b| b == delimiter)?;
+        Some((
+            ByteStr::from_bytes(&self.bytes[..position]),
+            ByteStr::from_bytes(&self.bytes[position + 1..]),
+        ))
+    }
+
+    /// Splits the value on the first occurrence of a given byte sequence,
+    /// excluding the sequence itself.
+    ///
+    /// Returns `None` if the sequence does not occur in the value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// let url: ByteString = "https://example.org/".into();
+    /// let (scheme, rest) = url.split_once_bytes(b"://").unwrap();
+    /// assert_eq!(scheme, "https");
+    /// assert_eq!(rest, "example.org/");
+    /// ```
+    pub fn split_once_bytes(&self, delimiter: &[u8]) -> Option<(&ByteStr, &ByteStr)> {
+        let position = if delimiter.is_empty() {
+            0
+        } else {
+            self.bytes
+                .windows(delimiter.len())
+                .position(|window| window == delimiter)?
+        };
+        Some((
+            ByteStr::from_bytes(&self.bytes[..position]),
+            ByteStr::from_bytes(&self.bytes[position + delimiter.len()..]),
+        ))
+    }
 }
 
 impl ops::Deref for ByteStr {
@@ -309,6 +487,21 @@ impl PartialEq<ByteStr> for [u8] {
     }
 }
 
+impl PartialOrd for ByteStr {
+    #[inline]
+    fn partial_cmp(&self, other: &ByteStr) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders values byte by byte, like `[u8]`.
+impl Ord for ByteStr {
+    #[inline]
+    fn cmp(&self, other: &ByteStr) -> cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
 impl hash::Hash for ByteStr {
     fn hash<H>(&self, state: &mut H)
     where
@@ -330,6 +523,30 @@ impl ByteString {
         Self::default()
     }
 
+    /// Creates a value from given bytes only if they are valid UTF-8.
+    ///
+    /// Unlike `From<Vec<u8>>`, which accepts arbitrary bytes, this is meant for values
+    /// that are required to be text. The original bytes can be recovered from the error
+    /// via [`FromUtf8Error::into_bytes`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::types::ByteString;
+    ///
+    /// assert_eq!(ByteString::try_from_utf8("text").unwrap(), "text");
+    /// assert!(ByteString::try_from_utf8(vec![0xff, 0xfe]).is_err());
+    /// ```
+    ///
+    /// [`FromUtf8Error::into_bytes`]: https://doc.rust-lang.org/std/string/struct.FromUtf8Error.html#method.into_bytes
+    pub fn try_from_utf8<B>(bytes: B) -> Result<Self, FromUtf8Error>
+    where
+        B: Into<Vec<u8>>,
+    {
+        String::from_utf8(bytes.into()).map(Self::from)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.as_ref().is_empty()
     }
@@ -342,6 +559,25 @@ impl ByteString {
         self.as_ref()
     }
 
+    /// Returns the value as `&str` if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.bytes).ok()
+    }
+
+    /// Returns an owned copy of a given sub-range, like [`ByteStr::slice`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is out of bounds.
+    ///
+    /// [`ByteStr::slice`]: struct.ByteStr.html#method.slice
+    pub fn substring<R>(&self, range: R) -> ByteString
+    where
+        R: SliceIndex<[u8], Output = [u8]>,
+    {
+        self.slice(range)
+    }
+
     pub fn into_bytes(self) -> Vec<u8> {
         self.bytes
     }
@@ -407,6 +643,20 @@ impl From<&[u8]> for ByteString {
     }
 }
 
+impl From<&Vec<u8>> for ByteString {
+    #[inline]
+    fn from(bytes: &Vec<u8>) -> Self {
+        bytes.clone().into()
+    }
+}
+
+impl From<Cow<'_, [u8]>> for ByteString {
+    #[inline]
+    fn from(bytes: Cow<'_, [u8]>) -> Self {
+        bytes.into_owned().into()
+    }
+}
+
 impl From<String> for ByteString {
     #[inline]
     fn from(text: String) -> Self {
@@ -428,6 +678,13 @@ impl From<&ByteString> for ByteString {
     }
 }
 
+impl From<ByteString> for Vec<u8> {
+    #[inline]
+    fn from(data: ByteString) -> Self {
+        data.bytes
+    }
+}
+
 impl PartialEq for ByteString {
     #[inline]
     fn eq(&self, other: &ByteString) -> bool {
@@ -519,13 +776,117 @@ impl PartialEq<ByteString> for &[u8] {
     }
 }
 
+impl PartialOrd for ByteString {
+    #[inline]
+    fn partial_cmp(&self, other: &ByteString) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders values byte by byte, like `[u8]`.
+impl Ord for ByteString {
+    #[inline]
+    fn cmp(&self, other: &ByteString) -> cmp::Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
 impl hash::Hash for ByteString {
     fn hash<H>(&self, state: &mut H)
     where
         H: hash::Hasher,
     {
-        (&**self).hash(state);
+        (**self).hash(state);
+    }
+}
+
+impl Borrow<ByteStr> for ByteString {
+    fn borrow(&self) -> &ByteStr {
+        self
+    }
+}
+
+impl Borrow<[u8]> for ByteString {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Characters to percent-encode via [`ByteStr::percent_encode`].
+///
+/// Control characters, space, `%` and non-ASCII bytes are always encoded.
+///
+/// [`ByteStr::percent_encode`]: struct.ByteStr.html#method.percent_encode
+#[cfg(feature = "percent-encoding")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum EncodeSet {
+    /// Encodes everything but the unreserved characters of RFC 3986, i.e. `A-Z`, `a-z`, `0-9`,
+    /// `-`, `.`, `_` and `~`. Meant for a single path segment or a query parameter name or value.
+    Component,
+    /// Like [`Component`], but keeps `/`, so that a whole path can be encoded at once.
+    ///
+    /// [`Component`]: #variant.Component
+    Path,
+}
+
+#[cfg(feature = "percent-encoding")]
+impl EncodeSet {
+    fn keeps(self, b: u8) -> bool {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => true,
+            b'/' => self == EncodeSet::Path,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "percent-encoding")]
+pub(crate) fn percent_encode(bytes: &[u8], set: EncodeSet) -> Vec<u8> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut encoded = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        if set.keeps(b) {
+            encoded.push(b);
+        } else {
+            encoded.extend_from_slice(&[b'%', HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+        }
     }
+    encoded
+}
+
+#[cfg(any(feature = "std", feature = "percent-encoding"))]
+pub(crate) fn percent_decode(bytes: &[u8], plus_as_space: bool) -> Vec<u8> {
+    fn hex(b: u8) -> Option<u8> {
+        match b {
+            b'0'..=b'9' => Some(b - b'0'),
+            b'a'..=b'f' => Some(b - b'a' + 10),
+            b'A'..=b'F' => Some(b - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => match (
+                bytes.get(i + 1).and_then(|&b| hex(b)),
+                bytes.get(i + 2).and_then(|&b| hex(b)),
+            ) {
+                (Some(hi), Some(lo)) => {
+                    decoded.push(hi << 4 | lo);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' if plus_as_space => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    decoded
 }
 
 #[cfg(test)]
@@ -539,9 +900,9 @@ mod tests {
         use bstr::ByteSlice;
 
         let string: ByteString = "hello".into();
-        assert_eq!(string.is_utf8(), true);
-        assert_eq!(string.starts_with_str("hel"), true);
-        assert_eq!(string.ends_with_str("lo"), true);
+        assert!(string.is_utf8());
+        assert!(string.starts_with_str("hel"));
+        assert!(string.ends_with_str("lo"));
     }
 
     #[test]
@@ -549,9 +910,172 @@ mod tests {
         use bstr::ByteSlice;
 
         let bytes: ByteString = vec![144u8, 145u8, 146u8].into();
-        assert_eq!(bytes.is_utf8(), false);
-        assert_eq!(bytes.starts_with_str(b"\x90"), true);
-        assert_eq!(bytes.ends_with_str(b"\x92"), true);
+        assert!(!bytes.is_utf8());
+        assert!(bytes.starts_with_str(b"\x90"));
+        assert!(bytes.ends_with_str(b"\x92"));
+    }
+
+    #[test]
+    fn test_bytestring_into_vec_and_lossy_string() {
+        let bytes: ByteString = vec![b'o', b'k', 0xff].into();
+        assert_eq!(bytes.to_string_lossy(), "ok\u{FFFD}");
+        assert_eq!(bytes.to_string_lossy(), bytes.to_string());
+        assert_eq!(bytes[..2].to_string_lossy(), "ok");
+
+        let bytes: Vec<u8> = bytes.into();
+        assert_eq!(bytes, vec![b'o', b'k', 0xff]);
+    }
+
+    #[test]
+    fn test_bytestring_ord() {
+        let mut values: Vec<ByteString> = vec![
+            "b".into(),
+            vec![0xffu8].into(),
+            "a2".into(),
+            "".into(),
+            "a".into(),
+        ];
+        values.sort();
+        let values: Vec<&[u8]> = values.iter().map(|value| value.as_bytes()).collect();
+        assert_eq!(values, [&b""[..], b"a", b"a2", b"b", b"\xff"]);
+
+        let value: ByteString = "abc".into();
+        assert!(value[..1] < value[1..]);
+    }
+
+    #[test]
+    #[cfg(feature = "percent-encoding")]
+    fn test_bytestring_percent_encoding() {
+        let value: ByteString = vec![b'a', b' ', b'/', 0xff, b'%', b'~'].into();
+        let encoded = value.percent_encode(EncodeSet::Component);
+        assert_eq!(encoded, "a%20%2F%FF%25~");
+        assert_eq!(encoded.percent_decode(), value);
+        assert_eq!(value.percent_encode(EncodeSet::Path), "a%20/%FF%25~");
+
+        let value: ByteString = "%41%2f+%".into();
+        assert_eq!(value.percent_decode(), "A/+%");
+    }
+
+    #[test]
+    #[cfg(feature = "percent-encoding")]
+    fn test_bytestring_percent_decode_malformed() {
+        for malformed in &["%", "%4", "%zz", "%%41", "%4g", "100%"] {
+            let value: ByteString = (*malformed).into();
+            let expected = malformed.replace("%41", "A");
+            assert_eq!(value.percent_decode(), expected.as_str());
+        }
+        let value: ByteString = "%e9%".into();
+        assert_eq!(value.percent_decode().as_bytes(), b"\xe9%");
+    }
+
+    #[test]
+    fn test_bytestring_as_str() {
+        let string: ByteString = "hello".into();
+        assert_eq!(string.as_str(), Some("hello"));
+        assert_eq!(string[1..3].as_str(), Some("el"));
+
+        let bytes: ByteString = vec![144u8, 145u8, 146u8].into();
+        assert_eq!(bytes.as_str(), None);
+        assert_eq!(bytes[..].as_str(), None);
+    }
+
+    #[test]
+    fn test_bytestring_parse_int() {
+        let value: ByteString = "\t42 \r\n".into();
+        assert_eq!(value.parse_int::<u32>(), Some(42));
+        assert_eq!(value.parse_int::<i64>(), Some(42));
+
+        let negative: ByteString = "-1".into();
+        assert_eq!(negative.parse_int::<i32>(), Some(-1));
+        assert_eq!(negative.parse_int::<u32>(), None);
+
+        let invalid: ByteString = "4 2".into();
+        assert_eq!(invalid.parse_int::<u32>(), None);
+        let empty = ByteString::new();
+        assert_eq!(empty.parse_int::<u32>(), None);
+        let bytes: ByteString = vec![0x34, 0xff].into();
+        assert_eq!(bytes.parse_int::<u32>(), None);
+    }
+
+    #[test]
+    fn test_bytestring_hashbrown_key() {
+        let mut map: crate::types::ByteStringMap<u32> = Default::default();
+        map.insert("x-request-id".into(), 1);
+        map.insert(vec![0xff].into(), 2);
+
+        let key: ByteString = "x-request-id".into();
+        assert_eq!(map.get(&key[..]), Some(&1));
+        assert_eq!(map.get(&b"x-request-id"[..]), Some(&1));
+        assert_eq!(map.get(&[0xffu8][..]), Some(&2));
+        assert_eq!(map.get(&b"missing"[..]), None);
+    }
+
+    #[test]
+    fn test_bytestring_slice() {
+        let string: ByteString = "Bearer abc".into();
+        assert_eq!(string.slice(7..), "abc");
+        assert_eq!(string.substring(..6), "Bearer");
+        assert_eq!(string[1..].slice(1..=2), "ar");
+        assert!(string.substring(10..).is_empty());
+    }
+
+    #[test]
+    fn test_bytestring_try_from_utf8() {
+        assert_eq!(ByteString::try_from_utf8(&b"value"[..]).unwrap(), "value");
+        assert_eq!(ByteString::try_from_utf8(String::new()).unwrap(), "");
+
+        let err = ByteString::try_from_utf8(vec![b'a', 0xc3]).unwrap_err();
+        assert_eq!(err.into_bytes(), vec![b'a', 0xc3]);
+    }
+
+    #[test]
+    fn test_bytestring_split_once() {
+        let string: ByteString = "Bearer abc def".into();
+        let (scheme, credentials) = string.split_once(b' ').unwrap();
+        assert_eq!(scheme, "Bearer");
+        assert_eq!(credentials, "abc def");
+        assert_eq!(string.split_once(b':'), None);
+
+        let (_, rest) = string.split_once_bytes(b"abc ").unwrap();
+        assert_eq!(rest, "def");
+        assert_eq!(string.split_once_bytes(b"xyz"), None);
+        let (head, rest) = string.split_once_bytes(b"").unwrap();
+        assert!(head.is_empty());
+        assert_eq!(rest, "Bearer abc def");
+
+        let binary: ByteString = vec![0u8, 255, 0, 1].into();
+        let (head, rest) = binary.split_once(255).unwrap();
+        assert_eq!(head, &[0u8][..]);
+        assert_eq!(rest, &[0u8, 1][..]);
+    }
+
+    #[test]
+    fn test_bytestring_from_vec_ref_and_cow() {
+        let bytes = vec![1u8, 2, 3];
+        assert_eq!(ByteString::from(&bytes), bytes);
+
+        let borrowed: Cow<[u8]> = Cow::Borrowed(b"abc");
+        assert_eq!(ByteString::from(borrowed), "abc");
+        let owned: Cow<[u8]> = Cow::Owned(vec![0xff]);
+        assert_eq!(ByteString::from(owned), vec![0xff]);
+    }
+
+    #[test]
+    fn test_bytestring_to_int_le() {
+        let value: ByteString = 503u64.to_le_bytes().to_vec().into();
+        assert_eq!(value.to_u64_le(), Some(503));
+        assert_eq!(value.to_i64_le(), Some(503));
+
+        let negative: ByteString = (-1i64).to_le_bytes().to_vec().into();
+        assert_eq!(negative.to_i64_le(), Some(-1));
+        assert_eq!(negative.to_u64_le(), Some(u64::MAX));
+
+        let short: ByteString = vec![1, 0, 0, 0].into();
+        assert_eq!(short.to_u64_le(), None);
+        assert_eq!(short.to_i64_le(), None);
+        let long: ByteString = vec![0; 9].into();
+        assert_eq!(long.to_u64_le(), None);
+        assert_eq!(ByteString::new().to_i64_le(), None);
     }
 
     #[test]
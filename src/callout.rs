@@ -0,0 +1,514 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for dispatching HTTP calls.
+
+use std::fmt;
+use std::time::Duration;
+
+use hashbrown::HashMap;
+
+use crate::dispatcher;
+use crate::error::Result;
+use crate::hostcalls;
+use crate::types::{BufferType, ByteString, MapType};
+
+/// Handler of the response to an HTTP call.
+pub type HttpCallHandler<T> = Box<dyn FnOnce(HttpCallResponse) -> T>;
+
+/// Keeps track of pending HTTP calls along with a handler of each call's response.
+///
+/// Calls cancelled with [`cancel_http_call`] or forgotten with [`forget_http_calls`]
+/// are no longer tracked, and their handlers are dropped on the next [`dispatch`].
+///
+/// [`cancel_http_call`]: ../fn.cancel_http_call.html
+/// [`forget_http_calls`]: ../fn.forget_http_calls.html
+/// [`dispatch`]: #method.dispatch
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use std::time::Duration;
+///
+/// use proxy_wasm::callout::HttpCallout;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::traits::*;
+/// use proxy_wasm::types::*;
+///
+/// struct Lookup {
+///     callouts: HttpCallout,
+/// }
+///
+/// impl HttpContext for Lookup {
+///     fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+///         for user in &["alice", "bob"] {
+///             let path = format!("/users/{}", user);
+///             self.callouts
+///                 .dispatch(
+///                     "users",
+///                     &[(":method", "GET"), (":path", &path), (":authority", "users")],
+///                     hostcalls::NO_BODY,
+///                     hostcalls::NO_TRAILERS,
+///                     Duration::from_secs(1),
+///                     move |response| {
+///                         let body = response.body();
+///                         // correlate `body` with `user`
+///                     },
+///                 )
+///                 .unwrap();
+///         }
+///         Action::Pause
+///     }
+/// }
+///
+/// impl Context for Lookup {
+///     fn on_http_call_response(
+///         &mut self,
+///         token_id: u32,
+///         num_headers: usize,
+///         body_size: usize,
+///         num_trailers: usize,
+///     ) {
+///         self.callouts
+///             .handle(token_id, num_headers, body_size, num_trailers);
+///         if self.callouts.is_empty() {
+///             self.resume_http_request();
+///         }
+///     }
+/// }
+/// ```
+pub struct HttpCallout<T = ()> {
+    pending: HashMap<u32, HttpCallHandler<T>>,
+}
+
+impl<T> HttpCallout<T> {
+    pub fn new() -> Self {
+        HttpCallout {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Dispatches an HTTP call to a given upstream and remembers `handler` until
+    /// the response arrives.
+    ///
+    /// See [`hostcalls::dispatch_http_call`] for details.
+    ///
+    /// [`hostcalls::dispatch_http_call`]: ../hostcalls/fn.dispatch_http_call.html
+    #[allow(clippy::too_many_arguments)]
+    pub fn dispatch<K1, V1, K2, V2, B, F>(
+        &mut self,
+        upstream: &str,
+        headers: &[(K1, V1)],
+        body: Option<B>,
+        trailers: &[(K2, V2)],
+        timeout: Duration,
+        handler: F,
+    ) -> Result<u32>
+    where
+        K1: AsRef<[u8]>,
+        V1: AsRef<[u8]>,
+        K2: AsRef<[u8]>,
+        V2: AsRef<[u8]>,
+        B: AsRef<[u8]>,
+        F: FnOnce(HttpCallResponse) -> T + 'static,
+    {
+        self.pending
+            .retain(|&token_id, _| dispatcher::is_http_call_pending(token_id));
+        let token_id = hostcalls::dispatch_http_call(upstream, headers, body, trailers, timeout)?;
+        self.pending.insert(token_id, Box::new(handler));
+        Ok(token_id)
+    }
+
+    /// Stops tracking a given HTTP call and returns its handler.
+    ///
+    /// Returns `None` if the call wasn't dispatched through this instance.
+    pub fn take(&mut self, token_id: u32) -> Option<HttpCallHandler<T>> {
+        self.pending.remove(&token_id)
+    }
+
+    /// Hands the response to a given HTTP call to its handler.
+    ///
+    /// Meant to be called from `on_http_call_response`. Returns `None` if the call
+    /// wasn't dispatched through this instance.
+    pub fn handle(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    ) -> Option<T> {
+        self.take(token_id).map(|handler| {
+            handler(HttpCallResponse {
+                token_id,
+                num_headers,
+                body_size,
+                num_trailers,
+            })
+        })
+    }
+
+    /// Cancels a given HTTP call and drops its handler.
+    ///
+    /// See [`cancel_http_call`] for details.
+    ///
+    /// [`cancel_http_call`]: ../fn.cancel_http_call.html
+    pub fn cancel(&mut self, token_id: u32) -> bool {
+        self.pending.remove(&token_id).is_some() && dispatcher::cancel_http_call(token_id)
+    }
+
+    pub fn is_pending(&self, token_id: u32) -> bool {
+        self.pending.contains_key(&token_id) && dispatcher::is_http_call_pending(token_id)
+    }
+
+    /// Returns the number of pending HTTP calls.
+    pub fn len(&self) -> usize {
+        self.pending
+            .keys()
+            .filter(|&&token_id| dispatcher::is_http_call_pending(token_id))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Default for HttpCallout<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for HttpCallout<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tokens: Vec<&u32> = self.pending.keys().collect();
+        tokens.sort_unstable();
+        f.debug_struct("HttpCallout")
+            .field("pending", &tokens)
+            .finish()
+    }
+}
+
+/// Represents the response to an HTTP call.
+///
+/// Only valid within `on_http_call_response`.
+#[derive(Debug)]
+pub struct HttpCallResponse {
+    token_id: u32,
+    num_headers: usize,
+    body_size: usize,
+    num_trailers: usize,
+}
+
+impl HttpCallResponse {
+    pub fn token_id(&self) -> u32 {
+        self.token_id
+    }
+
+    pub fn num_headers(&self) -> usize {
+        self.num_headers
+    }
+
+    pub fn body_size(&self) -> usize {
+        self.body_size
+    }
+
+    pub fn num_trailers(&self) -> usize {
+        self.num_trailers
+    }
+
+    pub fn headers(&self) -> Vec<(ByteString, ByteString)> {
+        hostcalls::get_map(MapType::HttpCallResponseHeaders).unwrap()
+    }
+
+    pub fn header(&self, name: &str) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpCallResponseHeaders, name).unwrap()
+    }
+
+    pub fn body(&self) -> Option<ByteString> {
+        hostcalls::get_buffer(BufferType::HttpCallResponseBody, 0, self.body_size).unwrap()
+    }
+
+    pub fn trailers(&self) -> Vec<(ByteString, ByteString)> {
+        hostcalls::get_map(MapType::HttpCallResponseTrailers).unwrap()
+    }
+}
+
+const RETRY_ON_CONDITIONS: &[&str] = &[
+    "5xx",
+    "gateway-error",
+    "reset",
+    "connect-failure",
+    "envoy-ratelimited",
+    "retriable-4xx",
+    "refused-stream",
+    "retriable-status-codes",
+    "retriable-headers",
+];
+
+/// Describes how the host should retry an HTTP call, using Envoy's `x-envoy-*` headers.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use std::time::Duration;
+///
+/// use proxy_wasm::callout::RetryPolicy;
+/// use proxy_wasm::hostcalls;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let policy = RetryPolicy::new()
+///     .max_retries(3)
+///     .retry_on("5xx")?
+///     .retry_on("connect-failure")?
+///     .per_try_timeout(Duration::from_millis(250));
+///
+/// let headers = policy.with_headers(&[
+///     (":method", "GET"),
+///     (":path", "/auth"),
+///     (":authority", "auth"),
+/// ]);
+/// hostcalls::dispatch_http_call(
+///     "auth",
+///     &headers,
+///     hostcalls::NO_BODY,
+///     hostcalls::NO_TRAILERS,
+///     Duration::from_secs(1),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RetryPolicy {
+    max_retries: Option<u32>,
+    retry_on: Vec<&'static str>,
+    per_try_timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of retries, i.e. `x-envoy-max-retries`.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Adds a condition to retry on, i.e. a token of `x-envoy-retry-on`, e.g. `5xx`.
+    ///
+    /// Returns an error if the condition is not one that Envoy recognizes.
+    pub fn retry_on(mut self, condition: &str) -> Result<Self> {
+        match RETRY_ON_CONDITIONS.iter().find(|&&c| c == condition) {
+            Some(condition) => {
+                if !self.retry_on.contains(condition) {
+                    self.retry_on.push(condition);
+                }
+                Ok(self)
+            }
+            None => Err(format!(
+                "unknown retry-on condition {:?}, expected one of: {}",
+                condition,
+                RETRY_ON_CONDITIONS.join(", ")
+            )
+            .into()),
+        }
+    }
+
+    /// Sets the timeout of each attempt, i.e. `x-envoy-upstream-rq-per-try-timeout-ms`.
+    pub fn per_try_timeout(mut self, timeout: Duration) -> Self {
+        self.per_try_timeout = Some(timeout);
+        self
+    }
+
+    /// Returns the `x-envoy-*` headers describing this policy.
+    pub fn headers(&self) -> Vec<(ByteString, ByteString)> {
+        let mut headers = Vec::new();
+        if let Some(max_retries) = self.max_retries {
+            headers.push(("x-envoy-max-retries".into(), max_retries.to_string().into()));
+        }
+        if !self.retry_on.is_empty() {
+            headers.push(("x-envoy-retry-on".into(), self.retry_on.join(",").into()));
+        }
+        if let Some(timeout) = self.per_try_timeout {
+            headers.push((
+                "x-envoy-upstream-rq-per-try-timeout-ms".into(),
+                timeout.as_millis().to_string().into(),
+            ));
+        }
+        headers
+    }
+
+    /// Returns given headers with the headers of this policy added,
+    /// replacing any existing headers of the same name.
+    pub fn with_headers<K, V>(&self, headers: &[(K, V)]) -> Vec<(ByteString, ByteString)>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let policy_headers = self.headers();
+        let mut merged: Vec<(ByteString, ByteString)> = headers
+            .iter()
+            .filter(|(name, _)| {
+                !policy_headers
+                    .iter()
+                    .any(|(policy_name, _)| policy_name.eq_ignore_ascii_case(name.as_ref()))
+            })
+            .map(|(name, value)| (name.as_ref().into(), value.as_ref().into()))
+            .collect();
+        merged.extend(policy_headers);
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn dispatch(callouts: &mut HttpCallout<&'static str>, name: &'static str) -> u32 {
+        callouts
+            .dispatch(
+                "upstream",
+                &[
+                    (":method", "GET"),
+                    (":path", "/"),
+                    (":authority", "upstream"),
+                ],
+                hostcalls::NO_BODY,
+                hostcalls::NO_TRAILERS,
+                Duration::from_secs(1),
+                move |_| name,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_http_callout_handle() {
+        let mut callouts = HttpCallout::new();
+        let first = dispatch(&mut callouts, "first");
+        let second = dispatch(&mut callouts, "second");
+        assert_eq!(callouts.len(), 2);
+        assert!(callouts.is_pending(first));
+
+        assert_eq!(callouts.handle(second, 0, 0, 0), Some("second"));
+        assert_eq!(callouts.handle(second, 0, 0, 0), None);
+        assert!(!callouts.is_pending(second));
+
+        let handler = callouts.take(first).unwrap();
+        assert!(callouts.is_empty());
+        assert!(callouts.take(first).is_none());
+        assert_eq!(
+            handler(HttpCallResponse {
+                token_id: first,
+                num_headers: 0,
+                body_size: 0,
+                num_trailers: 0,
+            }),
+            "first"
+        );
+    }
+
+    #[test]
+    fn test_http_callout_unknown_token() {
+        let mut callouts = HttpCallout::new();
+        let token_id = dispatch(&mut callouts, "known");
+        assert_eq!(callouts.handle(token_id + 1, 0, 0, 0), None);
+        assert!(!callouts.is_pending(token_id + 1));
+        assert!(!callouts.cancel(token_id + 1));
+        assert_eq!(callouts.len(), 1);
+    }
+
+    #[test]
+    fn test_http_callout_cancel() {
+        let calls = Rc::new(RefCell::new(0));
+        let mut callouts = HttpCallout::new();
+        let token_id = {
+            let calls = Rc::clone(&calls);
+            callouts
+                .dispatch(
+                    "upstream",
+                    &[
+                        (":method", "GET"),
+                        (":path", "/"),
+                        (":authority", "upstream"),
+                    ],
+                    hostcalls::NO_BODY,
+                    hostcalls::NO_TRAILERS,
+                    Duration::from_secs(1),
+                    move |_| *calls.borrow_mut() += 1,
+                )
+                .unwrap()
+        };
+        assert!(callouts.cancel(token_id));
+        assert!(!callouts.cancel(token_id));
+        assert!(!dispatcher::is_http_call_pending(token_id));
+        assert_eq!(callouts.handle(token_id, 0, 0, 0), None);
+        assert_eq!(*calls.borrow(), 0);
+        // the handler, and with it the state it captured, is dropped
+        assert_eq!(Rc::strong_count(&calls), 1);
+    }
+
+    #[test]
+    fn test_http_callout_cancelled_elsewhere() {
+        let mut callouts = HttpCallout::new();
+        let cancelled = dispatch(&mut callouts, "cancelled");
+        assert!(crate::cancel_http_call(cancelled));
+        assert!(!callouts.is_pending(cancelled));
+        assert!(callouts.is_empty());
+
+        let pending = dispatch(&mut callouts, "pending");
+        assert!(callouts.take(cancelled).is_none());
+        assert_eq!(callouts.handle(pending, 0, 0, 0), Some("pending"));
+    }
+
+    #[test]
+    fn test_retry_policy_headers() {
+        let policy = RetryPolicy::new()
+            .max_retries(2)
+            .retry_on("5xx")
+            .unwrap()
+            .retry_on("reset")
+            .unwrap()
+            .retry_on("5xx")
+            .unwrap()
+            .per_try_timeout(Duration::from_millis(250));
+        let headers = policy.with_headers(&[(":path", "/"), ("X-Envoy-Max-Retries", "9")]);
+        assert_eq!(
+            headers,
+            vec![
+                (":path".into(), "/".into()),
+                ("x-envoy-max-retries".into(), "2".into()),
+                ("x-envoy-retry-on".into(), "5xx,reset".into()),
+                (
+                    "x-envoy-upstream-rq-per-try-timeout-ms".into(),
+                    "250".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_policy_invalid_condition() {
+        let err = RetryPolicy::new().retry_on("5XX").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unknown retry-on condition \"5XX\""));
+        assert!(RetryPolicy::new().headers().is_empty());
+    }
+}
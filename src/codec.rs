@@ -0,0 +1,204 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The wire format of header maps exchanged with the host.
+//!
+//! A map with `N` entries is encoded as follows, with all integers being 32-bit little-endian:
+//!
+//! * the number of entries `N`,
+//! * `N` pairs of the key size and the value size,
+//! * `N` pairs of the key and the value, each followed by a `\0` byte.
+
+use crate::error::MapDecodeError;
+use crate::types::ByteString;
+use alloc::vec::Vec;
+
+/// Encodes a map in the host wire format, e.g. to store a header snapshot in shared data.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::types::{decode_map, encode_map};
+///
+/// let bytes = encode_map(&[("x-tenant", "acme")]);
+/// let map = decode_map(&bytes).unwrap();
+/// assert_eq!(map[0].0, "x-tenant");
+/// assert_eq!(map[0].1, "acme");
+/// ```
+pub fn encode_map<K, V>(map: &[(K, V)]) -> Vec<u8>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut size: usize = 4;
+    for (name, value) in map {
+        size += name.as_ref().len() + value.as_ref().len() + 10;
+    }
+    let mut bytes: Vec<u8> = Vec::with_capacity(size);
+    bytes.extend_from_slice(&(map.len() as u32).to_le_bytes());
+    for (name, value) in map {
+        bytes.extend_from_slice(&(name.as_ref().len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(value.as_ref().len() as u32).to_le_bytes());
+    }
+    for (name, value) in map {
+        bytes.extend_from_slice(name.as_ref());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_ref());
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Decodes a map from the host wire format.
+///
+/// An empty input decodes into an empty map. Returns a [`MapDecodeError`] if the input
+/// is truncated.
+///
+/// [`MapDecodeError`]: ../error/enum.MapDecodeError.html
+pub fn decode_map(bytes: &[u8]) -> Result<Vec<(ByteString, ByteString)>, MapDecodeError> {
+    let mut map = Vec::new();
+    if bytes.is_empty() {
+        return Ok(map);
+    }
+    if bytes.len() < 4 {
+        return Err(MapDecodeError::TruncatedCount { len: bytes.len() });
+    }
+    let size = read_u32(bytes, 0) as usize;
+    let mut p = match size.checked_mul(8).and_then(|n| n.checked_add(4)) {
+        Some(p) if p <= bytes.len() => p,
+        _ => {
+            return Err(MapDecodeError::TruncatedSizes {
+                len: bytes.len(),
+                entries: size,
+            })
+        }
+    };
+    for n in 0..size {
+        let s = 4 + n * 8;
+        let size = read_u32(bytes, s) as usize;
+        let key = slice_entry(bytes, p, size)?.to_vec();
+        p += size + 1;
+        let size = read_u32(bytes, s + 4) as usize;
+        let value = slice_entry(bytes, p, size)?.to_vec();
+        p += size + 1;
+        map.push((key.into(), value.into()));
+    }
+    Ok(map)
+}
+
+fn read_u32(bytes: &[u8], start: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[start..start + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn slice_entry(bytes: &[u8], start: usize, size: usize) -> Result<&[u8], MapDecodeError> {
+    start
+        .checked_add(size)
+        .and_then(|end| bytes.get(start..end))
+        .ok_or(MapDecodeError::EntryOverrun {
+            len: bytes.len(),
+            offset: start,
+            size,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_map_empty() {
+        assert_eq!(encode_map::<&str, &str>(&[]), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_encode_map_roundtrip() {
+        let bytes = encode_map(&[("host", "abc"), ("x", "")]);
+        assert_eq!(
+            bytes,
+            &b"\x02\x00\x00\x00\
+               \x04\x00\x00\x00\x03\x00\x00\x00\
+               \x01\x00\x00\x00\x00\x00\x00\x00\
+               host\x00abc\x00\
+               x\x00\x00"[..]
+        );
+        let map = decode_map(&bytes).unwrap();
+        assert_eq!(
+            map,
+            vec![("host".into(), "abc".into()), ("x".into(), "".into())]
+        );
+    }
+
+    #[test]
+    fn test_decode_map_empty() {
+        assert!(decode_map(&[]).unwrap().is_empty());
+        assert!(decode_map(&[0, 0, 0, 0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_map() {
+        let bytes = b"\x02\x00\x00\x00\
+                      \x04\x00\x00\x00\x03\x00\x00\x00\
+                      \x01\x00\x00\x00\x00\x00\x00\x00\
+                      host\x00abc\x00\
+                      x\x00\x00";
+        let map = decode_map(bytes).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[0].0, "host");
+        assert_eq!(map[0].1, "abc");
+        assert_eq!(map[1].0, "x");
+        assert_eq!(map[1].1, "");
+    }
+
+    #[test]
+    fn test_decode_map_count_exceeds_buffer() {
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 8]);
+        let err = decode_map(&bytes).unwrap_err();
+        assert_eq!(
+            err,
+            MapDecodeError::TruncatedSizes {
+                len: 12,
+                entries: u32::MAX as usize
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "serialized map of 12 bytes is too short to hold 4294967295 entries"
+        );
+    }
+
+    #[test]
+    fn test_decode_map_entry_exceeds_buffer() {
+        let bytes = b"\x01\x00\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00ab";
+        assert_eq!(
+            decode_map(bytes).unwrap_err(),
+            MapDecodeError::EntryOverrun {
+                len: 14,
+                offset: 12,
+                size: 16
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_map_truncated_count() {
+        assert_eq!(
+            decode_map(&[1, 0]).unwrap_err(),
+            MapDecodeError::TruncatedCount { len: 2 }
+        );
+    }
+}
@@ -12,10 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::error::Result;
 use crate::hostcalls;
 use crate::traits::*;
 use crate::types::*;
 use hashbrown::HashMap;
+use std::any::Any;
 use std::cell::{Cell, RefCell};
 
 thread_local! {
@@ -25,6 +27,11 @@ static DISPATCHER: Dispatcher = Dispatcher::new();
 type NewRootContextFn = dyn FnMut(u32) -> Box<dyn RootContext>;
 type NewStreamContextFn = dyn FnMut(u32, u32) -> Box<dyn StreamContext>;
 type NewHttpContextFn = dyn FnMut(u32, u32) -> Box<dyn HttpContext>;
+type VmInitFn = dyn FnOnce();
+
+pub(crate) fn set_vm_init(callback: Box<VmInitFn>) {
+    DISPATCHER.with(|dispatcher| dispatcher.set_vm_init(callback));
+}
 
 pub(crate) fn set_root_context(callback: Box<NewRootContextFn>) {
     DISPATCHER.with(|dispatcher| dispatcher.set_root_context(callback));
@@ -38,8 +45,194 @@ pub(crate) fn set_http_context(callback: Box<NewHttpContextFn>) {
     DISPATCHER.with(|dispatcher| dispatcher.set_http_context(callback));
 }
 
-pub(crate) fn register_callout(token_id: u32) {
-    DISPATCHER.with(|dispatcher| dispatcher.register_callout(token_id));
+pub(crate) fn register_callout(token_id: u32) -> Result<()> {
+    DISPATCHER.with(|dispatcher| dispatcher.register_callout(token_id))
+}
+
+#[cfg(feature = "test-host")]
+pub(crate) fn seed_root_context(context_id: u32, root_context: Box<dyn RootContext>) {
+    DISPATCHER.with(|dispatcher| dispatcher.seed_root_context(context_id, root_context));
+}
+
+/// Returns `true` if the end of a given body of the running HTTP context has been received.
+pub(crate) fn http_body_complete(direction: StreamType) -> bool {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.http_body_complete(dispatcher.active_id.get(), direction))
+}
+
+/// Returns `true` if the previous body callback of the running HTTP context paused a given
+/// body, so the host still holds the data it handed out then.
+pub(crate) fn http_body_buffered(direction: StreamType) -> bool {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.http_body_buffered(dispatcher.active_id.get(), direction))
+}
+
+pub(crate) fn pending_http_calls(context_id: u32) -> Vec<u32> {
+    DISPATCHER.with(|dispatcher| dispatcher.pending_http_calls(context_id))
+}
+
+pub(crate) fn forget_http_calls(context_id: u32) -> usize {
+    DISPATCHER.with(|dispatcher| dispatcher.forget_http_calls(context_id))
+}
+
+pub(crate) fn cancel_http_call(token_id: u32) -> bool {
+    DISPATCHER.with(|dispatcher| dispatcher.cancel_http_call(token_id))
+}
+
+pub(crate) fn is_http_call_pending(token_id: u32) -> bool {
+    DISPATCHER.with(|dispatcher| dispatcher.callouts.borrow().contains_key(&token_id))
+}
+
+pub(crate) fn set_context_data(context_id: u32, data: Box<dyn Any>) -> Option<Box<dyn Any>> {
+    DISPATCHER.with(|dispatcher| dispatcher.set_context_data(context_id, data))
+}
+
+pub(crate) fn take_context_data(context_id: u32) -> Option<Box<dyn Any>> {
+    DISPATCHER.with(|dispatcher| dispatcher.take_context_data(context_id))
+}
+
+pub(crate) fn context_counts() -> (usize, usize, usize) {
+    DISPATCHER.with(|dispatcher| dispatcher.context_counts())
+}
+
+/// Returns `true` while a context callback is running, i.e. outside of `_start`
+/// and context factories.
+#[cfg(feature = "strict")]
+pub(crate) fn in_context() -> bool {
+    DISPATCHER.with(|dispatcher| dispatcher.active.get())
+}
+
+/// Returns the direction of the stream data callback that is running, if any.
+pub(crate) fn current_stream_data() -> Option<StreamType> {
+    DISPATCHER.with(|dispatcher| {
+        if !dispatcher.active.get() {
+            return None;
+        }
+        match dispatcher.callback.get() {
+            Callback::Downstream => Some(StreamType::Downstream),
+            Callback::Upstream => Some(StreamType::Upstream),
+            _ => None,
+        }
+    })
+}
+
+/// Runs a given function as if a context callback was running.
+#[cfg(test)]
+pub(crate) fn with_active_context<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    DISPATCHER.with(|dispatcher| {
+        let _active = dispatcher.enter(0, Callback::Other);
+        f()
+    })
+}
+
+#[cfg(feature = "strict")]
+pub(crate) fn buffer_matches_callback(buffer_type: BufferType) -> bool {
+    DISPATCHER.with(|dispatcher| buffer_type.matches_callback(dispatcher.callback.get()))
+}
+
+/// Actions that stream callbacks support.
+///
+/// Connection-level filters can only continue or stop iteration, so the HTTP-specific
+/// variants are mapped to one of those by `Action::network_status`, e.g. `PauseAndWatermark`
+/// stops iteration without applying flow control.
+#[cfg(feature = "strict")]
+const STREAM_ACTIONS: &[Action] = &[Action::Continue, Action::Pause];
+
+/// Actions that HTTP headers callbacks support, i.e. all but `PauseNoBuffer`,
+/// which `Action::headers_status` maps to a plain `StopIteration`.
+#[cfg(feature = "strict")]
+const HTTP_HEADERS_ACTIONS: &[Action] = &[
+    Action::Continue,
+    Action::Pause,
+    Action::ContinueAndEndStream,
+    Action::PauseAndBuffer,
+    Action::PauseAndWatermark,
+];
+
+/// Actions that HTTP body callbacks support, i.e. all but `ContinueAndEndStream`,
+/// which `Action::data_status` maps to a plain `Continue`.
+#[cfg(feature = "strict")]
+const HTTP_BODY_ACTIONS: &[Action] = &[
+    Action::Continue,
+    Action::Pause,
+    Action::PauseAndBuffer,
+    Action::PauseAndWatermark,
+    Action::PauseNoBuffer,
+];
+
+/// Actions that HTTP trailers callbacks support. Like connection-level filters,
+/// they can only continue or stop iteration.
+#[cfg(feature = "strict")]
+const HTTP_TRAILERS_ACTIONS: &[Action] = &[Action::Continue, Action::Pause];
+
+/// Checks that a callback returned an action the host understands for it.
+///
+/// Other actions are mapped to the closest supported behavior, as described on `Action`,
+/// so returning them is likely a mistake.
+#[cfg(feature = "strict")]
+fn assert_action(callback: &str, action: Action, supported: &[Action]) {
+    debug_assert!(
+        supported.contains(&action),
+        "{} returned Action::{:?}, but it only supports {:?}",
+        callback,
+        action,
+        supported
+    );
+}
+
+/// Kind of the host callback that is currently being dispatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Callback {
+    VmStart,
+    Configure,
+    Downstream,
+    Upstream,
+    HttpRequest,
+    HttpResponse,
+    HttpCallResponse,
+    Other,
+}
+
+impl BufferType {
+    /// Returns `true` if a buffer of this type can be accessed from a given callback.
+    ///
+    /// Callbacks that are not tied to a particular buffer (e.g. `on_tick` or `on_log`)
+    /// are allowed to access any buffer. HTTP bodies can also be accessed from
+    /// `on_http_call_response`, where a paused stream is typically modified before it
+    /// is resumed.
+    #[cfg(any(test, feature = "strict"))]
+    pub(crate) fn matches_callback(self, callback: Callback) -> bool {
+        match self {
+            BufferType::HttpRequestBody => {
+                matches!(
+                    callback,
+                    Callback::HttpRequest | Callback::HttpCallResponse | Callback::Other
+                )
+            }
+            BufferType::HttpResponseBody => matches!(
+                callback,
+                Callback::HttpResponse | Callback::HttpCallResponse | Callback::Other
+            ),
+            BufferType::DownstreamData => {
+                matches!(callback, Callback::Downstream | Callback::Other)
+            }
+            BufferType::UpstreamData => matches!(callback, Callback::Upstream | Callback::Other),
+            BufferType::HttpCallResponseBody => {
+                matches!(callback, Callback::HttpCallResponse | Callback::Other)
+            }
+            BufferType::VmConfiguration => matches!(
+                callback,
+                Callback::VmStart | Callback::Configure | Callback::Other
+            ),
+            BufferType::PluginConfiguration => {
+                matches!(callback, Callback::Configure | Callback::Other)
+            }
+            BufferType::GrpcReceiveBuffer | BufferType::CallData => true,
+        }
+    }
 }
 
 struct NoopRoot;
@@ -48,6 +241,7 @@ impl Context for NoopRoot {}
 impl RootContext for NoopRoot {}
 
 struct Dispatcher {
+    vm_init: RefCell<Option<Box<VmInitFn>>>,
     new_root: RefCell<Option<Box<NewRootContextFn>>>,
     roots: RefCell<HashMap<u32, Box<dyn RootContext>>>,
     new_stream: RefCell<Option<Box<NewStreamContextFn>>>,
@@ -55,12 +249,21 @@ struct Dispatcher {
     new_http_stream: RefCell<Option<Box<NewHttpContextFn>>>,
     http_streams: RefCell<HashMap<u32, Box<dyn HttpContext>>>,
     active_id: Cell<u32>,
-    callouts: RefCell<HashMap<u32, u32>>,
+    callback: Cell<Callback>,
+    active: Cell<bool>,
+    noop_root_warned: Cell<bool>,
+    deferred_children: RefCell<Vec<(u32, u32)>>,
+    callouts: RefCell<HashMap<u32, (u32, Option<StreamType>)>>,
+    call_direction: Cell<Option<StreamType>>,
+    complete_bodies: RefCell<HashMap<u32, (bool, bool)>>,
+    buffered_bodies: RefCell<HashMap<u32, (bool, bool)>>,
+    context_data: RefCell<HashMap<u32, Box<dyn Any>>>,
 }
 
 impl Dispatcher {
     fn new() -> Dispatcher {
         Dispatcher {
+            vm_init: RefCell::new(None),
             new_root: RefCell::new(None),
             roots: RefCell::new(HashMap::new()),
             new_stream: RefCell::new(None),
@@ -68,10 +271,31 @@ impl Dispatcher {
             new_http_stream: RefCell::new(None),
             http_streams: RefCell::new(HashMap::new()),
             active_id: Cell::new(0),
+            callback: Cell::new(Callback::Other),
+            active: Cell::new(false),
+            noop_root_warned: Cell::new(false),
+            deferred_children: RefCell::new(Vec::new()),
             callouts: RefCell::new(HashMap::new()),
+            call_direction: Cell::new(None),
+            complete_bodies: RefCell::new(HashMap::new()),
+            buffered_bodies: RefCell::new(HashMap::new()),
+            context_data: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn enter(&self, context_id: u32, callback: Callback) -> ActiveGuard<'_> {
+        self.active_id.set(context_id);
+        self.callback.set(callback);
+        ActiveGuard {
+            active: &self.active,
+            previous: self.active.replace(true),
         }
     }
 
+    fn set_vm_init(&self, callback: Box<VmInitFn>) {
+        self.vm_init.replace(Some(callback));
+    }
+
     fn set_root_context(&self, callback: Box<NewRootContextFn>) {
         self.new_root.replace(Some(callback));
     }
@@ -84,21 +308,131 @@ impl Dispatcher {
         self.new_http_stream.replace(Some(callback));
     }
 
-    fn register_callout(&self, token_id: u32) {
-        if self
+    fn register_callout(&self, token_id: u32) -> Result<()> {
+        let mut callouts = self.callouts.borrow_mut();
+        if callouts.contains_key(&token_id) {
+            return Err(format!("token_id {} is still pending a response", token_id).into());
+        }
+        // The direction to resume once the response arrives. Follow-up calls dispatched
+        // while handling a response inherit the direction of that response's call.
+        let direction = match self.callback.get() {
+            Callback::HttpRequest => Some(StreamType::Request),
+            Callback::HttpResponse => Some(StreamType::Response),
+            Callback::HttpCallResponse => self.call_direction.get(),
+            _ => None,
+        };
+        callouts.insert(token_id, (self.active_id.get(), direction));
+        Ok(())
+    }
+
+    fn http_body_complete(&self, context_id: u32, direction: StreamType) -> bool {
+        match (self.complete_bodies.borrow().get(&context_id), direction) {
+            (Some(&(request, _)), StreamType::Request) => request,
+            (Some(&(_, response)), StreamType::Response) => response,
+            _ => false,
+        }
+    }
+
+    fn complete_http_body(&self, context_id: u32, direction: StreamType) {
+        let mut complete_bodies = self.complete_bodies.borrow_mut();
+        let complete = complete_bodies.entry(context_id).or_insert((false, false));
+        match direction {
+            StreamType::Request => complete.0 = true,
+            StreamType::Response => complete.1 = true,
+            _ => {}
+        }
+    }
+
+    fn http_body_buffered(&self, context_id: u32, direction: StreamType) -> bool {
+        match (self.buffered_bodies.borrow().get(&context_id), direction) {
+            (Some(&(request, _)), StreamType::Request) => request,
+            (Some(&(_, response)), StreamType::Response) => response,
+            _ => false,
+        }
+    }
+
+    /// Records whether the host keeps the body handed out to a body callback,
+    /// i.e. whether the callback paused without dropping the data, see `Action::data_status`.
+    fn buffer_http_body(&self, context_id: u32, direction: StreamType, action: Action) {
+        let buffered = matches!(
+            action,
+            Action::Pause | Action::PauseAndBuffer | Action::PauseAndWatermark
+        );
+        let mut buffered_bodies = self.buffered_bodies.borrow_mut();
+        let state = buffered_bodies.entry(context_id).or_insert((false, false));
+        match direction {
+            StreamType::Request => state.0 = buffered,
+            StreamType::Response => state.1 = buffered,
+            _ => {}
+        }
+    }
+
+    fn pending_http_calls(&self, context_id: u32) -> Vec<u32> {
+        let mut tokens: Vec<u32> = self
             .callouts
+            .borrow()
+            .iter()
+            .filter(|(_, &(owner_id, _))| owner_id == context_id)
+            .map(|(&token_id, _)| token_id)
+            .collect();
+        tokens.sort_unstable();
+        tokens
+    }
+
+    fn forget_http_calls(&self, context_id: u32) -> usize {
+        let mut callouts = self.callouts.borrow_mut();
+        let len = callouts.len();
+        callouts.retain(|_, (owner_id, _)| *owner_id != context_id);
+        len - callouts.len()
+    }
+
+    fn cancel_http_call(&self, token_id: u32) -> bool {
+        self.callouts.borrow_mut().remove(&token_id).is_some()
+    }
+
+    fn set_context_data(&self, context_id: u32, data: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        self.context_data.borrow_mut().insert(context_id, data)
+    }
+
+    fn take_context_data(&self, context_id: u32) -> Option<Box<dyn Any>> {
+        self.context_data.borrow_mut().remove(&context_id)
+    }
+
+    fn context_counts(&self) -> (usize, usize, usize) {
+        (
+            self.roots.borrow().len(),
+            self.streams.borrow().len(),
+            self.http_streams.borrow().len(),
+        )
+    }
+
+    #[cfg(any(test, feature = "test-host"))]
+    fn seed_root_context(&self, context_id: u32, root_context: Box<dyn RootContext>) {
+        if self
+            .roots
             .borrow_mut()
-            .insert(token_id, self.active_id.get())
+            .insert(context_id, root_context)
             .is_some()
         {
-            panic!("duplicate token_id")
+            panic!("duplicate context_id")
         }
+        self.create_deferred_children(context_id);
     }
 
     fn create_root_context(&self, context_id: u32) {
         let new_context = match *self.new_root.borrow_mut() {
             Some(ref mut f) => f(context_id),
-            None => Box::new(NoopRoot),
+            None => {
+                if !self.noop_root_warned.replace(true) {
+                    hostcalls::log(
+                        LogLevel::Warn,
+                        "no root context has been set via set_root_context, \
+                         falling back to a root context that does nothing",
+                    )
+                    .unwrap_or(());
+                }
+                Box::new(NoopRoot)
+            }
         };
         if self
             .roots
@@ -111,15 +445,15 @@ impl Dispatcher {
     }
 
     fn create_stream_context(&self, context_id: u32, root_context_id: u32) {
-        let new_context = match self.roots.borrow().get(&root_context_id) {
-            Some(root_context) => match *self.new_stream.borrow_mut() {
-                Some(ref mut f) => f(context_id, root_context_id),
-                None => match root_context.create_stream_context(context_id) {
+        let new_context = match *self.new_stream.borrow_mut() {
+            Some(ref mut f) => f(context_id, root_context_id),
+            None => match self.roots.borrow().get(&root_context_id) {
+                Some(root_context) => match root_context.create_stream_context(context_id) {
                     Some(stream_context) => stream_context,
                     None => panic!("create_stream_context returned None"),
                 },
+                None => panic!("invalid root_context_id"),
             },
-            None => panic!("invalid root_context_id"),
         };
         if self
             .streams
@@ -132,15 +466,15 @@ impl Dispatcher {
     }
 
     fn create_http_context(&self, context_id: u32, root_context_id: u32) {
-        let new_context = match self.roots.borrow().get(&root_context_id) {
-            Some(root_context) => match *self.new_http_stream.borrow_mut() {
-                Some(ref mut f) => f(context_id, root_context_id),
-                None => match root_context.create_http_context(context_id) {
+        let new_context = match *self.new_http_stream.borrow_mut() {
+            Some(ref mut f) => f(context_id, root_context_id),
+            None => match self.roots.borrow().get(&root_context_id) {
+                Some(root_context) => match root_context.create_http_context(context_id) {
                     Some(stream_context) => stream_context,
                     None => panic!("create_http_context returned None"),
                 },
+                None => panic!("invalid root_context_id"),
             },
-            None => panic!("invalid root_context_id"),
         };
         if self
             .http_streams
@@ -153,9 +487,60 @@ impl Dispatcher {
     }
 
     fn on_create_context(&self, context_id: u32, root_context_id: u32) {
+        let vm_init = self.vm_init.borrow_mut().take();
+        if let Some(vm_init) = vm_init {
+            vm_init();
+        }
+        // Attribute HTTP calls dispatched from context factories to the new context
+        // rather than to whichever context ran last.
+        self.active_id.set(context_id);
+        self.callback.set(Callback::Other);
         if root_context_id == 0 {
             self.create_root_context(context_id);
-        } else if self.new_http_stream.borrow().is_some() {
+            self.create_deferred_children(context_id);
+        } else if !self.roots.borrow().contains_key(&root_context_id)
+            && self.new_http_stream.borrow().is_none()
+            && self.new_stream.borrow().is_none()
+        {
+            // Some hosts may deliver a child before its root, e.g. during a restart.
+            // Without a factory set via set_http_context or set_stream_context, only the
+            // root can create the child, so create it once the root arrives rather than
+            // trapping the VM.
+            hostcalls::log(
+                LogLevel::Warn,
+                &format!(
+                    "context {} was created before its root context {}, deferring it",
+                    context_id, root_context_id
+                ),
+            )
+            .unwrap_or(());
+            self.deferred_children
+                .borrow_mut()
+                .push((context_id, root_context_id));
+        } else {
+            self.create_child_context(context_id, root_context_id);
+        }
+    }
+
+    fn create_deferred_children(&self, root_context_id: u32) {
+        let mut children = Vec::new();
+        self.deferred_children
+            .borrow_mut()
+            .retain(|&(context_id, root_id)| {
+                if root_id == root_context_id {
+                    children.push(context_id);
+                }
+                root_id != root_context_id
+            });
+        for context_id in children {
+            self.active_id.set(context_id);
+            self.callback.set(Callback::Other);
+            self.create_child_context(context_id, root_context_id);
+        }
+    }
+
+    fn create_child_context(&self, context_id: u32, root_context_id: u32) {
+        if self.new_http_stream.borrow().is_some() {
             self.create_http_context(context_id, root_context_id);
         } else if self.new_stream.borrow().is_some() {
             self.create_stream_context(context_id, root_context_id);
@@ -174,48 +559,80 @@ impl Dispatcher {
         }
     }
 
+    /// Logs that a callback for a deferred or unknown child context is ignored.
+    fn ignore_unknown_context(&self, context_id: u32, callback: &str) {
+        let deferred = self
+            .deferred_children
+            .borrow()
+            .iter()
+            .any(|&(child_id, _)| child_id == context_id);
+        hostcalls::log(
+            LogLevel::Warn,
+            &format!(
+                "ignoring {} for {} context {}",
+                callback,
+                if deferred { "deferred" } else { "unknown" },
+                context_id
+            ),
+        )
+        .unwrap_or(());
+    }
+
     fn on_done(&self, context_id: u32) -> bool {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             http_stream.on_done()
         } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             stream.on_done()
         } else if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             root.on_done()
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_done");
+            true
         }
     }
 
     fn on_log(&self, context_id: u32) {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             http_stream.on_log()
         } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             stream.on_log()
         } else if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             root.on_log()
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_log")
         }
     }
 
     fn on_delete(&self, context_id: u32) {
+        {
+            let mut deferred_children = self.deferred_children.borrow_mut();
+            let len = deferred_children.len();
+            deferred_children.retain(|&(child_id, _)| child_id != context_id);
+            if deferred_children.len() != len {
+                return;
+            }
+        }
+        self.forget_http_calls(context_id);
+        self.complete_bodies.borrow_mut().remove(&context_id);
+        self.buffered_bodies.borrow_mut().remove(&context_id);
+        self.context_data.borrow_mut().remove(&context_id);
         if !(self.http_streams.borrow_mut().remove(&context_id).is_some()
             || self.streams.borrow_mut().remove(&context_id).is_some()
             || self.roots.borrow_mut().remove(&context_id).is_some())
         {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_delete")
         }
     }
 
     fn on_vm_start(&self, context_id: u32, vm_configuration_size: usize) -> bool {
         if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::VmStart);
             root.on_vm_start(vm_configuration_size)
         } else {
             panic!("invalid context_id")
@@ -224,7 +641,7 @@ impl Dispatcher {
 
     fn on_configure(&self, context_id: u32, plugin_configuration_size: usize) -> bool {
         if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Configure);
             root.on_configure(plugin_configuration_size)
         } else {
             panic!("invalid context_id")
@@ -233,7 +650,7 @@ impl Dispatcher {
 
     fn on_tick(&self, context_id: u32) {
         if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             root.on_tick()
         } else {
             panic!("invalid context_id")
@@ -242,7 +659,7 @@ impl Dispatcher {
 
     fn on_queue_ready(&self, context_id: u32, queue_id: u32) {
         if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Other);
             root.on_queue_ready(queue_id)
         } else {
             panic!("invalid context_id")
@@ -251,46 +668,58 @@ impl Dispatcher {
 
     fn on_new_connection(&self, context_id: u32) -> Action {
         if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            stream.on_new_connection()
+            let _active = self.enter(context_id, Callback::Downstream);
+            let action = stream.on_new_connection();
+            #[cfg(feature = "strict")]
+            assert_action("on_new_connection", action, STREAM_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_new_connection");
+            Action::Continue
         }
     }
 
     fn on_downstream_data(&self, context_id: u32, data_size: usize, end_of_stream: bool) -> Action {
         if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            stream.on_downstream_data(data_size, end_of_stream)
+            let _active = self.enter(context_id, Callback::Downstream);
+            let action = stream.on_downstream_data(data_size, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_downstream_data", action, STREAM_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_downstream_data");
+            Action::Continue
         }
     }
 
     fn on_downstream_close(&self, context_id: u32, peer_type: PeerType) {
         if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Downstream);
             stream.on_downstream_close(peer_type)
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_downstream_close")
         }
     }
 
     fn on_upstream_data(&self, context_id: u32, data_size: usize, end_of_stream: bool) -> Action {
         if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            stream.on_upstream_data(data_size, end_of_stream)
+            let _active = self.enter(context_id, Callback::Upstream);
+            let action = stream.on_upstream_data(data_size, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_upstream_data", action, STREAM_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_upstream_data");
+            Action::Continue
         }
     }
 
     fn on_upstream_close(&self, context_id: u32, peer_type: PeerType) {
         if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
+            let _active = self.enter(context_id, Callback::Upstream);
             stream.on_upstream_close(peer_type)
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_upstream_close")
         }
     }
 
@@ -301,10 +730,17 @@ impl Dispatcher {
         end_of_stream: bool,
     ) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_request_headers(num_headers, end_of_stream)
+            let _active = self.enter(context_id, Callback::HttpRequest);
+            if end_of_stream {
+                self.complete_http_body(context_id, StreamType::Request);
+            }
+            let action = http_stream.on_http_request_headers(num_headers, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_request_headers", action, HTTP_HEADERS_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_request_headers");
+            Action::Continue
         }
     }
 
@@ -315,19 +751,32 @@ impl Dispatcher {
         end_of_stream: bool,
     ) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_request_body(body_size, end_of_stream)
+            let _active = self.enter(context_id, Callback::HttpRequest);
+            if end_of_stream {
+                self.complete_http_body(context_id, StreamType::Request);
+            }
+            let action = http_stream.on_http_request_body(body_size, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_request_body", action, HTTP_BODY_ACTIONS);
+            self.buffer_http_body(context_id, StreamType::Request, action);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_request_body");
+            Action::Continue
         }
     }
 
     fn on_http_request_trailers(&self, context_id: u32, num_trailers: usize) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_request_trailers(num_trailers)
+            let _active = self.enter(context_id, Callback::HttpRequest);
+            self.complete_http_body(context_id, StreamType::Request);
+            let action = http_stream.on_http_request_trailers(num_trailers);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_request_trailers", action, HTTP_TRAILERS_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_request_trailers");
+            Action::Continue
         }
     }
 
@@ -338,10 +787,17 @@ impl Dispatcher {
         end_of_stream: bool,
     ) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_response_headers(num_headers, end_of_stream)
+            let _active = self.enter(context_id, Callback::HttpResponse);
+            if end_of_stream {
+                self.complete_http_body(context_id, StreamType::Response);
+            }
+            let action = http_stream.on_http_response_headers(num_headers, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_response_headers", action, HTTP_HEADERS_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_response_headers");
+            Action::Continue
         }
     }
 
@@ -352,48 +808,135 @@ impl Dispatcher {
         end_of_stream: bool,
     ) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_response_body(body_size, end_of_stream)
+            let _active = self.enter(context_id, Callback::HttpResponse);
+            if end_of_stream {
+                self.complete_http_body(context_id, StreamType::Response);
+            }
+            let action = http_stream.on_http_response_body(body_size, end_of_stream);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_response_body", action, HTTP_BODY_ACTIONS);
+            self.buffer_http_body(context_id, StreamType::Response, action);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_response_body");
+            Action::Continue
         }
     }
 
     fn on_http_response_trailers(&self, context_id: u32, num_trailers: usize) -> Action {
         if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            http_stream.on_http_response_trailers(num_trailers)
+            let _active = self.enter(context_id, Callback::HttpResponse);
+            self.complete_http_body(context_id, StreamType::Response);
+            let action = http_stream.on_http_response_trailers(num_trailers);
+            #[cfg(feature = "strict")]
+            assert_action("on_http_response_trailers", action, HTTP_TRAILERS_ACTIONS);
+            action
         } else {
-            panic!("invalid context_id")
+            self.ignore_unknown_context(context_id, "on_http_response_trailers");
+            Action::Continue
         }
     }
 
     fn on_http_call_response(
         &self,
+        context_id: u32,
         token_id: u32,
         num_headers: usize,
         body_size: usize,
         num_trailers: usize,
     ) {
-        let context_id = self
-            .callouts
-            .borrow_mut()
-            .remove(&token_id)
-            .expect("invalid token_id");
+        // Responses to forgotten or cancelled calls are dropped.
+        let (callout_context_id, direction) = match self.callouts.borrow_mut().remove(&token_id) {
+            Some(callout) => callout,
+            None => return,
+        };
 
-        if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            hostcalls::set_effective_context(context_id).unwrap();
-            http_stream.on_http_call_response(token_id, num_headers, body_size, num_trailers)
-        } else if let Some(stream) = self.streams.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            hostcalls::set_effective_context(context_id).unwrap();
+        let _guard = EffectiveContextGuard::enter(self, callout_context_id, context_id);
+        let previous_direction = self.call_direction.replace(direction);
+        if let Some(http_stream) = self.http_streams.borrow_mut().get_mut(&callout_context_id) {
+            let action = http_stream.on_http_call_response_resumable(
+                token_id,
+                num_headers,
+                body_size,
+                num_trailers,
+            );
+            if action == HttpCallAction::Resume {
+                match direction {
+                    // e.g. the stream was reset while the call was in flight
+                    Some(stream_type) => hostcalls::continue_stream(stream_type)
+                        .or_else(|err| {
+                            hostcalls::log(
+                                LogLevel::Warn,
+                                &format!(
+                                    "failed to resume {:?} after HTTP call {}: {}",
+                                    stream_type, token_id, err
+                                ),
+                            )
+                        })
+                        .unwrap_or(()),
+                    None => hostcalls::log(
+                        LogLevel::Warn,
+                        &format!(
+                            "HTTP call {} was not dispatched while processing a request \
+                             or a response, so there is nothing to resume",
+                            token_id
+                        ),
+                    )
+                    .unwrap_or(()),
+                }
+            }
+        } else if let Some(stream) = self.streams.borrow_mut().get_mut(&callout_context_id) {
             stream.on_http_call_response(token_id, num_headers, body_size, num_trailers)
-        } else if let Some(root) = self.roots.borrow_mut().get_mut(&context_id) {
-            self.active_id.set(context_id);
-            hostcalls::set_effective_context(context_id).unwrap();
+        } else if let Some(root) = self.roots.borrow_mut().get_mut(&callout_context_id) {
             root.on_http_call_response(token_id, num_headers, body_size, num_trailers)
         }
+        self.call_direction.set(previous_direction);
+    }
+}
+
+/// Marks a context callback as running until dropped.
+struct ActiveGuard<'a> {
+    active: &'a Cell<bool>,
+    previous: bool,
+}
+
+impl<'a> Drop for ActiveGuard<'a> {
+    fn drop(&mut self) {
+        self.active.set(self.previous);
+    }
+}
+
+/// Makes a given context effective and restores the previous one when dropped.
+struct EffectiveContextGuard<'a> {
+    dispatcher: &'a Dispatcher,
+    context_id: u32,
+    previous_id: u32,
+    previous_callback: Callback,
+    _active: ActiveGuard<'a>,
+}
+
+impl<'a> EffectiveContextGuard<'a> {
+    fn enter(dispatcher: &'a Dispatcher, context_id: u32, previous_id: u32) -> Self {
+        let previous_callback = dispatcher.callback.get();
+        let active = dispatcher.enter(context_id, Callback::HttpCallResponse);
+        hostcalls::set_effective_context(context_id).unwrap();
+        EffectiveContextGuard {
+            dispatcher,
+            context_id,
+            previous_id,
+            previous_callback,
+            _active: active,
+        }
+    }
+}
+
+impl<'a> Drop for EffectiveContextGuard<'a> {
+    fn drop(&mut self) {
+        self.dispatcher.active_id.set(self.previous_id);
+        self.dispatcher.callback.set(self.previous_callback);
+        if self.context_id != self.previous_id {
+            hostcalls::set_effective_context(self.previous_id).unwrap_or(());
+        }
     }
 }
 
@@ -438,8 +981,10 @@ pub extern "C" fn proxy_on_queue_ready(context_id: u32, queue_id: u32) {
 }
 
 #[no_mangle]
-pub extern "C" fn proxy_on_new_connection(context_id: u32) -> Action {
-    DISPATCHER.with(|dispatcher| dispatcher.on_new_connection(context_id))
+pub extern "C" fn proxy_on_new_connection(context_id: u32) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.on_new_connection(context_id))
+        .network_status()
 }
 
 #[no_mangle]
@@ -447,9 +992,10 @@ pub extern "C" fn proxy_on_downstream_data(
     context_id: u32,
     data_size: usize,
     end_of_stream: bool,
-) -> Action {
+) -> u32 {
     DISPATCHER
         .with(|dispatcher| dispatcher.on_downstream_data(context_id, data_size, end_of_stream))
+        .network_status()
 }
 
 #[no_mangle]
@@ -462,8 +1008,10 @@ pub extern "C" fn proxy_on_upstream_data(
     context_id: u32,
     data_size: usize,
     end_of_stream: bool,
-) -> Action {
-    DISPATCHER.with(|dispatcher| dispatcher.on_upstream_data(context_id, data_size, end_of_stream))
+) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.on_upstream_data(context_id, data_size, end_of_stream))
+        .network_status()
 }
 
 #[no_mangle]
@@ -476,10 +1024,12 @@ pub extern "C" fn proxy_on_request_headers(
     context_id: u32,
     num_headers: usize,
     end_of_stream: bool,
-) -> Action {
-    DISPATCHER.with(|dispatcher| {
-        dispatcher.on_http_request_headers(context_id, num_headers, end_of_stream)
-    })
+) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| {
+            dispatcher.on_http_request_headers(context_id, num_headers, end_of_stream)
+        })
+        .headers_status()
 }
 
 #[no_mangle]
@@ -487,14 +1037,17 @@ pub extern "C" fn proxy_on_request_body(
     context_id: u32,
     body_size: usize,
     end_of_stream: bool,
-) -> Action {
+) -> u32 {
     DISPATCHER
         .with(|dispatcher| dispatcher.on_http_request_body(context_id, body_size, end_of_stream))
+        .data_status()
 }
 
 #[no_mangle]
-pub extern "C" fn proxy_on_request_trailers(context_id: u32, num_trailers: usize) -> Action {
-    DISPATCHER.with(|dispatcher| dispatcher.on_http_request_trailers(context_id, num_trailers))
+pub extern "C" fn proxy_on_request_trailers(context_id: u32, num_trailers: usize) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.on_http_request_trailers(context_id, num_trailers))
+        .trailers_status()
 }
 
 #[no_mangle]
@@ -502,10 +1055,12 @@ pub extern "C" fn proxy_on_response_headers(
     context_id: u32,
     num_headers: usize,
     end_of_stream: bool,
-) -> Action {
-    DISPATCHER.with(|dispatcher| {
-        dispatcher.on_http_response_headers(context_id, num_headers, end_of_stream)
-    })
+) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| {
+            dispatcher.on_http_response_headers(context_id, num_headers, end_of_stream)
+        })
+        .headers_status()
 }
 
 #[no_mangle]
@@ -513,25 +1068,796 @@ pub extern "C" fn proxy_on_response_body(
     context_id: u32,
     body_size: usize,
     end_of_stream: bool,
-) -> Action {
+) -> u32 {
     DISPATCHER
         .with(|dispatcher| dispatcher.on_http_response_body(context_id, body_size, end_of_stream))
+        .data_status()
 }
 
 #[no_mangle]
-pub extern "C" fn proxy_on_response_trailers(context_id: u32, num_trailers: usize) -> Action {
-    DISPATCHER.with(|dispatcher| dispatcher.on_http_response_trailers(context_id, num_trailers))
+pub extern "C" fn proxy_on_response_trailers(context_id: u32, num_trailers: usize) -> u32 {
+    DISPATCHER
+        .with(|dispatcher| dispatcher.on_http_response_trailers(context_id, num_trailers))
+        .trailers_status()
 }
 
 #[no_mangle]
 pub extern "C" fn proxy_on_http_call_response(
-    _context_id: u32,
+    context_id: u32,
     token_id: u32,
     num_headers: usize,
     body_size: usize,
     num_trailers: usize,
 ) {
     DISPATCHER.with(|dispatcher| {
-        dispatcher.on_http_call_response(token_id, num_headers, body_size, num_trailers)
+        dispatcher.on_http_call_response(context_id, token_id, num_headers, body_size, num_trailers)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    thread_local! {
+        static EFFECTIVE_CONTEXTS: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) };
+        static LOGGED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        static CONTINUED: RefCell<Vec<StreamType>> = const { RefCell::new(Vec::new()) };
+        static CONTINUE_STATUS: Cell<Status> = const { Cell::new(Status::Ok) };
+        static NEXT_TOKEN: Cell<u32> = const { Cell::new(100) };
+        static RESPONSES: RefCell<Vec<(&'static str, u32)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_http_call(
+        _upstream_data: *const u8,
+        _upstream_size: usize,
+        _headers_data: *const u8,
+        _headers_size: usize,
+        _body_data: *const u8,
+        _body_size: usize,
+        _trailers_data: *const u8,
+        _trailers_size: usize,
+        _timeout: u32,
+        return_token: *mut u32,
+    ) -> RawStatus {
+        let token = NEXT_TOKEN.with(|next| {
+            next.set(next.get() + 1);
+            next.get()
+        });
+        unsafe { *return_token = token };
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_continue_stream(stream_type: StreamType) -> RawStatus {
+        CONTINUED.with(|continued| continued.borrow_mut().push(stream_type));
+        CONTINUE_STATUS.with(|status| status.get()).into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_log(
+        _level: LogLevel,
+        message_data: *const u8,
+        message_size: usize,
+    ) -> RawStatus {
+        let message = unsafe { std::slice::from_raw_parts(message_data, message_size) };
+        LOGGED.with(|logged| {
+            logged
+                .borrow_mut()
+                .push(String::from_utf8_lossy(message).into_owned())
+        });
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_set_effective_context(context_id: u32) -> RawStatus {
+        EFFECTIVE_CONTEXTS.with(|contexts| contexts.borrow_mut().push(context_id));
+        Status::Ok.into()
+    }
+
+    struct TestHttp;
+
+    impl Context for TestHttp {}
+    impl HttpContext for TestHttp {}
+
+    struct ResumingHttp;
+
+    impl Context for ResumingHttp {}
+    impl HttpContext for ResumingHttp {
+        fn on_http_call_response_resumable(
+            &mut self,
+            token_id: u32,
+            _num_headers: usize,
+            _body_size: usize,
+            _num_trailers: usize,
+        ) -> HttpCallAction {
+            if token_id == 9 {
+                HttpCallAction::KeepPaused
+            } else {
+                HttpCallAction::Resume
+            }
+        }
+    }
+
+    struct ChainingHttp;
+
+    impl Context for ChainingHttp {}
+    impl HttpContext for ChainingHttp {
+        fn on_http_response_headers(&mut self, _: usize, _: bool) -> Action {
+            dispatch(self);
+            Action::Pause
+        }
+
+        fn on_http_call_response_resumable(
+            &mut self,
+            _token_id: u32,
+            _num_headers: usize,
+            _body_size: usize,
+            num_trailers: usize,
+        ) -> HttpCallAction {
+            // the first response asks for a second call, which then resumes the stream
+            if num_trailers == 0 {
+                dispatch(self);
+                HttpCallAction::KeepPaused
+            } else {
+                HttpCallAction::Resume
+            }
+        }
+    }
+
+    struct BodyWatchingHttp {
+        seen: Rc<RefCell<Vec<bool>>>,
+    }
+
+    impl Context for BodyWatchingHttp {}
+    impl HttpContext for BodyWatchingHttp {
+        fn on_http_request_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Action {
+            self.seen
+                .borrow_mut()
+                .push(self.http_request_body_complete());
+            Action::Continue
+        }
+    }
+
+    struct EndStreamHttp;
+
+    impl Context for EndStreamHttp {}
+    impl HttpContext for EndStreamHttp {
+        fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+            Action::ContinueAndEndStream
+        }
+
+        fn on_http_request_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Action {
+            Action::ContinueAndEndStream
+        }
+    }
+
+    struct WatermarkHttp;
+
+    impl Context for WatermarkHttp {}
+    impl HttpContext for WatermarkHttp {
+        fn on_http_response_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Action {
+            Action::PauseAndWatermark
+        }
+
+        fn on_http_response_trailers(&mut self, _num_trailers: usize) -> Action {
+            Action::PauseAndWatermark
+        }
+    }
+
+    struct WatermarkStream;
+
+    impl Context for WatermarkStream {}
+    impl StreamContext for WatermarkStream {
+        fn on_downstream_data(&mut self, _data_size: usize, _end_of_stream: bool) -> Action {
+            Action::PauseAndWatermark
+        }
+    }
+
+    fn dispatch(context: &dyn Context) -> u32 {
+        context
+            .dispatch_http_call("secrets", vec![], None, vec![], Duration::from_secs(1))
+            .unwrap()
+    }
+
+    struct CalloutHttp;
+
+    impl Context for CalloutHttp {
+        fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
+            RESPONSES.with(|responses| responses.borrow_mut().push(("http", token_id)));
+        }
+    }
+
+    impl HttpContext for CalloutHttp {
+        fn on_http_request_headers(&mut self, _: usize, _: bool) -> Action {
+            dispatch(self);
+            Action::Pause
+        }
+    }
+
+    struct CalloutRoot;
+
+    impl Context for CalloutRoot {
+        fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
+            RESPONSES.with(|responses| responses.borrow_mut().push(("root", token_id)));
+        }
+    }
+
+    impl RootContext for CalloutRoot {
+        fn on_vm_start(&mut self, _: usize) -> bool {
+            dispatch(self);
+            true
+        }
+
+        fn on_configure(&mut self, _: usize) -> bool {
+            dispatch(self);
+            true
+        }
+
+        fn on_tick(&mut self) {
+            dispatch(self);
+        }
+
+        fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+            Some(Box::new(CalloutHttp))
+        }
+
+        fn get_type(&self) -> Option<ContextType> {
+            Some(ContextType::HttpContext)
+        }
+    }
+
+    struct TestRoot;
+
+    impl Context for TestRoot {}
+    impl RootContext for TestRoot {
+        fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+            Some(Box::new(TestHttp))
+        }
+
+        fn get_type(&self) -> Option<ContextType> {
+            Some(ContextType::HttpContext)
+        }
+    }
+
+    #[test]
+    fn test_context_counts() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        assert_eq!(dispatcher.context_counts(), (0, 0, 0));
+
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_create_context(3, 1);
+        assert_eq!(dispatcher.context_counts(), (1, 0, 2));
+
+        dispatcher.on_delete(2);
+        assert_eq!(dispatcher.context_counts(), (1, 0, 1));
+    }
+
+    #[test]
+    #[cfg(feature = "strict")]
+    #[should_panic(expected = "on_downstream_data returned Action::PauseAndWatermark")]
+    fn test_strict_stream_action() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.seed_root_context(1, Box::new(TestRoot));
+        dispatcher.set_stream_context(Box::new(|_, _| Box::new(WatermarkStream)));
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_downstream_data(2, 0, false);
+    }
+
+    #[test]
+    #[cfg(feature = "strict")]
+    fn test_strict_http_action() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.seed_root_context(1, Box::new(TestRoot));
+        dispatcher.set_http_context(Box::new(|context_id, _| match context_id {
+            2 => Box::new(EndStreamHttp),
+            _ => Box::new(WatermarkHttp),
+        }));
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_create_context(3, 1);
+        assert_eq!(
+            dispatcher.on_http_request_headers(2, 0, false),
+            Action::ContinueAndEndStream
+        );
+        assert_eq!(
+            dispatcher.on_http_response_body(3, 0, false),
+            Action::PauseAndWatermark
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "strict")]
+    #[should_panic(expected = "on_http_request_body returned Action::ContinueAndEndStream")]
+    fn test_strict_http_body_action() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.seed_root_context(1, Box::new(TestRoot));
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(EndStreamHttp)));
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_http_request_body(2, 0, false);
+    }
+
+    #[test]
+    #[cfg(feature = "strict")]
+    #[should_panic(expected = "on_http_response_trailers returned Action::PauseAndWatermark")]
+    fn test_strict_http_trailers_action() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.seed_root_context(1, Box::new(TestRoot));
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(WatermarkHttp)));
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_http_response_trailers(2, 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict"))]
+    fn test_http_action_status() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.seed_root_context(1, Box::new(TestRoot));
+            dispatcher.set_http_context(Box::new(|context_id, _| match context_id {
+                2 => Box::new(EndStreamHttp),
+                _ => Box::new(WatermarkHttp),
+            }));
+            dispatcher.on_create_context(2, 1);
+            dispatcher.on_create_context(3, 1);
+        });
+        assert_eq!(super::proxy_on_request_headers(2, 0, false), 2);
+        // body callbacks cannot end the stream, so they just continue
+        assert_eq!(super::proxy_on_request_body(2, 0, false), 0);
+        assert_eq!(super::proxy_on_response_body(3, 0, false), 2);
+        // trailers callbacks only understand Continue (0) and StopIteration (1)
+        assert_eq!(super::proxy_on_response_trailers(3, 0), 1);
+    }
+
+    #[test]
+    #[cfg(not(feature = "strict"))]
+    fn test_stream_data_network_status() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.seed_root_context(1, Box::new(TestRoot));
+            dispatcher.set_stream_context(Box::new(|_, _| Box::new(WatermarkStream)));
+            dispatcher.on_create_context(2, 1);
+        });
+        // network filters only understand Continue (0) and StopIteration (1)
+        assert_eq!(super::proxy_on_downstream_data(2, 0, false), 1);
+    }
+
+    #[test]
+    fn test_child_before_root_is_created_by_factory() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+
+        // the factory needs no root, so there is nothing to wait for
+        dispatcher.on_create_context(2, 1);
+        assert_eq!(dispatcher.context_counts(), (0, 0, 1));
+        assert!(dispatcher.deferred_children.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_child_before_root_is_deferred() {
+        let dispatcher = Dispatcher::new();
+
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_create_context(3, 1);
+        assert_eq!(
+            dispatcher.on_http_request_headers(2, 0, false),
+            Action::Continue
+        );
+        dispatcher.on_log(2);
+        dispatcher.on_delete(3);
+        assert_eq!(dispatcher.context_counts(), (0, 0, 0));
+        LOGGED.with(|logged| {
+            let logged = logged.borrow();
+            assert_eq!(logged.len(), 4);
+            assert!(logged[0].contains("before its root context 1"));
+            assert_eq!(
+                logged[2],
+                "ignoring on_http_request_headers for deferred context 2"
+            );
+            assert_eq!(logged[3], "ignoring on_log for deferred context 2");
+        });
+
+        dispatcher.set_root_context(Box::new(|_| Box::new(TestRoot)));
+        dispatcher.on_create_context(1, 0);
+        assert_eq!(dispatcher.context_counts(), (1, 0, 1));
+        assert!(dispatcher.http_streams.borrow().contains_key(&2));
+    }
+
+    #[test]
+    fn test_unknown_context_is_ignored() {
+        let dispatcher = Dispatcher::new();
+
+        assert_eq!(
+            dispatcher.on_http_response_body(9, 10, true),
+            Action::Continue
+        );
+        assert_eq!(
+            dispatcher.on_downstream_data(9, 10, false),
+            Action::Continue
+        );
+        assert!(dispatcher.on_done(9));
+        dispatcher.on_delete(9);
+        LOGGED.with(|logged| {
+            assert_eq!(
+                *logged.borrow(),
+                vec![
+                    "ignoring on_http_response_body for unknown context 9",
+                    "ignoring on_downstream_data for unknown context 9",
+                    "ignoring on_done for unknown context 9",
+                    "ignoring on_delete for unknown context 9",
+                ]
+            )
+        });
+    }
+
+    #[test]
+    fn test_vm_init_runs_once_before_contexts() {
+        let calls = Rc::new(Cell::new(0));
+        let dispatcher = Dispatcher::new();
+        let init_calls = Rc::clone(&calls);
+        dispatcher.set_vm_init(Box::new(move || init_calls.set(init_calls.get() + 1)));
+        let root_calls = Rc::clone(&calls);
+        dispatcher.set_root_context(Box::new(move |_| {
+            assert_eq!(root_calls.get(), 1);
+            Box::new(TestRoot)
+        }));
+
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 0);
+        dispatcher.on_create_context(3, 1);
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_noop_root_warns_once() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 0);
+        LOGGED.with(|logged| {
+            let logged = logged.borrow();
+            assert_eq!(logged.len(), 1);
+            assert!(logged[0].contains("set_root_context"));
+        });
+    }
+
+    #[test]
+    fn test_seed_root_context() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.seed_root_context(7, Box::new(TestRoot));
+
+        dispatcher.on_create_context(8, 7);
+        assert_eq!(dispatcher.context_counts(), (1, 0, 1));
+    }
+
+    #[test]
+    fn test_register_callout_duplicate() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.register_callout(5).unwrap();
+        let err = dispatcher.register_callout(5).unwrap_err();
+        assert_eq!(err.to_string(), "token_id 5 is still pending a response");
+        dispatcher.register_callout(6).unwrap();
+    }
+
+    #[test]
+    fn test_http_body_complete() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_create_context(3, 1);
+
+        dispatcher.on_http_request_headers(2, 3, false);
+        dispatcher.on_http_request_body(2, 10, false);
+        assert!(!dispatcher.http_body_complete(2, StreamType::Request));
+        dispatcher.on_http_request_body(2, 10, true);
+        assert!(dispatcher.http_body_complete(2, StreamType::Request));
+
+        // Lookups are keyed on the context, not on whichever one ran last.
+        dispatcher.on_http_request_headers(3, 3, false);
+        assert!(!dispatcher.http_body_complete(3, StreamType::Request));
+        assert!(dispatcher.http_body_complete(2, StreamType::Request));
+
+        dispatcher.on_http_response_headers(2, 3, false);
+        assert!(!dispatcher.http_body_complete(2, StreamType::Response));
+        dispatcher.on_http_response_trailers(2, 1);
+        assert!(dispatcher.http_body_complete(2, StreamType::Response));
+
+        dispatcher.on_delete(2);
+        dispatcher.on_create_context(2, 1);
+        dispatcher.on_http_request_headers(2, 3, false);
+        assert!(!dispatcher.http_body_complete(2, StreamType::Request));
+    }
+
+    #[test]
+    fn test_http_body_complete_from_context() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let context_seen = Rc::clone(&seen);
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.set_http_context(Box::new(move |_, _| {
+                Box::new(BodyWatchingHttp {
+                    seen: Rc::clone(&context_seen),
+                })
+            }));
+            dispatcher.on_create_context(1, 0);
+            dispatcher.on_create_context(2, 1);
+        });
+
+        super::proxy_on_request_body(2, 10, false);
+        super::proxy_on_request_body(2, 10, true);
+        assert_eq!(*seen.borrow(), vec![false, true]);
+    }
+
+    #[test]
+    fn test_http_body_buffered() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+        assert!(!dispatcher.http_body_buffered(2, StreamType::Request));
+
+        dispatcher.buffer_http_body(2, StreamType::Request, Action::Pause);
+        assert!(dispatcher.http_body_buffered(2, StreamType::Request));
+        assert!(!dispatcher.http_body_buffered(2, StreamType::Response));
+
+        // the host drops the chunk it handed out
+        dispatcher.buffer_http_body(2, StreamType::Request, Action::PauseNoBuffer);
+        assert!(!dispatcher.http_body_buffered(2, StreamType::Request));
+
+        dispatcher.buffer_http_body(2, StreamType::Response, Action::PauseAndBuffer);
+        dispatcher.on_http_response_body(2, 0, false);
+        assert!(!dispatcher.http_body_buffered(2, StreamType::Response));
+
+        dispatcher.buffer_http_body(2, StreamType::Request, Action::Pause);
+        dispatcher.on_delete(2);
+        assert!(!dispatcher.http_body_buffered(2, StreamType::Request));
+    }
+
+    #[test]
+    fn test_http_call_response_restores_effective_context() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        dispatcher.on_http_request_headers(2, 0, false);
+        dispatcher.register_callout(5).unwrap();
+        dispatcher.on_http_call_response(1, 5, 0, 0, 0);
+
+        assert_eq!(dispatcher.active_id.get(), 1);
+        assert_eq!(dispatcher.callback.get(), Callback::HttpRequest);
+        EFFECTIVE_CONTEXTS.with(|contexts| assert_eq!(*contexts.borrow(), vec![2, 1]));
+    }
+
+    #[test]
+    fn test_active_only_during_callbacks() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+        assert!(!dispatcher.active.get());
+
+        {
+            let _active = dispatcher.enter(2, Callback::HttpRequest);
+            let _nested = dispatcher.enter(2, Callback::HttpRequest);
+            assert!(dispatcher.active.get());
+        }
+        dispatcher.on_http_request_headers(2, 0, false);
+        assert!(!dispatcher.active.get());
+    }
+
+    #[test]
+    fn test_context_data() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        assert!(dispatcher.set_context_data(2, Box::new(7u32)).is_none());
+        let previous = dispatcher.set_context_data(2, Box::new("done")).unwrap();
+        assert_eq!(previous.downcast_ref::<u32>(), Some(&7));
+        let data = dispatcher.take_context_data(2).unwrap();
+        assert_eq!(data.downcast_ref::<&str>(), Some(&"done"));
+        assert!(dispatcher.take_context_data(2).is_none());
+
+        dispatcher.set_context_data(2, Box::new(1u8));
+        dispatcher.on_delete(2);
+        assert!(dispatcher.take_context_data(2).is_none());
+    }
+
+    #[test]
+    fn test_buffer_matches_callback() {
+        assert!(BufferType::HttpRequestBody.matches_callback(Callback::HttpRequest));
+        assert!(!BufferType::HttpRequestBody.matches_callback(Callback::HttpResponse));
+        assert!(!BufferType::HttpResponseBody.matches_callback(Callback::HttpRequest));
+        assert!(!BufferType::DownstreamData.matches_callback(Callback::Upstream));
+        assert!(BufferType::HttpRequestBody.matches_callback(Callback::HttpCallResponse));
+        assert!(BufferType::HttpResponseBody.matches_callback(Callback::HttpCallResponse));
+        assert!(!BufferType::DownstreamData.matches_callback(Callback::HttpCallResponse));
+        assert!(!BufferType::HttpCallResponseBody.matches_callback(Callback::HttpRequest));
+        assert!(BufferType::HttpCallResponseBody.matches_callback(Callback::HttpCallResponse));
+        assert!(!BufferType::PluginConfiguration.matches_callback(Callback::VmStart));
+        assert!(BufferType::HttpResponseBody.matches_callback(Callback::Other));
+        assert!(BufferType::CallData.matches_callback(Callback::HttpRequest));
+    }
+
+    #[test]
+    fn test_cancel_http_call() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.set_http_context(Box::new(|_, _| Box::new(CalloutHttp)));
+            dispatcher.on_create_context(1, 0);
+            dispatcher.on_create_context(2, 1);
+
+            dispatcher.on_http_request_headers(2, 0, false);
+            dispatcher.on_http_request_headers(2, 0, false);
+            let tokens = dispatcher.pending_http_calls(2);
+            assert_eq!(tokens.len(), 2);
+
+            assert!(dispatcher.cancel_http_call(tokens[0]));
+            assert!(!dispatcher.cancel_http_call(tokens[0]));
+            assert_eq!(dispatcher.pending_http_calls(2), vec![tokens[1]]);
+
+            // a response to a cancelled call is dropped without running the handler
+            dispatcher.on_http_call_response(1, tokens[0], 0, 0, 0);
+            assert_eq!(dispatcher.pending_http_calls(2), vec![tokens[1]]);
+        });
+        RESPONSES.with(|responses| assert!(responses.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_forget_http_calls() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        dispatcher.on_configure(1, 0);
+        dispatcher.register_callout(7).unwrap();
+        dispatcher.register_callout(3).unwrap();
+        dispatcher.on_http_request_headers(2, 0, false);
+        dispatcher.register_callout(5).unwrap();
+        assert_eq!(dispatcher.pending_http_calls(1), vec![3, 7]);
+
+        assert_eq!(dispatcher.forget_http_calls(1), 2);
+        assert!(dispatcher.pending_http_calls(1).is_empty());
+        assert_eq!(dispatcher.pending_http_calls(2), vec![5]);
+
+        // a response to a forgotten call is dropped
+        dispatcher.on_http_call_response(1, 7, 0, 0, 0);
+
+        dispatcher.on_delete(2);
+        assert!(dispatcher.pending_http_calls(2).is_empty());
+    }
+
+    #[test]
+    fn test_http_call_response_resumes_stream() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(ResumingHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        dispatcher.on_http_request_headers(2, 0, false);
+        dispatcher.register_callout(5).unwrap();
+        dispatcher.register_callout(9).unwrap();
+        dispatcher.on_http_response_headers(2, 0, false);
+        dispatcher.register_callout(6).unwrap();
+
+        dispatcher.on_http_call_response(1, 6, 0, 0, 0);
+        dispatcher.on_http_call_response(1, 9, 0, 0, 0);
+        dispatcher.on_http_call_response(1, 5, 0, 0, 0);
+        CONTINUED.with(|continued| {
+            assert_eq!(
+                *continued.borrow(),
+                vec![StreamType::Response, StreamType::Request]
+            )
+        });
+    }
+
+    #[test]
+    fn test_http_call_response_resume_failure_is_logged() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(ResumingHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        dispatcher.on_http_request_headers(2, 0, false);
+        dispatcher.register_callout(5).unwrap();
+        // the stream has been reset while the call was in flight
+        CONTINUE_STATUS.with(|status| status.set(Status::NotFound));
+        dispatcher.on_http_call_response(1, 5, 0, 0, 0);
+        CONTINUED.with(|continued| assert_eq!(*continued.borrow(), vec![StreamType::Request]));
+        LOGGED.with(|logged| {
+            assert_eq!(
+                logged.borrow().last().unwrap(),
+                "failed to resume Request after HTTP call 5: call to the host ABI function \
+                 \"env.proxy_continue_stream\" has failed with status code 1"
+            )
+        });
+    }
+
+    #[test]
+    fn test_follow_up_call_resumes_original_direction() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.set_http_context(Box::new(|_, _| Box::new(ChainingHttp)));
+            dispatcher.on_create_context(1, 0);
+            dispatcher.on_create_context(2, 1);
+            dispatcher.on_http_response_headers(2, 0, false);
+            assert_eq!(dispatcher.pending_http_calls(2), vec![101]);
+
+            dispatcher.on_http_call_response(1, 101, 0, 0, 0);
+            assert_eq!(dispatcher.pending_http_calls(2), vec![102]);
+            dispatcher.on_http_call_response(1, 102, 0, 0, 1);
+        });
+        CONTINUED.with(|continued| assert_eq!(*continued.borrow(), vec![StreamType::Response]));
+    }
+
+    #[test]
+    fn test_http_call_response_without_direction_is_not_resumed() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(ResumingHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        {
+            // e.g. a call dispatched from on_log
+            let _active = dispatcher.enter(2, Callback::Other);
+            dispatcher.register_callout(5).unwrap();
+        }
+        dispatcher.on_http_call_response(1, 5, 0, 0, 0);
+        CONTINUED.with(|continued| assert!(continued.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_http_call_response_keeps_stream_paused_by_default() {
+        let dispatcher = Dispatcher::new();
+        dispatcher.set_http_context(Box::new(|_, _| Box::new(TestHttp)));
+        dispatcher.on_create_context(1, 0);
+        dispatcher.on_create_context(2, 1);
+
+        dispatcher.on_http_request_headers(2, 0, false);
+        dispatcher.register_callout(5).unwrap();
+        dispatcher.on_http_call_response(1, 5, 0, 0, 0);
+        CONTINUED.with(|continued| assert!(continued.borrow().is_empty()));
+    }
+
+    #[test]
+    fn test_root_callouts_route_to_root() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.set_root_context(Box::new(|_| Box::new(CalloutRoot)));
+            dispatcher.on_create_context(1, 0);
+            dispatcher.on_vm_start(1, 0);
+            dispatcher.on_configure(1, 0);
+            dispatcher.on_create_context(2, 1);
+            dispatcher.on_http_request_headers(2, 0, false);
+            dispatcher.on_tick(1);
+            assert_eq!(dispatcher.pending_http_calls(1), vec![101, 102, 104]);
+            assert_eq!(dispatcher.pending_http_calls(2), vec![103]);
+
+            for token_id in 101..=104 {
+                dispatcher.on_http_call_response(1, token_id, 0, 0, 0);
+            }
+        });
+        RESPONSES.with(|responses| {
+            assert_eq!(
+                *responses.borrow(),
+                vec![("root", 101), ("root", 102), ("http", 103), ("root", 104)]
+            )
+        });
+    }
+
+    #[test]
+    fn test_factory_callouts_route_to_new_context() {
+        DISPATCHER.with(|dispatcher| {
+            dispatcher.set_http_context(Box::new(|_, _| {
+                dispatch(&TestHttp);
+                Box::new(TestHttp)
+            }));
+            dispatcher.on_create_context(1, 0);
+            dispatcher.on_create_context(2, 1);
+            dispatcher.on_http_request_headers(2, 0, false);
+            dispatcher.on_create_context(3, 1);
+            assert_eq!(dispatcher.pending_http_calls(2), vec![101]);
+            assert_eq!(dispatcher.pending_http_calls(3), vec![102]);
+        });
+    }
+}
@@ -12,14 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::fmt;
+#[cfg(feature = "std")]
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
 
-use crate::types::Status;
+use crate::types::{ByteString, Status};
 
 /// A boxed [`Error`].
 ///
 /// [`Error`]: https://doc.rust-lang.org/std/fmt/struct.Error.html
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Error = Box<dyn core::error::Error + Send + Sync>;
 
 /// A specialized [`Result`] type.
 ///
@@ -28,21 +33,22 @@ pub type Result<T> = core::result::Result<T, Error>;
 
 /// An error to call a Host ABI function.
 #[derive(Debug)]
-pub struct HostCallError<'a> {
-    function: &'a str,
+pub struct HostCallError {
+    function: &'static str,
     status: Status,
 }
 
-impl<'a> HostCallError<'a> {
-    pub(crate) fn new(function: &'a str, status: Status) -> Self {
+impl HostCallError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(function: &'static str, status: Status) -> Self {
         HostCallError { function, status }
     }
 
-    pub fn module(&self) -> &'a str {
+    pub fn module(&self) -> &'static str {
         "env"
     }
 
-    pub fn function(&self) -> &'a str {
+    pub fn function(&self) -> &'static str {
         self.function
     }
 
@@ -51,42 +57,48 @@ impl<'a> HostCallError<'a> {
     }
 }
 
-impl<'a> fmt::Display for HostCallError<'a> {
+impl fmt::Display for HostCallError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "call to the host ABI function \"{}.{}\" has failed with status code {}",
             self.module(),
             self.function,
-            self.status as u32,
+            self.status.code(),
         )
     }
 }
 
-impl<'a> std::error::Error for HostCallError<'a> {}
+impl core::error::Error for HostCallError {}
 
 /// An error to parse the response from a Host ABI.
+///
+/// The underlying error is available via `source()`, e.g. a [`MapDecodeError`]
+/// for a malformed header map.
+///
+/// [`MapDecodeError`]: enum.MapDecodeError.html
 #[derive(Debug)]
-pub struct HostResponseError<'a> {
-    function: &'a str,
+pub struct HostResponseError {
+    function: &'static str,
     error: Error,
 }
 
-impl<'a> HostResponseError<'a> {
-    pub(crate) fn new(function: &'a str, error: Error) -> Self {
+impl HostResponseError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(function: &'static str, error: Error) -> Self {
         HostResponseError { function, error }
     }
 
-    pub fn module(&self) -> &'a str {
+    pub fn module(&self) -> &'static str {
         "env"
     }
 
-    pub fn function(&self) -> &'a str {
+    pub fn function(&self) -> &'static str {
         self.function
     }
 }
 
-impl<'a> fmt::Display for HostResponseError<'a> {
+impl fmt::Display for HostResponseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -98,8 +110,290 @@ impl<'a> fmt::Display for HostResponseError<'a> {
     }
 }
 
-impl<'a> std::error::Error for HostResponseError<'a> {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+impl core::error::Error for HostResponseError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// An error to decode a map from the host wire format.
+///
+/// The variants tell apart a length field that doesn't match the input from an entry
+/// that runs past its end, e.g. to distinguish a truncated map from corrupt sizes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapDecodeError {
+    /// The input is too short to hold the number of entries.
+    TruncatedCount { len: usize },
+    /// The input is too short to hold the key and value sizes of the given number of entries.
+    TruncatedSizes { len: usize, entries: usize },
+    /// A key or a value of a given size at a given offset runs past the end of the input.
+    EntryOverrun {
+        len: usize,
+        offset: usize,
+        size: usize,
+    },
+}
+
+impl fmt::Display for MapDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MapDecodeError::TruncatedCount { len } => write!(
+                f,
+                "serialized map of {} bytes is too short to hold the number of entries",
+                len,
+            ),
+            MapDecodeError::TruncatedSizes { len, entries } => write!(
+                f,
+                "serialized map of {} bytes is too short to hold {} entries",
+                len, entries,
+            ),
+            MapDecodeError::EntryOverrun { len, offset, size } => write!(
+                f,
+                "serialized map of {} bytes is too short to hold an entry of {} bytes at offset {}",
+                len, size, offset,
+            ),
+        }
+    }
+}
+
+impl core::error::Error for MapDecodeError {}
+
+/// An error to accumulate more data than a buffer is allowed to hold.
+#[derive(Debug)]
+pub struct BufferLimitError {
+    limit: usize,
+    size: usize,
+}
+
+impl BufferLimitError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(limit: usize, size: usize) -> Self {
+        BufferLimitError { limit, size }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Returns the size of the buffer, or a lower bound on it if the buffer was read
+    /// only up to the limit because its size was not known upfront.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl fmt::Display for BufferLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "buffer of {} bytes exceeds the limit of {} bytes",
+            self.size, self.limit,
+        )
+    }
+}
+
+impl core::error::Error for BufferLimitError {}
+
+/// An error to define a metric with a name the host would reject.
+#[derive(Debug)]
+pub struct InvalidMetricNameError {
+    name: String,
+    invalid: Option<(usize, char)>,
+}
+
+impl InvalidMetricNameError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(name: &str, invalid: Option<(usize, char)>) -> Self {
+        InvalidMetricNameError {
+            name: name.to_owned(),
+            invalid,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the byte offset of the offending character, or `None` if the name is empty.
+    pub fn position(&self) -> Option<usize> {
+        self.invalid.map(|(position, _)| position)
+    }
+
+    /// Returns the offending character, or `None` if the name is empty.
+    pub fn character(&self) -> Option<char> {
+        self.invalid.map(|(_, character)| character)
+    }
+}
+
+impl fmt::Display for InvalidMetricNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.invalid {
+            Some((position, character)) => write!(
+                f,
+                "metric name {:?} contains invalid character {:?} at position {}",
+                self.name, character, position,
+            ),
+            None => write!(f, "metric name must not be empty"),
+        }
+    }
+}
+
+impl core::error::Error for InvalidMetricNameError {}
+
+/// An error to set one of several properties.
+///
+/// Properties are set in order, so the ones before [`failed_path`] have already been applied
+/// and may need to be rolled back by the caller.
+///
+/// [`failed_path`]: #method.failed_path
+#[derive(Debug)]
+pub struct SetPropertiesError {
+    applied: usize,
+    total: usize,
+    failed_path: Vec<String>,
+    error: Error,
+}
+
+impl SetPropertiesError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(
+        applied: usize,
+        total: usize,
+        failed_path: Vec<String>,
+        error: Error,
+    ) -> Self {
+        SetPropertiesError {
+            applied,
+            total,
+            failed_path,
+            error,
+        }
+    }
+
+    /// Returns the number of properties that have been set before the failure.
+    pub fn applied(&self) -> usize {
+        self.applied
+    }
+
+    /// Returns the number of properties that were requested to be set.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the path of the property that could not be set.
+    pub fn failed_path(&self) -> &[String] {
+        &self.failed_path
+    }
+}
+
+impl fmt::Display for SetPropertiesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "failed to set property \"{}\" after setting {} of {} properties: {}",
+            self.failed_path.join("."),
+            self.applied,
+            self.total,
+            self.error,
+        )
+    }
+}
+
+impl core::error::Error for SetPropertiesError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
         Some(&*self.error)
     }
 }
+
+/// An error to set a map that the host has not kept in full, e.g. because it silently
+/// dropped a disallowed pseudo-header.
+#[derive(Debug)]
+pub struct SetMapMismatchError {
+    expected: usize,
+    actual: usize,
+    missing: Vec<ByteString>,
+}
+
+impl SetMapMismatchError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(expected: usize, actual: usize, missing: Vec<ByteString>) -> Self {
+        SetMapMismatchError {
+            expected,
+            actual,
+            missing,
+        }
+    }
+
+    /// Returns the number of entries that were requested to be set.
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+
+    /// Returns the number of entries the map holds after setting it.
+    pub fn actual(&self) -> usize {
+        self.actual
+    }
+
+    /// Returns the keys that were requested to be set but are absent from the map.
+    pub fn missing(&self) -> &[ByteString] {
+        &self.missing
+    }
+}
+
+impl fmt::Display for SetMapMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "map holds {} of {} entries after setting it",
+            self.actual, self.expected,
+        )?;
+        for (i, key) in self.missing.iter().enumerate() {
+            let separator = if i == 0 { ", missing: " } else { ", " };
+            write!(f, "{}{}", separator, key)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for SetMapMismatchError {}
+
+/// An error to apply some of the updates of a metrics batch.
+#[derive(Debug)]
+pub struct MetricsBatchError {
+    total: usize,
+    failures: Vec<(u32, Error)>,
+}
+
+impl MetricsBatchError {
+    #[cfg(feature = "std")]
+    pub(crate) fn new(total: usize, failures: Vec<(u32, Error)>) -> Self {
+        MetricsBatchError { total, failures }
+    }
+
+    /// Returns the number of updates in the batch.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the metric id and the error of each failed update, in order.
+    pub fn failures(&self) -> &[(u32, Error)] {
+        &self.failures
+    }
+}
+
+impl fmt::Display for MetricsBatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} metric updates have failed",
+            self.failures.len(),
+            self.total,
+        )?;
+        if let Some((metric_id, error)) = self.failures.first() {
+            write!(f, ", first for metric {}: {}", metric_id, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for MetricsBatchError {}
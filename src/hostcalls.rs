@@ -12,12 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::allocator;
+use crate::codec;
 use crate::dispatcher;
+#[cfg(feature = "serde_json")]
+use crate::http;
+use crate::logger;
 use crate::types::*;
 use std::ptr::{null, null_mut};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::error::{HostCallError, HostResponseError, Result};
+use crate::error::{
+    HostCallError, HostResponseError, Result, SetMapMismatchError, SetPropertiesError,
+};
 
 /// Represents empty headers map.
 pub const NO_HEADERS: &[(&[u8], &[u8])] = &[];
@@ -61,13 +68,27 @@ mod abi {
 }
 
 extern "C" {
-    fn proxy_log(level: LogLevel, message_data: *const u8, message_size: usize) -> Status;
+    fn proxy_log(level: LogLevel, message_data: *const u8, message_size: usize) -> RawStatus;
 }
 
 /// Logs a message at a given log level.
+///
+/// Messages below the level set via [`set_log_level`] are dropped
+/// without calling into the host.
+///
+/// With the `test-host` feature on non-Wasm targets, messages are also written to stderr,
+/// so that they show up in `cargo test` output.
+///
+/// [`set_log_level`]: ../fn.set_log_level.html
 pub fn log(level: LogLevel, message: &str) -> Result<()> {
+    if !logger::enabled(level) {
+        return Ok(());
+    }
+    if cfg!(all(feature = "test-host", not(target_arch = "wasm32"))) {
+        eprintln!("[{:?}] {}", level, message);
+    }
     unsafe {
-        match proxy_log(level, message.as_ptr(), message.len()) {
+        match Status::from(proxy_log(level, message.as_ptr(), message.len())) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_LOG, status).into()),
         }
@@ -75,15 +96,24 @@ pub fn log(level: LogLevel, message: &str) -> Result<()> {
 }
 
 extern "C" {
-    fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status;
+    fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> RawStatus;
 }
 
 /// Returns current system time.
 pub fn get_current_time() -> Result<SystemTime> {
+    get_current_time_nanos().map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+/// Returns current system time as nanoseconds since the Unix epoch.
+///
+/// Convenient for integer math on time deltas, e.g. across ticks.
+/// Note that the host clock is a wall clock and is not guaranteed to be monotonic,
+/// so deltas between two readings may be negative.
+pub fn get_current_time_nanos() -> Result<u64> {
     let mut return_time: u64 = 0;
     unsafe {
-        match proxy_get_current_time_nanoseconds(&mut return_time) {
-            Status::Ok => Ok(UNIX_EPOCH + Duration::from_nanos(return_time)),
+        match Status::from(proxy_get_current_time_nanoseconds(&mut return_time)) {
+            Status::Ok => Ok(return_time),
             status => {
                 Err(HostCallError::new(abi::PROXY_GET_CURRENT_TIME_NANOSECONDS, status).into())
             }
@@ -92,13 +122,19 @@ pub fn get_current_time() -> Result<SystemTime> {
 }
 
 extern "C" {
-    fn proxy_set_tick_period_milliseconds(period: u32) -> Status;
+    fn proxy_set_tick_period_milliseconds(period: u32) -> RawStatus;
 }
 
 /// Sets the timer to a given period.
+///
+/// The host only supports whole milliseconds, so a non-zero period is rounded up
+/// to the next millisecond, i.e. `1ms` is the shortest period, and periods that don't fit
+/// into `u32` milliseconds are capped. A zero period disables the timer.
 pub fn set_tick_period(period: Duration) -> Result<()> {
     unsafe {
-        match proxy_set_tick_period_milliseconds(period.as_millis() as u32) {
+        match Status::from(proxy_set_tick_period_milliseconds(
+            utils::tick_period_millis(period),
+        )) {
             Status::Ok => Ok(()),
             status => {
                 Err(HostCallError::new(abi::PROXY_SET_TICK_PERIOD_MILLISECONDS, status).into())
@@ -114,33 +150,44 @@ extern "C" {
         max_size: usize,
         return_buffer_data: *mut *mut u8,
         return_buffer_size: *mut usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns content from a given buffer.
+///
+/// Returns `None` if the buffer is missing or empty, so `Some` always holds at least one byte,
+/// regardless of whether the host returns a null pointer or a zero-length one for an empty buffer.
 pub fn get_buffer(
     buffer_type: BufferType,
     start: usize,
     max_size: usize,
 ) -> Result<Option<ByteString>> {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_GET_BUFFER_BYTES);
+    #[cfg(feature = "strict")]
+    debug_assert!(
+        dispatcher::buffer_matches_callback(buffer_type),
+        "buffer {} is not available in the current callback",
+        buffer_type
+    );
     let mut return_data: *mut u8 = null_mut();
     let mut return_size: usize = 0;
     unsafe {
-        match proxy_get_buffer_bytes(
+        match Status::from(proxy_get_buffer_bytes(
             buffer_type,
             start,
             max_size,
             &mut return_data,
             &mut return_size,
-        ) {
+        )) {
             Status::Ok => {
                 if !return_data.is_null() {
-                    Ok(Vec::from_raw_parts(return_data, return_size, return_size))
-                        .map(ByteString::from)
-                        .map(Option::from)
-                } else {
-                    Ok(None)
+                    let data = Vec::from_raw_parts(return_data, return_size, return_size);
+                    if !data.is_empty() {
+                        return Ok(Some(ByteString::from(data)));
+                    }
                 }
+                Ok(None)
             }
             Status::NotFound => Ok(None),
             status => Err(HostCallError::new(abi::PROXY_GET_BUFFER_BYTES, status).into()),
@@ -155,11 +202,24 @@ extern "C" {
         size: usize,
         buffer_data: *const u8,
         buffer_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Mutates content in a given buffer.
 ///
+/// Replaces `size` bytes starting at `start` with `value`. Hosts such as Envoy only support
+/// a subset of ranges:
+///
+/// - `start == 0` and `size == 0` prepends `value`,
+/// - `start == 0` and `size` at least the length of the buffer replaces the whole buffer,
+/// - `start` at or past the end of the buffer appends `value`.
+///
+/// Any other range fails with [`Status::BadArgument`]. To edit the middle of a buffer,
+/// read it, edit it locally and replace the whole buffer, see [`BufferEditor`].
+///
+/// [`Status::BadArgument`]: ../types/enum.Status.html#variant.BadArgument
+/// [`BufferEditor`]: ../body/struct.BufferEditor.html
+///
 /// # Examples
 ///
 /// ```no_run
@@ -171,18 +231,27 @@ extern "C" {
 /// hostcalls::set_buffer(BufferType::HttpRequestBody, 0, usize::MAX, "replacement text")?;
 /// # Ok(())
 /// # }
+/// ```
 pub fn set_buffer<B>(buffer_type: BufferType, start: usize, size: usize, value: B) -> Result<()>
 where
     B: AsRef<[u8]>,
 {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_SET_BUFFER_BYTES);
+    #[cfg(feature = "strict")]
+    debug_assert!(
+        dispatcher::buffer_matches_callback(buffer_type),
+        "buffer {} is not available in the current callback",
+        buffer_type
+    );
     unsafe {
-        match proxy_set_buffer_bytes(
+        match Status::from(proxy_set_buffer_bytes(
             buffer_type,
             start,
             size,
             value.as_ref().as_ptr(),
             value.as_ref().len(),
-        ) {
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SET_BUFFER_BYTES, status).into()),
         }
@@ -194,20 +263,26 @@ extern "C" {
         map_type: MapType,
         return_map_data: *mut *mut u8,
         return_map_size: *mut usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns all key-value pairs from a given map.
 pub fn get_map(map_type: MapType) -> Result<Vec<(ByteString, ByteString)>> {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_GET_HEADER_MAP_PAIRS);
     unsafe {
         let mut return_data: *mut u8 = null_mut();
         let mut return_size: usize = 0;
-        match proxy_get_header_map_pairs(map_type, &mut return_data, &mut return_size) {
+        match Status::from(proxy_get_header_map_pairs(
+            map_type,
+            &mut return_data,
+            &mut return_size,
+        )) {
             Status::Ok => {
                 if !return_data.is_null() {
                     let serialized_map = Vec::from_raw_parts(return_data, return_size, return_size);
-                    utils::deserialize_map(&serialized_map).map_err(|err| {
-                        HostResponseError::new(abi::PROXY_GET_HEADER_MAP_PAIRS, err).into()
+                    codec::decode_map(&serialized_map).map_err(|err| {
+                        HostResponseError::new(abi::PROXY_GET_HEADER_MAP_PAIRS, err.into()).into()
                     })
                 } else {
                     Ok(Vec::new())
@@ -218,16 +293,32 @@ pub fn get_map(map_type: MapType) -> Result<Vec<(ByteString, ByteString)>> {
     }
 }
 
+/// Returns all key-value pairs from a given map, sorted by key.
+///
+/// Pairs with equal keys keep the order returned by the host. Unlike [`get_map`], which
+/// returns pairs in host order, the result doesn't depend on the host version, so it is
+/// meant for tests and diagnostics rather than the request path.
+///
+/// [`get_map`]: fn.get_map.html
+pub fn get_map_sorted(map_type: MapType) -> Result<Vec<(ByteString, ByteString)>> {
+    let mut map = get_map(map_type)?;
+    map.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(map)
+}
+
 extern "C" {
     fn proxy_set_header_map_pairs(
         map_type: MapType,
         map_data: *const u8,
         map_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Sets all key-value pairs in a given map.
 ///
+/// The host may silently drop entries it doesn't allow, e.g. some pseudo-headers,
+/// while still reporting success. Use [`set_map_checked`] to detect that.
+///
 /// # Examples
 ///
 /// ```no_run
@@ -248,15 +339,216 @@ where
     K: AsRef<[u8]>,
     V: AsRef<[u8]>,
 {
-    let serialized_map = utils::serialize_map(map);
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_SET_HEADER_MAP_PAIRS);
+    let serialized_map = codec::encode_map(map);
     unsafe {
-        match proxy_set_header_map_pairs(map_type, serialized_map.as_ptr(), serialized_map.len()) {
+        match Status::from(proxy_set_header_map_pairs(
+            map_type,
+            serialized_map.as_ptr(),
+            serialized_map.len(),
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SET_HEADER_MAP_PAIRS, status).into()),
         }
     }
 }
 
+/// Sets all key-value pairs in a given map and reads the map back to verify that
+/// the host has kept every entry.
+///
+/// Returns a [`SetMapMismatchError`] if the map holds a different number of entries
+/// afterwards. Keys are compared case-insensitively to find the missing ones, since
+/// hosts usually lowercase header names.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// hostcalls::set_map_checked(MapType::HttpResponseHeaders, &[
+///     (":status", "403"),
+///     ("content-type", "text/plain"),
+/// ])?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SetMapMismatchError`]: ../error/struct.SetMapMismatchError.html
+pub fn set_map_checked<K, V>(map_type: MapType, map: &[(K, V)]) -> Result<()>
+where
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    set_map(map_type, map)?;
+    let actual = get_map(map_type)?;
+    if actual.len() == map.len() {
+        return Ok(());
+    }
+    let mut missing: Vec<ByteString> = Vec::new();
+    for (key, _) in map {
+        let key = key.as_ref();
+        if !actual.iter().any(|(k, _)| k.eq_ignore_ascii_case(key))
+            && !missing.iter().any(|k| k == key)
+        {
+            missing.push(key.into());
+        }
+    }
+    Err(SetMapMismatchError::new(map.len(), actual.len(), missing).into())
+}
+
+/// Sets headers in a given map from a map-like value, e.g. a struct with a field per header.
+///
+/// Each header in the value replaces all existing values of that header, while headers
+/// that are not in the value, e.g. `:status`, are kept as they are. See
+/// [`http::serialize_headers`] for how the value is flattened into pairs.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+/// use std::collections::BTreeMap;
+///
+/// # fn action(defaults: &BTreeMap<String, String>) -> proxy_wasm::error::Result<()> {
+/// hostcalls::set_map_from_serialize(MapType::HttpResponseHeaders, defaults)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`http::serialize_headers`]: ../http/fn.serialize_headers.html
+#[cfg(feature = "serde_json")]
+pub fn set_map_from_serialize<T>(map_type: MapType, value: &T) -> Result<()>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let headers = http::serialize_headers(value)?;
+    for (i, (name, value)) in headers.iter().enumerate() {
+        // array elements come out next to each other, so only the first one replaces
+        if i > 0 && headers[i - 1].0 == *name {
+            add_map_value(map_type, name, value)?;
+        } else {
+            set_map_value(map_type, name, Some(value))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns all headers from a given map as an [`http::HeaderMap`], for use with
+/// libraries from the `http` ecosystem.
+///
+/// Pseudo-headers, e.g. `:path`, are not valid header names, so they are left out.
+/// Read them via [`get_map_value`] instead. Values are kept as opaque bytes, so
+/// non-UTF-8 values are preserved. Any other header the `http` crate rejects is an error.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let headers = hostcalls::get_http_headers(MapType::HttpRequestHeaders)?;
+/// let accepts = headers.get_all("accept").iter().count();
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`http::HeaderMap`]: https://docs.rs/http/1/http/header/struct.HeaderMap.html
+/// [`get_map_value`]: fn.get_map_value.html
+#[cfg(feature = "http")]
+pub fn get_http_headers(map_type: MapType) -> Result<::http::HeaderMap> {
+    utils::to_header_map(&get_map(map_type)?)
+}
+
+/// Sets all headers in a given map from an [`http::HeaderMap`].
+///
+/// Like [`set_map`], this replaces the whole map, except for pseudo-headers, e.g. `:path`,
+/// which are kept as they are, since an [`http::HeaderMap`] cannot hold them.
+///
+/// [`http::HeaderMap`]: https://docs.rs/http/1/http/header/struct.HeaderMap.html
+/// [`set_map`]: fn.set_map.html
+#[cfg(feature = "http")]
+pub fn set_http_headers(map_type: MapType, headers: &::http::HeaderMap) -> Result<()> {
+    let mut map = get_map(map_type)?;
+    map.retain(|(name, _)| name.starts_with(b":"));
+    let mut pairs: Vec<(&[u8], &[u8])> = map
+        .iter()
+        .map(|(name, value)| (name.as_bytes(), value.as_bytes()))
+        .collect();
+    pairs.extend(
+        headers
+            .iter()
+            .map(|(name, value)| (name.as_str().as_bytes(), value.as_bytes())),
+    );
+    set_map(map_type, &pairs)
+}
+
+/// Returns all values of a given key from a given map, in order.
+///
+/// Unlike [`get_map_value`], which returns a single value as joined by the host,
+/// this returns every occurrence of a multi-valued key, e.g. `set-cookie`.
+/// Keys are matched byte for byte.
+///
+/// [`get_map_value`]: fn.get_map_value.html
+pub fn get_map_values<K>(map_type: MapType, key: K) -> Result<Vec<ByteString>>
+where
+    K: AsRef<[u8]>,
+{
+    let key = key.as_ref();
+    Ok(get_map(map_type)?
+        .into_iter()
+        .filter(|(name, _)| name == key)
+        .map(|(_, value)| value)
+        .collect())
+}
+
+/// Removes all key-value pairs from a given map in a single call.
+///
+/// The map is replaced with an empty one, which the host accepts like any other map.
+/// Note that for HTTP headers this removes pseudo-headers, e.g. `:path`, as well.
+pub fn clear_map(map_type: MapType) -> Result<()> {
+    set_map(map_type, NO_HEADERS)
+}
+
+/// Keeps only the key-value pairs of a given map for which the predicate returns `true`.
+///
+/// The map is read and written back once, regardless of how many pairs get removed.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// const HOP_BY_HOP: &[&str] = &["connection", "keep-alive", "te", "upgrade"];
+///
+/// hostcalls::retain_map(MapType::HttpRequestHeaders, |name, _| {
+///     !HOP_BY_HOP.iter().any(|header| name == *header)
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn retain_map<F>(map_type: MapType, mut predicate: F) -> Result<()>
+where
+    F: FnMut(&ByteString, &ByteString) -> bool,
+{
+    let mut map = get_map(map_type)?;
+    let len = map.len();
+    map.retain(|(name, value)| predicate(name, value));
+    if map.len() == len {
+        return Ok(());
+    }
+    set_map(map_type, &map)
+}
+
 extern "C" {
     fn proxy_get_header_map_value(
         map_type: MapType,
@@ -264,7 +556,7 @@ extern "C" {
         key_size: usize,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns value of a given key from a given map.
@@ -285,21 +577,25 @@ pub fn get_map_value<K>(map_type: MapType, key: K) -> Result<Option<ByteString>>
 where
     K: AsRef<[u8]>,
 {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_GET_HEADER_MAP_VALUE);
     let mut return_data: *mut u8 = null_mut();
     let mut return_size: usize = 0;
     unsafe {
-        match proxy_get_header_map_value(
+        match Status::from(proxy_get_header_map_value(
             map_type,
             key.as_ref().as_ptr(),
             key.as_ref().len(),
             &mut return_data,
             &mut return_size,
-        ) {
+        )) {
             Status::Ok => {
                 if !return_data.is_null() {
-                    Ok(Vec::from_raw_parts(return_data, return_size, return_size))
-                        .map(ByteString::from)
-                        .map(Option::from)
+                    Ok(Some(ByteString::from(Vec::from_raw_parts(
+                        return_data,
+                        return_size,
+                        return_size,
+                    ))))
                 } else {
                     Ok(None)
                 }
@@ -309,6 +605,94 @@ where
     }
 }
 
+/// Returns `true` if a given map contains a given key.
+///
+/// Unlike looking the key up in the result of [`get_map`], this doesn't copy the whole map.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// if hostcalls::has_map_value(MapType::HttpRequestHeaders, "x-debug")? {
+///     // log extra details
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`get_map`]: fn.get_map.html
+pub fn has_map_value<K>(map_type: MapType, key: K) -> Result<bool>
+where
+    K: AsRef<[u8]>,
+{
+    get_map_value(map_type, key).map(|value| value.is_some())
+}
+
+/// Reads value of a given key from a given map into a reusable buffer.
+///
+/// Returns `false`, leaving the buffer empty, if the key is missing.
+///
+/// The host hands the value over in memory it allocates through the plugin, so [`get_map_value`]
+/// costs an allocation per call. Here, that allocation lands in the spare capacity of `buf`
+/// instead, whenever the value fits, so a buffer kept across requests stops allocating once it
+/// has grown to the size of the largest value. A value that does not fit replaces the buffer.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let mut buf = Vec::with_capacity(256);
+/// for name in &["x-request-id", "x-b3-traceid", "x-b3-spanid"] {
+///     if hostcalls::get_map_value_into(MapType::HttpRequestHeaders, name, &mut buf)? {
+///         // use `buf`
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`get_map_value`]: fn.get_map_value.html
+pub fn get_map_value_into<K>(map_type: MapType, key: K, buf: &mut Vec<u8>) -> Result<bool>
+where
+    K: AsRef<[u8]>,
+{
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_GET_HEADER_MAP_VALUE);
+    let mut return_data: *mut u8 = null_mut();
+    let mut return_size: usize = 0;
+    let status = allocator::with_landing_buffer(buf, || unsafe {
+        Status::from(proxy_get_header_map_value(
+            map_type,
+            key.as_ref().as_ptr(),
+            key.as_ref().len(),
+            &mut return_data,
+            &mut return_size,
+        ))
+    });
+    match status {
+        Status::Ok => {
+            if return_data.is_null() {
+                Ok(false)
+            } else if return_data == buf.as_mut_ptr() {
+                unsafe { buf.set_len(return_size) };
+                Ok(true)
+            } else {
+                *buf = unsafe { Vec::from_raw_parts(return_data, return_size, return_size) };
+                Ok(true)
+            }
+        }
+        status => Err(HostCallError::new(abi::PROXY_GET_HEADER_MAP_VALUE, status).into()),
+    }
+}
+
 extern "C" {
     fn proxy_replace_header_map_value(
         map_type: MapType,
@@ -316,7 +700,7 @@ extern "C" {
         key_size: usize,
         value_data: *const u8,
         value_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 extern "C" {
@@ -324,7 +708,7 @@ extern "C" {
         map_type: MapType,
         key_data: *const u8,
         key_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Sets / replaces / removes value of given key from a given map.
@@ -346,23 +730,28 @@ where
     K: AsRef<[u8]>,
     V: AsRef<[u8]>,
 {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_REPLACE_HEADER_MAP_VALUE);
     unsafe {
         if let Some(value) = value {
-            match proxy_replace_header_map_value(
+            match Status::from(proxy_replace_header_map_value(
                 map_type,
                 key.as_ref().as_ptr(),
                 key.as_ref().len(),
                 value.as_ref().as_ptr(),
                 value.as_ref().len(),
-            ) {
+            )) {
                 Status::Ok => Ok(()),
                 status => {
                     Err(HostCallError::new(abi::PROXY_REPLACE_HEADER_MAP_VALUE, status).into())
                 }
             }
         } else {
-            match proxy_remove_header_map_value(map_type, key.as_ref().as_ptr(), key.as_ref().len())
-            {
+            match Status::from(proxy_remove_header_map_value(
+                map_type,
+                key.as_ref().as_ptr(),
+                key.as_ref().len(),
+            )) {
                 Status::Ok => Ok(()),
                 status => {
                     Err(HostCallError::new(abi::PROXY_REMOVE_HEADER_MAP_VALUE, status).into())
@@ -379,7 +768,7 @@ extern "C" {
         key_size: usize,
         value_data: *const u8,
         value_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Adds a key-value pair to a given map.
@@ -401,14 +790,16 @@ where
     K: AsRef<[u8]>,
     V: AsRef<[u8]>,
 {
+    #[cfg(feature = "strict")]
+    utils::assert_in_context(abi::PROXY_ADD_HEADER_MAP_VALUE);
     unsafe {
-        match proxy_add_header_map_value(
+        match Status::from(proxy_add_header_map_value(
             map_type,
             key.as_ref().as_ptr(),
             key.as_ref().len(),
             value.as_ref().as_ptr(),
             value.as_ref().len(),
-        ) {
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_ADD_HEADER_MAP_VALUE, status).into()),
         }
@@ -421,22 +812,56 @@ extern "C" {
         path_size: usize,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns value of a property in the current context.
 ///
+/// Returns `Ok(None)` if the property doesn't exist.
+///
+/// # Errors
+///
+/// If the host rejects the path itself, e.g. because one of its segments
+/// cannot be resolved, the returned error is a [`HostCallError`] with
+/// [`Status::BadArgument`]. This makes it possible to tell a malformed path
+/// apart from missing data.
+///
 /// # Examples
 ///
 /// ```no_run
 /// # use proxy_wasm_experimental as proxy_wasm;
 /// use proxy_wasm::hostcalls;
+/// use proxy_wasm::properties::paths;
 ///
 /// # fn action() -> proxy_wasm::error::Result<()> {
-/// let value = hostcalls::get_property(&["request", "time"])?;
+/// let value = hostcalls::get_property(paths::REQUEST_TIME)?;
 /// # Ok(())
 /// # }
 /// ```
+///
+/// Distinguishing a malformed path from other failures:
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::error::HostCallError;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::Status;
+///
+/// match hostcalls::get_property(&["request", "headers", "x-tenant"]) {
+///     Ok(Some(value)) => { /* use value */ }
+///     Ok(None) => { /* property doesn't exist */ }
+///     Err(err) => match err.downcast_ref::<HostCallError>() {
+///         Some(err) if err.status() == Status::BadArgument => { /* malformed path */ }
+///         _ => { /* other failure */ }
+///     },
+/// }
+/// ```
+///
+/// Paths of well-known properties are available in [`properties::paths`].
+///
+/// [`HostCallError`]: ../error/struct.HostCallError.html
+/// [`Status::BadArgument`]: ../types/enum.Status.html#variant.BadArgument
+/// [`properties::paths`]: ../properties/paths/index.html
 pub fn get_property<P>(path: &[P]) -> Result<Option<ByteString>>
 where
     P: AsRef<str>,
@@ -445,17 +870,19 @@ where
     let mut return_data: *mut u8 = null_mut();
     let mut return_size: usize = 0;
     unsafe {
-        match proxy_get_property(
+        match Status::from(proxy_get_property(
             serialized_path.as_ptr(),
             serialized_path.len(),
             &mut return_data,
             &mut return_size,
-        ) {
+        )) {
             Status::Ok => {
                 if !return_data.is_null() {
-                    Ok(Vec::from_raw_parts(return_data, return_size, return_size))
-                        .map(ByteString::from)
-                        .map(Option::from)
+                    Ok(Some(ByteString::from(Vec::from_raw_parts(
+                        return_data,
+                        return_size,
+                        return_size,
+                    ))))
                 } else {
                     Ok(None)
                 }
@@ -472,7 +899,7 @@ extern "C" {
         path_size: usize,
         value_data: *const u8,
         value_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Sets property to a given value in the current context.
@@ -494,22 +921,63 @@ where
     V: AsRef<[u8]>,
 {
     let serialized_path = utils::serialize_property_path(path);
-    let (value_ptr, value_len) = value.map_or((null(), 0), |value| {
+    let (value_ptr, value_len) = value.as_ref().map_or((null(), 0), |value| {
         (value.as_ref().as_ptr(), value.as_ref().len())
     });
     unsafe {
-        match proxy_set_property(
+        match Status::from(proxy_set_property(
             serialized_path.as_ptr(),
             serialized_path.len(),
             value_ptr,
             value_len,
-        ) {
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SET_PROPERTY, status).into()),
         }
     }
 }
 
+/// Sets several properties in the current context, in order.
+///
+/// The host has no way to set properties atomically, so this function stops at the first
+/// property that could not be set and reports how many of them have been applied by then.
+///
+/// # Errors
+///
+/// Returns [`SetPropertiesError`] describing the failed property.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// hostcalls::set_properties(&[
+///     (&["my_filter", "tenant"][..], "acme"),
+///     (&["my_filter", "plan"][..], "gold"),
+/// ])?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`SetPropertiesError`]: ../error/struct.SetPropertiesError.html
+pub fn set_properties<P, V>(properties: &[(&[P], V)]) -> Result<()>
+where
+    P: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    for (applied, (path, value)) in properties.iter().enumerate() {
+        if let Err(err) = set_property(path, Some(value)) {
+            let failed_path = path.iter().map(|part| part.as_ref().to_owned()).collect();
+            return Err(
+                SetPropertiesError::new(applied, properties.len(), failed_path, err).into(),
+            );
+        }
+    }
+    Ok(())
+}
+
 extern "C" {
     fn proxy_get_shared_data(
         key_data: *const u8,
@@ -517,7 +985,7 @@ extern "C" {
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
         return_cas: *mut u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns shared data by key.
@@ -541,13 +1009,13 @@ where
     let mut return_size: usize = 0;
     let mut return_cas: u32 = 0;
     unsafe {
-        match proxy_get_shared_data(
+        match Status::from(proxy_get_shared_data(
             key.as_ref().as_ptr(),
             key.as_ref().len(),
             &mut return_data,
             &mut return_size,
             &mut return_cas,
-        ) {
+        )) {
             Status::Ok => {
                 let cas = match return_cas {
                     0 => None,
@@ -555,8 +1023,11 @@ where
                 };
                 if !return_data.is_null() {
                     Ok((
-                        Some(Vec::from_raw_parts(return_data, return_size, return_size))
-                            .map(ByteString::from),
+                        Some(ByteString::from(Vec::from_raw_parts(
+                            return_data,
+                            return_size,
+                            return_size,
+                        ))),
                         cas,
                     ))
                 } else {
@@ -569,6 +1040,53 @@ where
     }
 }
 
+/// Returns shared data by key, without its version.
+///
+/// Use [`get_shared_data`] instead if the version is needed for a subsequent
+/// compare-and-swap update.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let data = hostcalls::get_shared_data_bytes("my_shared_key")?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`get_shared_data`]: fn.get_shared_data.html
+pub fn get_shared_data_bytes<K>(key: K) -> Result<Option<ByteString>>
+where
+    K: AsRef<str>,
+{
+    get_shared_data(key).map(|(data, _)| data)
+}
+
+/// Returns shared data by key, or a given default value if there is none.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::ByteString;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let data: ByteString = hostcalls::get_shared_data_or("my_shared_key", "default".into())?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn get_shared_data_or<K, V>(key: K, default: V) -> Result<V>
+where
+    K: AsRef<str>,
+    V: From<ByteString>,
+{
+    get_shared_data_bytes(key).map(|data| data.map_or(default, V::from))
+}
+
 extern "C" {
     fn proxy_set_shared_data(
         key_data: *const u8,
@@ -576,7 +1094,7 @@ extern "C" {
         value_data: *const u8,
         value_size: usize,
         cas: u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Sets shared data by key.
@@ -597,17 +1115,17 @@ where
     K: AsRef<str>,
     V: AsRef<[u8]>,
 {
-    let (value_ptr, value_len) = value.map_or((null(), 0), |value| {
+    let (value_ptr, value_len) = value.as_ref().map_or((null(), 0), |value| {
         (value.as_ref().as_ptr(), value.as_ref().len())
     });
     unsafe {
-        match proxy_set_shared_data(
+        match Status::from(proxy_set_shared_data(
             key.as_ref().as_ptr(),
             key.as_ref().len(),
             value_ptr,
             value_len,
             cas.unwrap_or(0),
-        ) {
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SET_SHARED_DATA, status).into()),
         }
@@ -619,14 +1137,18 @@ extern "C" {
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Registers a shared queue with a given name.
 pub fn register_shared_queue(name: &str) -> Result<u32> {
     unsafe {
         let mut return_id: u32 = 0;
-        match proxy_register_shared_queue(name.as_ptr(), name.len(), &mut return_id) {
+        match Status::from(proxy_register_shared_queue(
+            name.as_ptr(),
+            name.len(),
+            &mut return_id,
+        )) {
             Status::Ok => Ok(return_id),
             status => Err(HostCallError::new(abi::PROXY_REGISTER_SHARED_QUEUE, status).into()),
         }
@@ -640,20 +1162,20 @@ extern "C" {
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Looks up for an existing shared queue with a given name.
 pub fn resolve_shared_queue(vm_id: &str, name: &str) -> Result<Option<u32>> {
     let mut return_id: u32 = 0;
     unsafe {
-        match proxy_resolve_shared_queue(
+        match Status::from(proxy_resolve_shared_queue(
             vm_id.as_ptr(),
             vm_id.len(),
             name.as_ptr(),
             name.len(),
             &mut return_id,
-        ) {
+        )) {
             Status::Ok => Ok(Some(return_id)),
             Status::NotFound => Ok(None),
             status => Err(HostCallError::new(abi::PROXY_RESOLVE_SHARED_QUEUE, status).into()),
@@ -666,7 +1188,7 @@ extern "C" {
         queue_id: u32,
         return_value_data: *mut *mut u8,
         return_value_size: *mut usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Returns data from the end of a given queue.
@@ -674,12 +1196,18 @@ pub fn dequeue_shared_queue(queue_id: u32) -> Result<Option<ByteString>> {
     let mut return_data: *mut u8 = null_mut();
     let mut return_size: usize = 0;
     unsafe {
-        match proxy_dequeue_shared_queue(queue_id, &mut return_data, &mut return_size) {
+        match Status::from(proxy_dequeue_shared_queue(
+            queue_id,
+            &mut return_data,
+            &mut return_size,
+        )) {
             Status::Ok => {
                 if !return_data.is_null() {
-                    Ok(Vec::from_raw_parts(return_data, return_size, return_size))
-                        .map(ByteString::from)
-                        .map(Option::from)
+                    Ok(Some(ByteString::from(Vec::from_raw_parts(
+                        return_data,
+                        return_size,
+                        return_size,
+                    ))))
                 } else {
                     Ok(None)
                 }
@@ -695,7 +1223,7 @@ extern "C" {
         queue_id: u32,
         value_data: *const u8,
         value_size: usize,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Adds a value to the front of a given queue.
@@ -715,11 +1243,11 @@ pub fn enqueue_shared_queue<V>(queue_id: u32, value: Option<V>) -> Result<()>
 where
     V: AsRef<[u8]>,
 {
-    let (value_ptr, value_len) = value.map_or((null(), 0), |value| {
+    let (value_ptr, value_len) = value.as_ref().map_or((null(), 0), |value| {
         (value.as_ref().as_ptr(), value.as_ref().len())
     });
     unsafe {
-        match proxy_enqueue_shared_queue(queue_id, value_ptr, value_len) {
+        match Status::from(proxy_enqueue_shared_queue(queue_id, value_ptr, value_len)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_ENQUEUE_SHARED_QUEUE, status).into()),
         }
@@ -727,13 +1255,16 @@ where
 }
 
 extern "C" {
-    fn proxy_continue_stream(stream: StreamType) -> Status;
+    fn proxy_continue_stream(stream: StreamType) -> RawStatus;
 }
 
-/// Resumes processing of a given stream, i.e. HTTP request or HTTP response.
+/// Resumes processing of a given stream, i.e. HTTP request or HTTP response,
+/// or data received from a downstream or upstream peer.
+///
+/// Only the given direction is resumed; the opposite direction is unaffected.
 pub fn continue_stream(stream_type: StreamType) -> Result<()> {
     unsafe {
-        match proxy_continue_stream(stream_type) {
+        match Status::from(proxy_continue_stream(stream_type)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_CONTINUE_STREAM, status).into()),
         }
@@ -741,13 +1272,35 @@ pub fn continue_stream(stream_type: StreamType) -> Result<()> {
 }
 
 extern "C" {
-    fn proxy_close_stream(stream: StreamType) -> Status;
+    fn proxy_close_stream(stream: StreamType) -> RawStatus;
 }
 
-/// Terminates processing of a given stream, i.e. HTTP request or HTTP response.
+/// Terminates processing of a given stream, i.e. HTTP request or HTTP response,
+/// or a downstream or upstream connection.
+///
+/// ABI 0.2.0 doesn't let the caller pass a reset reason. To tell policy-driven
+/// closes apart from generic resets, record the reason before closing the stream,
+/// e.g. via [`set_property`]. Envoy exposes such values to access logs as filter state
+/// under the `wasm.` prefix, e.g. `%FILTER_STATE(wasm.close_reason)%`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::types::StreamType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// hostcalls::set_property(&["close_reason"], Some(b"denied_by_policy"))?;
+/// hostcalls::close_stream(StreamType::Downstream)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`set_property`]: fn.set_property.html
 pub fn close_stream(stream_type: StreamType) -> Result<()> {
     unsafe {
-        match proxy_close_stream(stream_type) {
+        match Status::from(proxy_close_stream(stream_type)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_CLOSE_STREAM, status).into()),
         }
@@ -764,7 +1317,7 @@ extern "C" {
         headers_data: *const u8,
         headers_size: usize,
         grpc_status: i32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Sends HTTP response without forwarding request to the upstream.
@@ -794,12 +1347,12 @@ where
     V: AsRef<[u8]>,
     B: AsRef<[u8]>,
 {
-    let serialized_headers = utils::serialize_map(headers);
-    let (body_ptr, body_len) = body.map_or((null(), 0), |body| {
+    let serialized_headers = codec::encode_map(headers);
+    let (body_ptr, body_len) = body.as_ref().map_or((null(), 0), |body| {
         (body.as_ref().as_ptr(), body.as_ref().len())
     });
     unsafe {
-        match proxy_send_local_response(
+        match Status::from(proxy_send_local_response(
             status_code,
             null(),
             0,
@@ -808,7 +1361,7 @@ where
             serialized_headers.as_ptr(),
             serialized_headers.len(),
             -1,
-        ) {
+        )) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SEND_LOCAL_RESPONSE, status).into()),
         }
@@ -827,11 +1380,16 @@ extern "C" {
         trailers_size: usize,
         timeout: u32,
         return_token: *mut u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
 /// Dispatches an HTTP call to a given upstream.
 ///
+/// # Errors
+///
+/// If the host returns a token that is still pending a response, the returned error
+/// is a [`HostResponseError`] naming that token.
+///
 /// # Examples
 ///
 /// ```no_run
@@ -853,6 +1411,8 @@ extern "C" {
 /// # Ok(())
 /// # }
 /// ```
+///
+/// [`HostResponseError`]: ../error/struct.HostResponseError.html
 pub fn dispatch_http_call<K1, V1, K2, V2, B>(
     upstream: &str,
     headers: &[(K1, V1)],
@@ -867,14 +1427,14 @@ where
     V2: AsRef<[u8]>,
     B: AsRef<[u8]>,
 {
-    let serialized_headers = utils::serialize_map(headers);
-    let serialized_trailers = utils::serialize_map(trailers);
-    let (body_ptr, body_len) = body.map_or((null(), 0), |body| {
+    let serialized_headers = codec::encode_map(headers);
+    let serialized_trailers = codec::encode_map(trailers);
+    let (body_ptr, body_len) = body.as_ref().map_or((null(), 0), |body| {
         (body.as_ref().as_ptr(), body.as_ref().len())
     });
     let mut return_token: u32 = 0;
     unsafe {
-        match proxy_http_call(
+        match Status::from(proxy_http_call(
             upstream.as_ptr(),
             upstream.len(),
             serialized_headers.as_ptr(),
@@ -885,9 +1445,10 @@ where
             serialized_trailers.len(),
             timeout.as_millis() as u32,
             &mut return_token,
-        ) {
+        )) {
             Status::Ok => {
-                dispatcher::register_callout(return_token);
+                dispatcher::register_callout(return_token)
+                    .map_err(|err| HostResponseError::new(abi::PROXY_HTTP_CALL, err))?;
                 Ok(return_token)
             }
             status => Err(HostCallError::new(abi::PROXY_HTTP_CALL, status).into()),
@@ -895,14 +1456,67 @@ where
     }
 }
 
+/// Dispatches an HTTP call to a given upstream, like [`dispatch_http_call`],
+/// with the `:authority` pseudo-header set to a given value.
+///
+/// Any `:authority` in `headers` is replaced.
+///
+/// # Errors
+///
+/// Returns an error without calling into the host if `headers` lack
+/// a non-empty `:method` or `:path` pseudo-header.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let request_handle = hostcalls::dispatch_http_call_with_authority(
+///     "auth_cluster",
+///     "auth.internal",
+///     &vec![
+///         (":method", "GET"),
+///         (":path", "/check"),
+///     ],
+///     hostcalls::NO_BODY,
+///     hostcalls::NO_TRAILERS,
+///     Duration::from_secs(1),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`dispatch_http_call`]: fn.dispatch_http_call.html
+pub fn dispatch_http_call_with_authority<K1, V1, K2, V2, B>(
+    upstream: &str,
+    authority: &str,
+    headers: &[(K1, V1)],
+    body: Option<B>,
+    trailers: &[(K2, V2)],
+    timeout: Duration,
+) -> Result<u32>
+where
+    K1: AsRef<[u8]>,
+    V1: AsRef<[u8]>,
+    K2: AsRef<[u8]>,
+    V2: AsRef<[u8]>,
+    B: AsRef<[u8]>,
+{
+    let headers = utils::with_authority(upstream, authority, headers)?;
+    dispatch_http_call(upstream, &headers, body, trailers, timeout)
+}
+
 extern "C" {
-    fn proxy_set_effective_context(context_id: u32) -> Status;
+    fn proxy_set_effective_context(context_id: u32) -> RawStatus;
 }
 
 /// Changes the effective context.
 pub fn set_effective_context(context_id: u32) -> Result<()> {
     unsafe {
-        match proxy_set_effective_context(context_id) {
+        match Status::from(proxy_set_effective_context(context_id)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_SET_EFFECTIVE_CONTEXT, status).into()),
         }
@@ -910,32 +1524,120 @@ pub fn set_effective_context(context_id: u32) -> Result<()> {
 }
 
 extern "C" {
-    fn proxy_done() -> Status;
+    fn proxy_done() -> RawStatus;
 }
 
 /// Indicates to the host environment that Wasm VM side is done processing current context.
 pub fn done() -> Result<()> {
     unsafe {
-        match proxy_done() {
+        match Status::from(proxy_done()) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_DONE, status).into()),
         }
     }
 }
 
+/// Calls [`done`] exactly once, either explicitly or when the guard is dropped.
+///
+/// Useful for root contexts that return `false` from `on_done` and
+/// signal completion later, e.g. after draining a queue.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls::DoneGuard;
+/// use proxy_wasm::traits::{Context, RootContext};
+///
+/// struct Worker {
+///     done: Option<DoneGuard>,
+/// }
+///
+/// impl Context for Worker {
+///     fn on_done(&mut self) -> bool {
+///         self.done = Some(DoneGuard::new());
+///         false
+///     }
+/// }
+///
+/// impl RootContext for Worker {
+///     fn on_queue_ready(&mut self, _queue_id: u32) {
+///         // drain the queue, then
+///         if let Some(guard) = self.done.as_mut() {
+///             guard.done().unwrap();
+///         }
+///     }
+/// }
+/// ```
+///
+/// [`done`]: fn.done.html
+#[derive(Debug, Default)]
+pub struct DoneGuard {
+    done: bool,
+}
+
+impl DoneGuard {
+    pub fn new() -> Self {
+        DoneGuard { done: false }
+    }
+
+    /// Returns `true` if [`done`] has already been called through this guard.
+    ///
+    /// [`done`]: fn.done.html
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Calls [`done`] unless it has already been called through this guard.
+    ///
+    /// [`done`]: fn.done.html
+    pub fn done(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        done()
+    }
+}
+
+impl Drop for DoneGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            self.done = true;
+            let _ = done();
+        }
+    }
+}
+
 extern "C" {
     fn proxy_define_metric(
         metric_type: MetricType,
         name_data: *const u8,
         name_size: usize,
         return_id: *mut u32,
-    ) -> Status;
+    ) -> RawStatus;
 }
 
+/// Defines a metric of a given type.
+///
+/// # Errors
+///
+/// Metric names must be non-empty and consist of printable ASCII characters
+/// other than space, i.e. `'!'..='~'`. Dots are allowed and separate name segments.
+/// Other names are rejected with an [`InvalidMetricNameError`] naming the offending
+/// character, without calling into the host.
+///
+/// [`InvalidMetricNameError`]: ../error/struct.InvalidMetricNameError.html
 pub fn define_metric(metric_type: MetricType, name: &str) -> Result<u32> {
+    utils::validate_metric_name(name)?;
     let mut return_id: u32 = 0;
     unsafe {
-        match proxy_define_metric(metric_type, name.as_ptr(), name.len(), &mut return_id) {
+        match Status::from(proxy_define_metric(
+            metric_type,
+            name.as_ptr(),
+            name.len(),
+            &mut return_id,
+        )) {
             Status::Ok => Ok(return_id),
             status => Err(HostCallError::new(abi::PROXY_DEFINE_METRIC, status).into()),
         }
@@ -943,13 +1645,13 @@ pub fn define_metric(metric_type: MetricType, name: &str) -> Result<u32> {
 }
 
 extern "C" {
-    fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status;
+    fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> RawStatus;
 }
 
 pub fn get_metric(metric_id: u32) -> Result<u64> {
     let mut return_value: u64 = 0;
     unsafe {
-        match proxy_get_metric(metric_id, &mut return_value) {
+        match Status::from(proxy_get_metric(metric_id, &mut return_value)) {
             Status::Ok => Ok(return_value),
             status => Err(HostCallError::new(abi::PROXY_GET_METRIC, status).into()),
         }
@@ -957,12 +1659,12 @@ pub fn get_metric(metric_id: u32) -> Result<u64> {
 }
 
 extern "C" {
-    fn proxy_record_metric(metric_id: u32, value: u64) -> Status;
+    fn proxy_record_metric(metric_id: u32, value: u64) -> RawStatus;
 }
 
 pub fn record_metric(metric_id: u32, value: u64) -> Result<()> {
     unsafe {
-        match proxy_record_metric(metric_id, value) {
+        match Status::from(proxy_record_metric(metric_id, value)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_RECORD_METRIC, status).into()),
         }
@@ -970,22 +1672,180 @@ pub fn record_metric(metric_id: u32, value: u64) -> Result<()> {
 }
 
 extern "C" {
-    fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status;
+    fn proxy_increment_metric(metric_id: u32, offset: i64) -> RawStatus;
 }
 
 pub fn increment_metric(metric_id: u32, offset: i64) -> Result<()> {
     unsafe {
-        match proxy_increment_metric(metric_id, offset) {
+        match Status::from(proxy_increment_metric(metric_id, offset)) {
             Status::Ok => Ok(()),
             status => Err(HostCallError::new(abi::PROXY_INCREMENT_METRIC, status).into()),
         }
     }
 }
 
+/// Optional host features detected by [`probe`].
+///
+/// [`probe`]: fn.probe.html
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    plugin_name: Option<ByteString>,
+    properties: bool,
+    shared_data: bool,
+    shared_queues: bool,
+    metrics: bool,
+    grpc: bool,
+    foreign_functions: bool,
+}
+
+impl Capabilities {
+    /// Returns the `plugin_name` property read while probing for properties.
+    pub fn plugin_name(&self) -> Option<&ByteString> {
+        self.plugin_name.as_ref()
+    }
+
+    pub fn properties(&self) -> bool {
+        self.properties
+    }
+
+    pub fn shared_data(&self) -> bool {
+        self.shared_data
+    }
+
+    pub fn shared_queues(&self) -> bool {
+        self.shared_queues
+    }
+
+    pub fn metrics(&self) -> bool {
+        self.metrics
+    }
+
+    pub fn grpc(&self) -> bool {
+        self.grpc
+    }
+
+    pub fn foreign_functions(&self) -> bool {
+        self.foreign_functions
+    }
+}
+
+extern "C" {
+    fn proxy_grpc_call(
+        grpc_service_data: *const u8,
+        grpc_service_size: usize,
+        service_name_data: *const u8,
+        service_name_size: usize,
+        method_name_data: *const u8,
+        method_name_size: usize,
+        initial_metadata_data: *const u8,
+        initial_metadata_size: usize,
+        grpc_message_data: *const u8,
+        grpc_message_size: usize,
+        timeout_milliseconds: u32,
+        return_callout_id: *mut u32,
+    ) -> RawStatus;
+
+    fn proxy_grpc_cancel(callout_id: u32) -> RawStatus;
+
+    fn proxy_call_foreign_function(
+        function_name_data: *const u8,
+        function_name_size: usize,
+        arguments_data: *const u8,
+        arguments_size: usize,
+        return_results_data: *mut *mut u8,
+        return_results_size: *mut usize,
+    ) -> RawStatus;
+}
+
+/// Name of a foreign function that no host is expected to register.
+const PROBE_FOREIGN_FUNCTION: &str = "proxy_wasm_experimental.probe";
+
+/// Starts a gRPC call without a service, which a host that implements gRPC rejects
+/// as a bad argument. Should it start the call anyway, the call is cancelled right away.
+fn probe_grpc() -> bool {
+    let mut callout_id: u32 = 0;
+    let status = unsafe {
+        Status::from(proxy_grpc_call(
+            null(),
+            0,
+            null(),
+            0,
+            null(),
+            0,
+            null(),
+            0,
+            null(),
+            0,
+            0,
+            &mut callout_id,
+        ))
+    };
+    if status == Status::Ok {
+        unsafe { proxy_grpc_cancel(callout_id) };
+    }
+    utils::is_implemented(status)
+}
+
+/// Calls a foreign function that does not exist, which a host that implements foreign
+/// functions answers with `NotFound`.
+fn probe_foreign_functions() -> bool {
+    let mut return_data: *mut u8 = null_mut();
+    let mut return_size: usize = 0;
+    let status = unsafe {
+        Status::from(proxy_call_foreign_function(
+            PROBE_FOREIGN_FUNCTION.as_ptr(),
+            PROBE_FOREIGN_FUNCTION.len(),
+            null(),
+            0,
+            &mut return_data,
+            &mut return_size,
+        ))
+    };
+    if !return_data.is_null() {
+        unsafe { drop(Vec::from_raw_parts(return_data, return_size, return_size)) };
+    }
+    utils::is_implemented(status)
+}
+
+/// Detects which optional host features are usable, e.g. from `on_vm_start`.
+///
+/// Each feature is probed with a lookup that has no side effects, and counts as available
+/// if the host answers it with either a result or `NotFound`. gRPC and foreign functions
+/// have no such lookup, so they are probed with a call the host has to reject, i.e.
+/// a gRPC call without a service and a call to a foreign function that does not exist,
+/// and count as available unless the host answers `Unimplemented`.
+///
+/// Note that this cannot detect imports that the host does not provide at all,
+/// since the module then fails to instantiate before any code runs. It detects imports
+/// that the host links but does not implement, which is how hosts usually handle
+/// partial ABI support.
+pub fn probe() -> Capabilities {
+    let plugin_name = get_property(crate::properties::paths::PLUGIN_NAME);
+    Capabilities {
+        properties: utils::is_answered(&plugin_name),
+        plugin_name: plugin_name.unwrap_or(None),
+        shared_data: utils::is_answered(&get_shared_data_bytes("")),
+        shared_queues: utils::is_answered(&resolve_shared_queue("", "")),
+        metrics: utils::is_answered(&get_metric(u32::MAX)),
+        grpc: probe_grpc(),
+        foreign_functions: probe_foreign_functions(),
+    }
+}
+
 mod utils {
-    use crate::error::Result;
+    use crate::error::{HostCallError, InvalidMetricNameError, Result};
+    #[cfg(feature = "http")]
     use crate::types::ByteString;
+    use crate::types::Status;
     use std::convert::TryFrom;
+    use std::time::Duration;
+
+    /// Converts a tick period into whole milliseconds, rounding up so that
+    /// a sub-millisecond period doesn't turn into `0`, which disables the timer.
+    pub(super) fn tick_period_millis(period: Duration) -> u32 {
+        let millis = period.as_nanos().div_ceil(1_000_000);
+        u32::try_from(millis).unwrap_or(u32::MAX)
+    }
 
     pub(super) fn serialize_property_path<P>(path: &[P]) -> Vec<u8>
     where
@@ -1007,47 +1867,547 @@ mod utils {
         bytes
     }
 
-    pub(super) fn serialize_map<K, V>(map: &[(K, V)]) -> Vec<u8>
+    pub(super) fn with_authority<'a, K, V>(
+        upstream: &str,
+        authority: &'a str,
+        headers: &'a [(K, V)],
+    ) -> Result<Vec<(&'a [u8], &'a [u8])>>
     where
         K: AsRef<[u8]>,
         V: AsRef<[u8]>,
     {
-        let mut size: usize = 4;
-        for (name, value) in map {
-            size += name.as_ref().len() + value.as_ref().len() + 10;
+        for required in &[":method", ":path"] {
+            let present = headers.iter().any(|(name, value)| {
+                name.as_ref() == required.as_bytes() && !value.as_ref().is_empty()
+            });
+            if !present {
+                return Err(format!(
+                    "HTTP call to \"{}\" is missing the \"{}\" header",
+                    upstream, required
+                )
+                .into());
+            }
         }
-        let mut bytes: Vec<u8> = Vec::with_capacity(size);
-        bytes.extend_from_slice(&map.len().to_le_bytes());
-        for (name, value) in map {
-            bytes.extend_from_slice(&name.as_ref().len().to_le_bytes());
-            bytes.extend_from_slice(&value.as_ref().len().to_le_bytes());
+        let mut result: Vec<(&[u8], &[u8])> = vec![(b":authority", authority.as_bytes())];
+        result.extend(
+            headers
+                .iter()
+                .filter(|(name, _)| name.as_ref() != b":authority")
+                .map(|(name, value)| (name.as_ref(), value.as_ref())),
+        );
+        Ok(result)
+    }
+
+    #[cfg(feature = "http")]
+    pub(super) fn to_header_map(map: &[(ByteString, ByteString)]) -> Result<::http::HeaderMap> {
+        let mut headers = ::http::HeaderMap::with_capacity(map.len());
+        for (name, value) in map.iter().filter(|(name, _)| !name.starts_with(b":")) {
+            let header_name = ::http::HeaderName::from_bytes(name)
+                .map_err(|err| format!("invalid header name \"{}\": {}", name, err))?;
+            let header_value = ::http::HeaderValue::from_bytes(value)
+                .map_err(|err| format!("invalid value of header \"{}\": {}", name, err))?;
+            headers.append(header_name, header_value);
         }
-        for (name, value) in map {
-            bytes.extend_from_slice(name.as_ref());
-            bytes.push(0);
-            bytes.extend_from_slice(value.as_ref());
-            bytes.push(0);
+        Ok(headers)
+    }
+
+    #[cfg(feature = "strict")]
+    pub(super) fn assert_in_context(function: &str) {
+        debug_assert!(
+            crate::dispatcher::in_context(),
+            "{} must be called from a context callback, not from `_start` or a context factory",
+            function
+        );
+    }
+
+    /// Status code of `Unimplemented`, which this crate has no `Status` variant for.
+    const UNIMPLEMENTED: u32 = 12;
+
+    /// Returns `true` if a host call returned any status other than `Unimplemented`.
+    pub(super) fn is_implemented(status: Status) -> bool {
+        status != Status::Unknown(UNIMPLEMENTED)
+    }
+
+    /// Returns `true` if a host call returned either a result or `NotFound`.
+    pub(super) fn is_answered<T>(result: &Result<T>) -> bool {
+        match result {
+            Ok(_) => true,
+            Err(err) => err
+                .downcast_ref::<HostCallError>()
+                .is_some_and(|err| err.status() == Status::NotFound),
         }
-        bytes
     }
 
-    pub(super) fn deserialize_map(bytes: &[u8]) -> Result<Vec<(ByteString, ByteString)>> {
-        let mut map = Vec::new();
-        if bytes.is_empty() {
-            return Ok(map);
+    pub(super) fn validate_metric_name(
+        name: &str,
+    ) -> std::result::Result<(), InvalidMetricNameError> {
+        if name.is_empty() {
+            return Err(InvalidMetricNameError::new(name, None));
+        }
+        match name.char_indices().find(|(_, c)| !c.is_ascii_graphic()) {
+            Some(invalid) => Err(InvalidMetricNameError::new(name, Some(invalid))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{utils, DoneGuard};
+    use crate::error::{HostCallError, Result, SetMapMismatchError, SetPropertiesError};
+    #[cfg(feature = "http")]
+    use crate::types::ByteString;
+    use crate::types::{BufferType, MapType, RawStatus, Status};
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    static DONE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    thread_local! {
+        pub(crate) static REPLACED: RefCell<Vec<(MapType, String, String)>> = const { RefCell::new(Vec::new()) };
+        static ADDED: RefCell<Vec<(MapType, String, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_done() -> RawStatus {
+        DONE_CALLS.fetch_add(1, Ordering::SeqCst);
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_set_property(
+        path_data: *const u8,
+        path_size: usize,
+        _value_data: *const u8,
+        _value_size: usize,
+    ) -> RawStatus {
+        let path = unsafe { std::slice::from_raw_parts(path_data, path_size) };
+        if path.ends_with(b"readonly") {
+            Status::BadArgument.into()
+        } else if path.ends_with(b"unimplemented") {
+            // a status code this crate doesn't know about
+            Status::Unknown(12).into()
+        } else {
+            Status::Ok.into()
         }
-        let size = u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[0..4])?) as usize;
-        let mut p = 4 + size * 8;
-        for n in 0..size {
-            let s = 4 + n * 8;
-            let size = u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[s..s + 4])?) as usize;
-            let key = bytes[p..p + size].to_vec();
-            p += size + 1;
-            let size = u32::from_le_bytes(<[u8; 4]>::try_from(&bytes[s + 4..s + 8])?) as usize;
-            let value = bytes[p..p + size].to_vec();
-            p += size + 1;
-            map.push((key.into(), value.into()));
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_get_buffer_bytes(
+        buffer_type: BufferType,
+        _start: usize,
+        _max_size: usize,
+        return_buffer_data: *mut *mut u8,
+        return_buffer_size: *mut usize,
+    ) -> RawStatus {
+        let data: &[u8] = match buffer_type {
+            BufferType::VmConfiguration => return Status::Ok.into(),
+            BufferType::PluginConfiguration => b"",
+            _ => b"data",
+        };
+        unsafe {
+            *return_buffer_data = crate::allocator::proxy_on_memory_allocate(data.len());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), *return_buffer_data, data.len());
+            *return_buffer_size = data.len();
+        }
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_get_header_map_value(
+        _map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        return_value_data: *mut *mut u8,
+        return_value_size: *mut usize,
+    ) -> RawStatus {
+        let key = unsafe { std::slice::from_raw_parts(key_data, key_size) };
+        let value: &[u8] = match key {
+            b"short" => b"abc",
+            b"long" => b"abcdefghijklmnopqrstuvwxyz",
+            _ => return Status::Ok.into(),
+        };
+        unsafe {
+            *return_value_data = crate::allocator::proxy_on_memory_allocate(value.len());
+            std::ptr::copy_nonoverlapping(value.as_ptr(), *return_value_data, value.len());
+            *return_value_size = value.len();
         }
-        Ok(map)
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_get_header_map_pairs(
+        _map_type: MapType,
+        return_map_data: *mut *mut u8,
+        return_map_size: *mut usize,
+    ) -> RawStatus {
+        let map = crate::codec::encode_map(&[
+            ("x-b", "1"),
+            ("accept", "*/*"),
+            ("x-a", "2"),
+            ("accept", "text/html"),
+        ]);
+        unsafe {
+            *return_map_data = crate::allocator::proxy_on_memory_allocate(map.len());
+            std::ptr::copy_nonoverlapping(map.as_ptr(), *return_map_data, map.len());
+            *return_map_size = map.len();
+        }
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_replace_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+    ) -> RawStatus {
+        let key = unsafe { std::slice::from_raw_parts(key_data, key_size) };
+        let value = unsafe { std::slice::from_raw_parts(value_data, value_size) };
+        REPLACED.with(|replaced| {
+            replaced.borrow_mut().push((
+                map_type,
+                String::from_utf8_lossy(key).into_owned(),
+                String::from_utf8_lossy(value).into_owned(),
+            ))
+        });
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_add_header_map_value(
+        map_type: MapType,
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+    ) -> RawStatus {
+        let key = unsafe { std::slice::from_raw_parts(key_data, key_size) };
+        let value = unsafe { std::slice::from_raw_parts(value_data, value_size) };
+        ADDED.with(|added| {
+            added.borrow_mut().push((
+                map_type,
+                String::from_utf8_lossy(key).into_owned(),
+                String::from_utf8_lossy(value).into_owned(),
+            ))
+        });
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_set_header_map_pairs(
+        _map_type: MapType,
+        _map_data: *const u8,
+        _map_size: usize,
+    ) -> RawStatus {
+        Status::Ok.into()
+    }
+
+    #[test]
+    fn test_set_map_checked() {
+        crate::dispatcher::with_active_context(set_map_checked)
+    }
+
+    fn set_map_checked() {
+        // the stub host always holds the same four entries
+        let map = [
+            ("X-B", "1"),
+            ("accept", "*/*"),
+            ("x-a", "2"),
+            ("accept", "text/html"),
+        ];
+        super::set_map_checked(MapType::HttpRequestHeaders, &map).unwrap();
+
+        let map = [
+            (":status", "403"),
+            ("x-a", "2"),
+            (":status", "404"),
+            ("x-b", "1"),
+            ("accept", "*/*"),
+            ("x-a", "2"),
+            ("accept", "text/html"),
+        ];
+        let err = super::set_map_checked(MapType::HttpRequestHeaders, &map).unwrap_err();
+        let err = err.downcast_ref::<SetMapMismatchError>().unwrap();
+        assert_eq!((err.expected(), err.actual()), (7, 4));
+        assert_eq!(err.missing().len(), 1);
+        assert_eq!(err.missing()[0], ":status");
+        assert_eq!(
+            err.to_string(),
+            "map holds 4 of 7 entries after setting it, missing: :status"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_set_map_from_serialize() {
+        crate::dispatcher::with_active_context(set_map_from_serialize)
+    }
+
+    #[cfg(feature = "serde_json")]
+    fn set_map_from_serialize() {
+        use std::collections::BTreeMap;
+
+        let mut defaults = BTreeMap::new();
+        defaults.insert("x-b", vec!["1"]);
+        defaults.insert("set-cookie", vec!["a=1", "b=2"]);
+        super::set_map_from_serialize(MapType::HttpResponseHeaders, &defaults).unwrap();
+
+        let entry = |name: &str, value: &str| {
+            (
+                MapType::HttpResponseHeaders,
+                name.to_owned(),
+                value.to_owned(),
+            )
+        };
+        REPLACED.with(|replaced| {
+            assert_eq!(
+                *replaced.borrow(),
+                vec![entry("set-cookie", "a=1"), entry("x-b", "1")]
+            )
+        });
+        ADDED.with(|added| assert_eq!(*added.borrow(), vec![entry("set-cookie", "b=2")]));
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn test_to_header_map() {
+        let map: Vec<(ByteString, ByteString)> = vec![
+            (":path".into(), "/".into()),
+            ("Accept".into(), "*/*".into()),
+            ("x-raw".into(), vec![0xff, b'a'].into()),
+            ("accept".into(), "text/html".into()),
+        ];
+        let headers = utils::to_header_map(&map).unwrap();
+        assert_eq!(headers.len(), 3);
+        let accepts: Vec<&[u8]> = headers
+            .get_all("accept")
+            .iter()
+            .map(|v| v.as_bytes())
+            .collect();
+        assert_eq!(accepts, vec![&b"*/*"[..], &b"text/html"[..]]);
+        assert_eq!(headers["x-raw"].as_bytes(), &[0xff, b'a']);
+
+        let map: Vec<(ByteString, ByteString)> = vec![("bad name".into(), "1".into())];
+        let err = utils::to_header_map(&map).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid header name \"bad name\""));
+
+        let map: Vec<(ByteString, ByteString)> = vec![("x-a".into(), "a\nb".into())];
+        let err = utils::to_header_map(&map).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid value of header \"x-a\""));
+    }
+
+    #[test]
+    fn test_get_map_sorted() {
+        crate::dispatcher::with_active_context(get_map_sorted)
+    }
+
+    fn get_map_sorted() {
+        let map = super::get_map_sorted(MapType::HttpRequestHeaders).unwrap();
+        let map: Vec<(&str, &str)> = map
+            .iter()
+            .map(|(name, value)| (name.as_str().unwrap(), value.as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            map,
+            vec![
+                ("accept", "*/*"),
+                ("accept", "text/html"),
+                ("x-a", "2"),
+                ("x-b", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_has_map_value() {
+        crate::dispatcher::with_active_context(|| {
+            assert!(super::has_map_value(MapType::HttpRequestHeaders, "short").unwrap());
+            assert!(!super::has_map_value(MapType::HttpRequestHeaders, "none").unwrap());
+        })
+    }
+
+    #[test]
+    fn test_get_map_value_into() {
+        crate::dispatcher::with_active_context(get_map_value_into)
+    }
+
+    fn get_map_value_into() {
+        let mut buf = Vec::with_capacity(8);
+        let data = buf.as_ptr();
+        assert!(super::get_map_value_into(MapType::HttpRequestHeaders, "short", &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+        assert_eq!(buf.as_ptr(), data);
+
+        assert!(!super::get_map_value_into(MapType::HttpRequestHeaders, "none", &mut buf).unwrap());
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), data);
+
+        // doesn't fit, so the buffer is replaced by the host allocation
+        assert!(super::get_map_value_into(MapType::HttpRequestHeaders, "long", &mut buf).unwrap());
+        assert_eq!(buf, b"abcdefghijklmnopqrstuvwxyz");
+        let data = buf.as_ptr();
+        assert!(super::get_map_value_into(MapType::HttpRequestHeaders, "short", &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+        assert_eq!(buf.as_ptr(), data);
+
+        // no capacity to land in
+        let mut buf = Vec::new();
+        assert!(super::get_map_value_into(MapType::HttpRequestHeaders, "short", &mut buf).unwrap());
+        assert_eq!(buf, b"abc");
+    }
+
+    #[test]
+    fn test_get_buffer_empty() {
+        crate::dispatcher::with_active_context(get_buffer_empty)
+    }
+
+    fn get_buffer_empty() {
+        // null pointer
+        assert_eq!(
+            super::get_buffer(BufferType::VmConfiguration, 0, usize::MAX).unwrap(),
+            None
+        );
+        // zero-length pointer
+        assert_eq!(
+            super::get_buffer(BufferType::PluginConfiguration, 0, usize::MAX).unwrap(),
+            None
+        );
+        assert_eq!(
+            super::get_buffer(BufferType::CallData, 0, usize::MAX).unwrap(),
+            Some("data".into())
+        );
+    }
+
+    #[test]
+    fn test_done_guard() {
+        let before = DONE_CALLS.load(Ordering::SeqCst);
+        let mut guard = DoneGuard::new();
+        assert!(!guard.is_done());
+        guard.done().unwrap();
+        guard.done().unwrap();
+        assert!(guard.is_done());
+        drop(guard);
+        assert_eq!(DONE_CALLS.load(Ordering::SeqCst), before + 1);
+
+        drop(DoneGuard::new());
+        assert_eq!(DONE_CALLS.load(Ordering::SeqCst), before + 2);
+    }
+
+    #[test]
+    fn test_set_properties() {
+        super::set_properties(&[(&["a", "b"][..], "1"), (&["c"][..], "2")]).unwrap();
+
+        let err = super::set_properties(&[
+            (&["a", "b"][..], "1"),
+            (&["c", "readonly"][..], "2"),
+            (&["d"][..], "3"),
+        ])
+        .unwrap_err();
+        let err = err.downcast_ref::<SetPropertiesError>().unwrap();
+        assert_eq!(err.applied(), 1);
+        assert_eq!(err.total(), 3);
+        assert_eq!(err.failed_path(), ["c", "readonly"]);
+    }
+
+    #[test]
+    fn test_unknown_host_status() {
+        let err = super::set_property(&["unimplemented"], Some(b"1")).unwrap_err();
+        let err = err.downcast_ref::<HostCallError>().unwrap();
+        assert_eq!(err.status(), Status::Unknown(12));
+        assert_eq!(
+            err.to_string(),
+            "call to the host ABI function \"env.proxy_set_property\" has failed with status code 12"
+        );
+    }
+
+    #[test]
+    fn test_owned_value_outlives_host_call() {
+        // an owned value must stay alive until the host has copied it
+        let value: Vec<u8> = (1..=64).collect();
+        super::set_shared_data("key", Some(value.clone()), None).unwrap();
+        let (data, _) = super::get_shared_data("key").unwrap();
+        assert_eq!(data.unwrap(), value);
+    }
+
+    #[test]
+    fn test_tick_period_millis() {
+        assert_eq!(utils::tick_period_millis(Duration::from_secs(0)), 0);
+        assert_eq!(utils::tick_period_millis(Duration::from_nanos(1)), 1);
+        assert_eq!(utils::tick_period_millis(Duration::from_micros(500)), 1);
+        assert_eq!(utils::tick_period_millis(Duration::from_millis(1)), 1);
+        assert_eq!(utils::tick_period_millis(Duration::from_micros(1500)), 2);
+        assert_eq!(utils::tick_period_millis(Duration::from_secs(5)), 5000);
+        assert_eq!(utils::tick_period_millis(Duration::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn test_is_answered() {
+        assert!(utils::is_answered(&Ok(())));
+        let not_found: Result<()> = Err(HostCallError::new("f", Status::NotFound).into());
+        assert!(utils::is_answered(&not_found));
+        let failure: Result<()> = Err(HostCallError::new("f", Status::InternalFailure).into());
+        assert!(!utils::is_answered(&failure));
+        let other: Result<()> = Err("unexpected".into());
+        assert!(!utils::is_answered(&other));
+    }
+
+    #[test]
+    fn test_is_implemented() {
+        assert!(utils::is_implemented(Status::Ok));
+        assert!(utils::is_implemented(Status::NotFound));
+        assert!(utils::is_implemented(Status::BadArgument));
+        // ParseFailure
+        assert!(utils::is_implemented(Status::Unknown(4)));
+        // Unimplemented
+        assert!(!utils::is_implemented(Status::Unknown(12)));
+    }
+
+    #[test]
+    fn test_with_authority() {
+        let headers = [(":method", "GET"), (":authority", "old"), (":path", "/")];
+        let result = utils::with_authority("cluster", "auth", &headers).unwrap();
+        assert_eq!(
+            result,
+            vec![
+                (&b":authority"[..], &b"auth"[..]),
+                (&b":method"[..], &b"GET"[..]),
+                (&b":path"[..], &b"/"[..]),
+            ]
+        );
+
+        let err = utils::with_authority("cluster", "auth", &[(":method", "GET")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "HTTP call to \"cluster\" is missing the \":path\" header"
+        );
+        let headers = [(":method", ""), (":path", "/")];
+        assert!(utils::with_authority("cluster", "auth", &headers).is_err());
+    }
+
+    #[test]
+    fn test_validate_metric_name() {
+        assert!(utils::validate_metric_name("http.requests_total").is_ok());
+
+        let err = utils::validate_metric_name("requests total").unwrap_err();
+        assert_eq!(err.position(), Some(8));
+        assert_eq!(err.character(), Some(' '));
+        assert_eq!(
+            err.to_string(),
+            "metric name \"requests total\" contains invalid character ' ' at position 8"
+        );
+
+        let err = utils::validate_metric_name("caf\u{e9}").unwrap_err();
+        assert_eq!(err.character(), Some('\u{e9}'));
+
+        let err = utils::validate_metric_name("").unwrap_err();
+        assert_eq!(err.position(), None);
+        assert_eq!(err.to_string(), "metric name must not be empty");
     }
 }
@@ -0,0 +1,915 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for working with HTTP messages.
+
+use std::fmt;
+
+use crate::bytestring::percent_decode;
+use crate::error::Result;
+use crate::hostcalls;
+use crate::types::{ByteStr, ByteString, MapType};
+
+/// Represents a pseudo-header, e.g. `:path`.
+///
+/// Can be used wherever a header name is expected.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::http::PseudoHeader;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// hostcalls::set_map_value(MapType::HttpRequestHeaders, PseudoHeader::Path, Some("/v2/stuff"))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PseudoHeader {
+    Method,
+    Scheme,
+    Authority,
+    Path,
+    Status,
+}
+
+impl PseudoHeader {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PseudoHeader::Method => ":method",
+            PseudoHeader::Scheme => ":scheme",
+            PseudoHeader::Authority => ":authority",
+            PseudoHeader::Path => ":path",
+            PseudoHeader::Status => ":status",
+        }
+    }
+}
+
+impl AsRef<str> for PseudoHeader {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<[u8]> for PseudoHeader {
+    fn as_ref(&self) -> &[u8] {
+        self.as_str().as_bytes()
+    }
+}
+
+impl fmt::Display for PseudoHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses query parameters out of a request path, e.g. `/search?q=proxy&page=2`.
+///
+/// Parameters are returned in order of appearance, without percent-decoding.
+/// A parameter without `=` has an empty value, empty parameters are skipped,
+/// and a trailing `#fragment` is ignored.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http;
+///
+/// let params = http::parse_query(b"/search?q=proxy&page=2");
+/// assert_eq!(params[0].0, "q");
+/// assert_eq!(params[0].1, "proxy");
+/// assert_eq!(params[1].0, "page");
+/// assert_eq!(params[1].1, "2");
+/// ```
+pub fn parse_query(path: &[u8]) -> Vec<(ByteString, ByteString)> {
+    split_query(path, |bytes| bytes.into())
+}
+
+/// Parses query parameters out of a request path, like [`parse_query`],
+/// and percent-decodes names and values.
+///
+/// `+` is decoded as a space. Malformed percent-encoded sequences are kept as is.
+/// Decoded values are not necessarily valid UTF-8.
+///
+/// [`parse_query`]: fn.parse_query.html
+pub fn parse_query_decoded(path: &[u8]) -> Vec<(ByteString, ByteString)> {
+    split_query(path, |bytes| percent_decode(bytes, true).into())
+}
+
+fn split_query<F>(path: &[u8], decode: F) -> Vec<(ByteString, ByteString)>
+where
+    F: Fn(&[u8]) -> ByteString,
+{
+    let path = match path.iter().position(|&b| b == b'#') {
+        Some(pos) => &path[..pos],
+        None => path,
+    };
+    let query = match path.iter().position(|&b| b == b'?') {
+        Some(pos) => &path[pos + 1..],
+        None => return Vec::new(),
+    };
+    query
+        .split(|&b| b == b'&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.iter().position(|&b| b == b'=') {
+            Some(pos) => (decode(&param[..pos]), decode(&param[pos + 1..])),
+            None => (decode(param), ByteString::new()),
+        })
+        .collect()
+}
+
+/// Parses the value of a `cookie` header, e.g. `session=abc; theme="dark"`.
+///
+/// Cookies are returned in order of appearance. Whitespace around names and values
+/// is trimmed, surrounding double quotes are removed from values, and entries without
+/// `=` are skipped. Values are returned as raw bytes.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http;
+///
+/// let cookies = http::parse_cookies(b"session=abc; theme=\"dark\"");
+/// assert_eq!(cookies[0].0, "session");
+/// assert_eq!(cookies[0].1, "abc");
+/// assert_eq!(cookies[1].0, "theme");
+/// assert_eq!(cookies[1].1, "dark");
+/// ```
+pub fn parse_cookies(header_value: &[u8]) -> Vec<(ByteString, ByteString)> {
+    header_value
+        .split(|&b| b == b';')
+        .filter_map(|pair| {
+            let pos = pair.iter().position(|&b| b == b'=')?;
+            let name = trim_whitespace(&pair[..pos]);
+            if name.is_empty() {
+                return None;
+            }
+            let mut value = trim_whitespace(&pair[pos + 1..]);
+            if value.len() >= 2 && value[0] == b'"' && value[value.len() - 1] == b'"' {
+                value = &value[1..value.len() - 1];
+            }
+            Some((name.into(), value.into()))
+        })
+        .collect()
+}
+
+/// Parses the value of an `authorization` header into its scheme and credentials,
+/// e.g. `Bearer abc.def`.
+///
+/// The value is split on the first space or tab. Whitespace around the scheme and
+/// the credentials is trimmed, so any run of whitespace between them is accepted.
+/// Returns `None` if either part is empty. Credentials are returned as raw bytes.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http;
+///
+/// let (scheme, credentials) = http::parse_authorization(b"Basic  dXNlcjpwYXNz").unwrap();
+/// assert_eq!(scheme, "Basic");
+/// assert_eq!(credentials, "dXNlcjpwYXNz");
+/// ```
+pub fn parse_authorization(header_value: &[u8]) -> Option<(ByteString, ByteString)> {
+    let value = trim_whitespace(header_value);
+    let pos = value.iter().position(|&b| b == b' ' || b == b'\t')?;
+    let scheme = &value[..pos];
+    let credentials = trim_whitespace(&value[pos + 1..]);
+    if scheme.is_empty() || credentials.is_empty() {
+        return None;
+    }
+    Some((scheme.into(), credentials.into()))
+}
+
+/// Returns `true` if a given value is a valid HTTP token (RFC 7230), e.g. a method name.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http;
+///
+/// assert!(http::is_token(b"PATCH"));
+/// assert!(!http::is_token(b"GET /"));
+/// ```
+pub fn is_token(value: &[u8]) -> bool {
+    !value.is_empty()
+        && value.iter().all(|&b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+/// Flattens a map-like value, e.g. a struct with a field per header, into header pairs.
+///
+/// The value is flattened as follows:
+///
+/// * each field, or map entry, becomes a header named after it, ordered by name;
+/// * strings are used as is, numbers and booleans are formatted as in JSON, e.g. `10`, `0.5`
+///   or `true`;
+/// * an array of such values becomes one header per element, e.g. for `set-cookie`;
+/// * `null`, e.g. `None`, skips the header.
+///
+/// Returns an error if the value is not map-like or if a field holds a nested map or array.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http;
+/// use serde::Serialize;
+///
+/// #[derive(Serialize)]
+/// struct ErrorHeaders {
+///     #[serde(rename = "content-type")]
+///     content_type: &'static str,
+///     #[serde(rename = "retry-after")]
+///     retry_after: Option<u32>,
+/// }
+///
+/// let headers = http::serialize_headers(&ErrorHeaders {
+///     content_type: "application/json",
+///     retry_after: Some(30),
+/// })
+/// .unwrap();
+/// assert_eq!(headers[1], ("retry-after".to_owned(), "30".to_owned()));
+/// ```
+#[cfg(feature = "serde_json")]
+pub fn serialize_headers<T>(value: &T) -> Result<Vec<(String, String)>>
+where
+    T: serde::Serialize + ?Sized,
+{
+    use serde_json::Value;
+
+    fn scalar(name: &str, value: Value) -> Result<Option<String>> {
+        match value {
+            Value::Null => Ok(None),
+            Value::String(text) => Ok(Some(text)),
+            Value::Bool(_) | Value::Number(_) => Ok(Some(value.to_string())),
+            Value::Array(_) | Value::Object(_) => {
+                Err(format!("header {:?} must not hold a nested array or map", name).into())
+            }
+        }
+    }
+
+    let fields = match serde_json::to_value(value)? {
+        Value::Object(fields) => fields,
+        _ => return Err("headers must be serialized from a struct or a map".into()),
+    };
+    // `serde_json` only keeps fields sorted while its `preserve_order` feature is off
+    let mut fields: Vec<(String, Value)> = fields.into_iter().collect();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut headers = Vec::with_capacity(fields.len());
+    for (name, value) in fields {
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    if let Some(value) = scalar(&name, value)? {
+                        headers.push((name.clone(), value));
+                    }
+                }
+            }
+            value => {
+                if let Some(value) = scalar(&name, value)? {
+                    headers.push((name, value));
+                }
+            }
+        }
+    }
+    Ok(headers)
+}
+
+fn trim_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|&b| b != b' ' && b != b'\t')
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(start, |pos| pos + 1);
+    &bytes[start..end]
+}
+
+/// Stages edits to a header map and applies them with a single read and a single write.
+///
+/// Header names are matched ASCII case-insensitively.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http::HeaderMapEditor;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// HeaderMapEditor::new(MapType::HttpRequestHeaders)
+///     .replace("x-tenant", "acme")
+///     .add("x-forwarded-for", "10.0.0.1")
+///     .remove("authorization")
+///     .commit()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct HeaderMapEditor {
+    map_type: MapType,
+    edits: Vec<HeaderEdit>,
+}
+
+#[derive(Debug, Clone)]
+enum HeaderEdit {
+    Add(ByteString, ByteString),
+    Replace(ByteString, ByteString),
+    Remove(ByteString),
+}
+
+impl HeaderMapEditor {
+    pub fn new(map_type: MapType) -> Self {
+        HeaderMapEditor {
+            map_type,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Adds a header, keeping existing values of the same name.
+    pub fn add<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: Into<ByteString>,
+        V: Into<ByteString>,
+    {
+        self.edits.push(HeaderEdit::Add(name.into(), value.into()));
+        self
+    }
+
+    /// Sets a header to a single value, replacing all existing values of the same name.
+    pub fn replace<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: Into<ByteString>,
+        V: Into<ByteString>,
+    {
+        self.edits
+            .push(HeaderEdit::Replace(name.into(), value.into()));
+        self
+    }
+
+    /// Removes all values of a header.
+    pub fn remove<K>(&mut self, name: K) -> &mut Self
+    where
+        K: Into<ByteString>,
+    {
+        self.edits.push(HeaderEdit::Remove(name.into()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Applies staged edits in order and writes the map back to the host.
+    ///
+    /// Does nothing if there are no staged edits.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.edits.is_empty() {
+            return Ok(());
+        }
+        let mut map = hostcalls::get_map(self.map_type)?;
+        self.apply(&mut map);
+        hostcalls::set_map(self.map_type, &map)?;
+        self.edits.clear();
+        Ok(())
+    }
+
+    fn apply(&self, map: &mut Vec<(ByteString, ByteString)>) {
+        for edit in &self.edits {
+            match edit {
+                HeaderEdit::Add(name, value) => map.push((name.clone(), value.clone())),
+                HeaderEdit::Replace(name, value) => {
+                    match map.iter().position(|(k, _)| k.eq_ignore_ascii_case(name)) {
+                        Some(pos) => {
+                            map[pos].1 = value.clone();
+                            let mut index = 0;
+                            map.retain(|(k, _)| {
+                                index += 1;
+                                index - 1 == pos || !k.eq_ignore_ascii_case(name)
+                            });
+                        }
+                        None => map.push((name.clone(), value.clone())),
+                    }
+                }
+                HeaderEdit::Remove(name) => map.retain(|(k, _)| !k.eq_ignore_ascii_case(name)),
+            }
+        }
+    }
+}
+
+/// Represents the pairs of a header map, in the order returned by the host.
+///
+/// The pairs are an owned copy, detached from the host: they can be kept past the callback
+/// that read them, e.g. to compare request headers against response headers later in
+/// the same stream, see [`diff`], and changes to them only reach the host via [`write`].
+///
+/// [`diff`]: #method.diff
+/// [`write`]: #method.write
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::http::HeaderMap;
+/// use proxy_wasm::types::MapType;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let headers = HeaderMap::read(MapType::HttpRequestHeaders)?;
+/// for (name, value) in &headers {
+///     // inspect each header without copying it
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(ByteString, ByteString)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a given header map from the host.
+    pub fn read(map_type: MapType) -> Result<Self> {
+        hostcalls::get_map(map_type).map(HeaderMap::from)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the first value of a given header, matching the name ASCII case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&ByteStr> {
+        self.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, v)| v)
+    }
+
+    /// Returns an iterator over the pairs, without copying them.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.entries.iter(),
+        }
+    }
+
+    /// Removes pairs that repeat an earlier pair, keeping the first one of each.
+    ///
+    /// Names are compared ASCII case-insensitively and values byte for byte, so
+    /// `vary: accept` and `Vary: accept` are duplicates, while `vary: Accept` is not.
+    /// The remaining pairs keep their order.
+    pub fn dedup(&mut self) {
+        let mut kept: Vec<(ByteString, ByteString)> = Vec::with_capacity(self.entries.len());
+        for (name, value) in self.entries.drain(..) {
+            if !kept
+                .iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(&name) && *v == value)
+            {
+                kept.push((name, value));
+            }
+        }
+        self.entries = kept;
+    }
+
+    /// Sorts pairs by name, byte by byte.
+    ///
+    /// The sort is stable, so values of a multi-valued header, e.g. `set-cookie`,
+    /// keep their relative order.
+    pub fn sort_stable(&mut self) {
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    /// Replaces a given header map on the host with these pairs.
+    pub fn write(&self, map_type: MapType) -> Result<()> {
+        hostcalls::set_map(map_type, &self.entries)
+    }
+
+    /// Returns the pairs removed from and added to this map to get `other`, e.g. for debug logs.
+    ///
+    /// Names are compared ASCII case-insensitively and values byte for byte. A changed value
+    /// is reported as a removed pair followed, after all removals, by an added one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::http::HeaderMap;
+    ///
+    /// let before = HeaderMap::from(vec![("x-tenant".into(), "acme".into())]);
+    /// let after = HeaderMap::from(vec![("x-tenant".into(), "other".into())]);
+    /// let changes: Vec<String> = before.diff(&after).iter().map(|c| c.to_string()).collect();
+    /// assert_eq!(changes, ["- x-tenant: acme", "+ x-tenant: other"]);
+    /// ```
+    pub fn diff<'a>(&'a self, other: &'a HeaderMap) -> Vec<HeaderChange<'a>> {
+        let mut unmatched: Vec<Option<&(ByteString, ByteString)>> =
+            other.entries.iter().map(Some).collect();
+        let mut changes = Vec::new();
+        for (name, value) in &self.entries {
+            let found = unmatched.iter_mut().find(|entry| {
+                entry.is_some_and(|(k, v)| k.eq_ignore_ascii_case(name) && v == value)
+            });
+            match found {
+                Some(entry) => *entry = None,
+                None => changes.push(HeaderChange::Removed(name, value)),
+            }
+        }
+        changes.extend(
+            unmatched
+                .into_iter()
+                .flatten()
+                .map(|(name, value)| HeaderChange::Added(name, value)),
+        );
+        changes
+    }
+}
+
+/// A difference between two [`HeaderMap`]s, see [`HeaderMap::diff`].
+///
+/// [`HeaderMap`]: struct.HeaderMap.html
+/// [`HeaderMap::diff`]: struct.HeaderMap.html#method.diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderChange<'a> {
+    Added(&'a ByteStr, &'a ByteStr),
+    Removed(&'a ByteStr, &'a ByteStr),
+}
+
+impl fmt::Display for HeaderChange<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderChange::Added(name, value) => write!(f, "+ {}: {}", name, value),
+            HeaderChange::Removed(name, value) => write!(f, "- {}: {}", name, value),
+        }
+    }
+}
+
+impl From<Vec<(ByteString, ByteString)>> for HeaderMap {
+    fn from(entries: Vec<(ByteString, ByteString)>) -> Self {
+        HeaderMap { entries }
+    }
+}
+
+impl From<HeaderMap> for Vec<(ByteString, ByteString)> {
+    fn from(map: HeaderMap) -> Self {
+        map.entries
+    }
+}
+
+impl IntoIterator for HeaderMap {
+    type Item = (ByteString, ByteString);
+    type IntoIter = std::vec::IntoIter<(ByteString, ByteString)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = (&'a ByteStr, &'a ByteStr);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A borrowing iterator over the pairs of a [`HeaderMap`].
+///
+/// [`HeaderMap`]: struct.HeaderMap.html
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    inner: std::slice::Iter<'a, (ByteString, ByteString)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a ByteStr, &'a ByteStr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, v)| (&**k, &**v))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_map_iter() {
+        let map = HeaderMap::from(vec![
+            ("Host".into(), "example.org".into()),
+            ("x-b3".into(), vec![0u8, 255].into()),
+        ]);
+        assert_eq!(map.get("host").unwrap(), "example.org");
+        assert_eq!(map.get("missing"), None);
+
+        let mut iter = map.iter();
+        assert_eq!(iter.len(), 2);
+        let (name, value) = iter.next().unwrap();
+        assert_eq!(name, "Host");
+        assert_eq!(value, "example.org");
+
+        let names: Vec<&ByteStr> = (&map).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(names, ["Host", "x-b3"]);
+
+        let owned: Vec<(ByteString, ByteString)> = map.into_iter().collect();
+        assert_eq!(owned[1].1, vec![0u8, 255]);
+    }
+
+    #[test]
+    fn test_header_map_dedup_and_sort() {
+        let mut map = HeaderMap::from(vec![
+            ("vary".into(), "accept".into()),
+            ("set-cookie".into(), "b=2".into()),
+            ("Vary".into(), "accept".into()),
+            ("set-cookie".into(), "a=1".into()),
+            ("vary".into(), "Accept".into()),
+            ("set-cookie".into(), "b=2".into()),
+            ("cache-control".into(), "no-store".into()),
+        ]);
+        map.dedup();
+        map.sort_stable();
+
+        let pairs: Vec<(&str, &str)> = map
+            .iter()
+            .map(|(k, v)| (k.as_str().unwrap(), v.as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("cache-control", "no-store"),
+                ("set-cookie", "b=2"),
+                ("set-cookie", "a=1"),
+                ("vary", "accept"),
+                ("vary", "Accept"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_map_diff() {
+        let request = HeaderMap::from(vec![
+            ("accept".into(), "*/*".into()),
+            ("x-tenant".into(), "acme".into()),
+            ("cookie".into(), "a=1".into()),
+            ("cookie".into(), "a=1".into()),
+        ]);
+        let response = HeaderMap::from(vec![
+            ("Cookie".into(), "a=1".into()),
+            ("x-tenant".into(), "other".into()),
+            ("accept".into(), "*/*".into()),
+            ("server".into(), "envoy".into()),
+        ]);
+        let changes: Vec<String> = request
+            .diff(&response)
+            .iter()
+            .map(|change| change.to_string())
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                "- x-tenant: acme",
+                "- cookie: a=1",
+                "+ x-tenant: other",
+                "+ server: envoy",
+            ]
+        );
+        assert!(request.diff(&request).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_serialize_headers() {
+        use serde::Serialize;
+        use std::collections::BTreeMap;
+
+        #[derive(Serialize)]
+        struct Headers {
+            server: &'static str,
+            #[serde(rename = "x-ratio")]
+            ratio: f64,
+            #[serde(rename = "x-cached")]
+            cached: bool,
+            #[serde(rename = "set-cookie")]
+            cookies: Vec<&'static str>,
+            #[serde(rename = "retry-after")]
+            retry_after: Option<u32>,
+        }
+
+        let headers = serialize_headers(&Headers {
+            server: "envoy",
+            ratio: 0.5,
+            cached: false,
+            cookies: vec!["a=1", "b=2"],
+            retry_after: None,
+        })
+        .unwrap();
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                ("server", "envoy"),
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+                ("x-cached", "false"),
+                ("x-ratio", "0.5"),
+            ]
+        );
+
+        let mut nested = BTreeMap::new();
+        nested.insert("x-nested", vec![vec![1]]);
+        assert_eq!(
+            serialize_headers(&nested).unwrap_err().to_string(),
+            "header \"x-nested\" must not hold a nested array or map"
+        );
+        assert!(serialize_headers(&"text").is_err());
+    }
+
+    #[test]
+    fn test_is_token() {
+        assert!(is_token(b"GET"));
+        assert!(is_token(b"M-SEARCH"));
+        assert!(is_token(b"x!#$%&'*+.^_`|~9"));
+        assert!(!is_token(b""));
+        assert!(!is_token(b"GET\r\n"));
+        assert!(!is_token(b"P(ST"));
+        assert!(!is_token("PÓST".as_bytes()));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query(b"/path?a=1&b=&c&&d=x=y#frag");
+        assert_eq!(
+            params,
+            vec![
+                ("a".into(), "1".into()),
+                ("b".into(), "".into()),
+                ("c".into(), "".into()),
+                ("d".into(), "x=y".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_query_without_query() {
+        assert!(parse_query(b"/path").is_empty());
+        assert!(parse_query(b"/path?").is_empty());
+        assert!(parse_query(b"/path#a=1").is_empty());
+        assert!(parse_query(b"/a#frag?x=1").is_empty());
+        assert!(parse_query_decoded(b"/a#frag?x=1").is_empty());
+    }
+
+    #[test]
+    fn test_parse_query_raw() {
+        let params = parse_query(b"/?q=a+b%20c");
+        assert_eq!(params, vec![("q".into(), "a+b%20c".into())]);
+    }
+
+    #[test]
+    fn test_parse_query_decoded() {
+        let params = parse_query_decoded(b"/?q=a+b%20c&na%6De=%e2%9C%93&bin=%FF");
+        assert_eq!(
+            params,
+            vec![
+                ("q".into(), "a b c".into()),
+                ("name".into(), "\u{2713}".into()),
+                ("bin".into(), vec![0xff].into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cookies() {
+        let cookies = parse_cookies(b"  a=1;b=2 ;\tc = \"x y\" ; ; d ; =e; f=; g=\"");
+        assert_eq!(
+            cookies,
+            vec![
+                ("a".into(), "1".into()),
+                ("b".into(), "2".into()),
+                ("c".into(), "x y".into()),
+                ("f".into(), "".into()),
+                ("g".into(), "\"".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cookies_binary() {
+        let cookies = parse_cookies(b"a=\xff\x00");
+        assert_eq!(cookies, vec![("a".into(), vec![0xff, 0x00].into())]);
+    }
+
+    #[test]
+    fn test_parse_authorization() {
+        assert_eq!(
+            parse_authorization(b"Bearer abc.def"),
+            Some(("Bearer".into(), "abc.def".into()))
+        );
+        assert_eq!(
+            parse_authorization(b" \tBasic \t dXNlcjpwYXNz== "),
+            Some(("Basic".into(), "dXNlcjpwYXNz==".into()))
+        );
+        assert_eq!(
+            parse_authorization(b"Custom a b"),
+            Some(("Custom".into(), "a b".into()))
+        );
+        assert_eq!(
+            parse_authorization(b"Raw \xff\x00"),
+            Some(("Raw".into(), vec![0xff, 0x00].into()))
+        );
+        assert_eq!(parse_authorization(b"Bearer"), None);
+        assert_eq!(parse_authorization(b"Bearer   "), None);
+        assert_eq!(parse_authorization(b""), None);
+    }
+
+    #[test]
+    fn test_header_map_editor_apply() {
+        let mut map: Vec<(ByteString, ByteString)> = vec![
+            (":path".into(), "/".into()),
+            ("set-cookie".into(), "a=1".into()),
+            ("X-Tenant".into(), "old".into()),
+            ("set-cookie".into(), "b=2".into()),
+            ("x-tenant".into(), "older".into()),
+        ];
+        let mut editor = HeaderMapEditor::new(MapType::HttpRequestHeaders);
+        editor
+            .replace("x-tenant", "acme")
+            .remove("Set-Cookie")
+            .add("via", "proxy")
+            .replace("x-new", "1");
+        editor.apply(&mut map);
+        assert_eq!(
+            map,
+            vec![
+                (":path".into(), "/".into()),
+                ("X-Tenant".into(), "acme".into()),
+                ("via".into(), "proxy".into()),
+                ("x-new".into(), "1".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_map_editor_commit_empty() {
+        let mut editor = HeaderMapEditor::new(MapType::HttpRequestHeaders);
+        assert!(editor.is_empty());
+        editor.commit().unwrap();
+    }
+
+    #[test]
+    fn test_percent_decode_malformed() {
+        assert_eq!(percent_decode(b"100%", true), b"100%");
+        assert_eq!(percent_decode(b"%4", true), b"%4");
+        assert_eq!(percent_decode(b"%zz%41", true), b"%zzA");
+        assert_eq!(percent_decode(b"a+b", false), b"a+b");
+    }
+}
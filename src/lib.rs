@@ -13,21 +13,82 @@
 // limitations under the License.
 
 #![doc(html_root_url = "https://docs.rs/proxy-wasm-experimental/0.0.8")]
+//! Building without the default `std` feature leaves only the parts that do not depend
+//! on the host ABI, i.e. [`error`] and [`types`], including the byte string types and
+//! the header map codec. These only require `alloc`.
+//!
+//! [`error`]: error/index.html
+//! [`types`]: types/index.html
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod macros;
+
+#[cfg(feature = "std")]
+pub mod body;
+#[cfg(feature = "std")]
+pub mod callout;
 pub mod error;
+#[cfg(feature = "std")]
 pub mod hostcalls;
+#[cfg(feature = "std")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod properties;
+#[cfg(feature = "std")]
+pub mod shared_data;
+#[cfg(feature = "std")]
+pub mod shared_queue;
+#[cfg(feature = "std")]
 pub mod traits;
 pub mod types;
 
+#[cfg(feature = "std")]
 mod allocator;
 mod bytestring;
+mod codec;
+#[cfg(feature = "std")]
 mod dispatcher;
+#[cfg(feature = "std")]
 mod logger;
 
+#[cfg(feature = "std")]
 pub fn set_log_level(level: types::LogLevel) {
     logger::set_log_level(level);
 }
 
+/// Returns `true` if messages at a given log level pass the level set via [`set_log_level`].
+///
+/// [`set_log_level`]: fn.set_log_level.html
+#[cfg(feature = "std")]
+pub fn log_enabled(level: types::LogLevel) -> bool {
+    logger::enabled(level)
+}
+
+/// Registers a callback that runs exactly once, right before the first context is created.
+///
+/// This is the place for process-wide initialization, e.g. installing a custom panic hook,
+/// that must happen before any root context is constructed, no matter how many root contexts
+/// the plugin ends up with. Register it from `_start` along with [`set_root_context`].
+///
+/// Logging via [`set_log_level`] is safe to initialize here, but note that it installs its own
+/// panic hook, so a custom hook should be installed afterwards.
+///
+/// [`set_root_context`]: fn.set_root_context.html
+/// [`set_log_level`]: fn.set_log_level.html
+#[cfg(feature = "std")]
+pub fn set_vm_init<F>(callback: F)
+where
+    F: FnOnce() + 'static,
+{
+    dispatcher::set_vm_init(Box::new(callback));
+}
+
+#[cfg(feature = "std")]
 pub fn set_root_context<F>(callback: F)
 where
     F: FnMut(u32) -> Box<dyn traits::RootContext> + 'static,
@@ -35,6 +96,7 @@ where
     dispatcher::set_root_context(Box::new(callback));
 }
 
+#[cfg(feature = "std")]
 pub fn set_stream_context<F>(callback: F)
 where
     F: FnMut(u32, u32) -> Box<dyn traits::StreamContext> + 'static,
@@ -42,6 +104,7 @@ where
     dispatcher::set_stream_context(Box::new(callback));
 }
 
+#[cfg(feature = "std")]
 pub fn set_http_context<F>(callback: F)
 where
     F: FnMut(u32, u32) -> Box<dyn traits::HttpContext> + 'static,
@@ -49,5 +112,85 @@ where
     dispatcher::set_http_context(Box::new(callback));
 }
 
+/// Returns tokens of HTTP calls dispatched by a given context that are still pending a response.
+#[cfg(feature = "std")]
+pub fn pending_http_calls(context_id: u32) -> Vec<u32> {
+    dispatcher::pending_http_calls(context_id)
+}
+
+/// Forgets HTTP calls dispatched by a given context that are still pending a response,
+/// e.g. when a root context is reconfigured. Their responses will be dropped.
+///
+/// Returns the number of forgotten calls.
+#[cfg(feature = "std")]
+pub fn forget_http_calls(context_id: u32) -> usize {
+    dispatcher::forget_http_calls(context_id)
+}
+
+/// Cancels a pending HTTP call, e.g. a speculative callout whose response is no longer needed.
+///
+/// ABI 0.2.0 has no way to cancel the call on the host, so it keeps running there,
+/// but its response will be dropped without invoking `on_http_call_response`.
+///
+/// Returns `false` if the call has already completed, been cancelled or never existed.
+#[cfg(feature = "std")]
+pub fn cancel_http_call(token_id: u32) -> bool {
+    dispatcher::cancel_http_call(token_id)
+}
+
+/// Stores data for a given context, e.g. to hand a callout result from a root context
+/// over to one of its child contexts. The data is dropped once the context is deleted.
+///
+/// Returns the data previously stored for the context, if any.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// # let child_id = 2;
+/// proxy_wasm::set_context_data(child_id, Box::new(String::from("allowed")));
+///
+/// // later, from the child context
+/// if let Some(data) = proxy_wasm::take_context_data(child_id) {
+///     if let Ok(verdict) = data.downcast::<String>() {
+///         // act on the verdict
+///     }
+/// }
+/// ```
+#[cfg(feature = "std")]
+pub fn set_context_data(
+    context_id: u32,
+    data: Box<dyn std::any::Any>,
+) -> Option<Box<dyn std::any::Any>> {
+    dispatcher::set_context_data(context_id, data)
+}
+
+/// Removes and returns data stored for a given context via [`set_context_data`].
+///
+/// [`set_context_data`]: fn.set_context_data.html
+#[cfg(feature = "std")]
+pub fn take_context_data(context_id: u32) -> Option<Box<dyn std::any::Any>> {
+    dispatcher::take_context_data(context_id)
+}
+
+/// Returns the number of live root, stream and HTTP contexts, in that order.
+///
+/// Useful for spotting context leaks, e.g. by logging the counts from `on_tick`.
+#[cfg(feature = "std")]
+pub fn context_counts() -> (usize, usize, usize) {
+    dispatcher::context_counts()
+}
+
+/// Registers a root context under a given id without going through `on_context_create`,
+/// so that child contexts can be created for it in tests.
+///
+/// Child contexts are then created once `proxy_on_context_create` is called
+/// with the seeded id as `root_context_id`.
+#[cfg(feature = "test-host")]
+pub fn seed_root_context(context_id: u32, root_context: Box<dyn traits::RootContext>) {
+    dispatcher::seed_root_context(context_id, root_context);
+}
+
+#[cfg(feature = "std")]
 #[no_mangle]
 pub extern "C" fn proxy_abi_version_0_2_0() {}
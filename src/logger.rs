@@ -15,12 +15,13 @@
 use crate::hostcalls;
 use crate::types::LogLevel;
 use std::panic;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 struct Logger;
 
 static LOGGER: Logger = Logger;
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static LEVEL: AtomicU32 = AtomicU32::new(LogLevel::Trace as u32);
 
 pub(crate) fn set_log_level(level: LogLevel) {
     if !INITIALIZED.load(Ordering::Relaxed) {
@@ -31,6 +32,16 @@ pub(crate) fn set_log_level(level: LogLevel) {
         INITIALIZED.store(true, Ordering::Relaxed);
     }
     LOGGER.set_log_level(level);
+    LEVEL.store(level as u32, Ordering::Relaxed);
+}
+
+/// Returns `true` if messages at a given log level pass the level set via `set_log_level`.
+pub(crate) fn enabled(level: LogLevel) -> bool {
+    passes(level as u32, LEVEL.load(Ordering::Relaxed))
+}
+
+fn passes(level: u32, threshold: u32) -> bool {
+    level >= threshold
 }
 
 impl Logger {
@@ -69,3 +80,17 @@ impl log::Log for Logger {
 
     fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passes() {
+        assert!(passes(LogLevel::Info as u32, LogLevel::Info as u32));
+        assert!(passes(LogLevel::Error as u32, LogLevel::Info as u32));
+        assert!(passes(LogLevel::Critical as u32, LogLevel::Critical as u32));
+        assert!(!passes(LogLevel::Debug as u32, LogLevel::Info as u32));
+        assert!(!passes(LogLevel::Error as u32, LogLevel::Critical as u32));
+    }
+}
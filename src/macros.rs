@@ -0,0 +1,82 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Logs a message at a given log level.
+///
+/// The message is formatted only if the level passes the level set via
+/// [`set_log_level`]. Errors returned by the host are ignored.
+///
+/// These macros share their names with the ones of the `log` crate, so call them by path,
+/// e.g. `proxy_wasm::info!`, rather than importing both. Unlike the `log` crate ones, they
+/// work before the logger is installed via [`set_log_level`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::types::LogLevel;
+///
+/// proxy_wasm::log!(LogLevel::Info, "request to {} took {}ms", "/v1/stuff", 42);
+/// ```
+///
+/// [`set_log_level`]: fn.set_log_level.html
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::log_enabled(level) {
+            let _ = $crate::hostcalls::log(level, &format!($($arg)+));
+        }
+    }};
+}
+
+/// Logs a message at the `Trace` level. See [`log!`](macro.log.html).
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::types::LogLevel::Trace, $($arg)+)
+    };
+}
+
+/// Logs a message at the `Debug` level. See [`log!`](macro.log.html).
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::types::LogLevel::Debug, $($arg)+)
+    };
+}
+
+/// Logs a message at the `Info` level. See [`log!`](macro.log.html).
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::types::LogLevel::Info, $($arg)+)
+    };
+}
+
+/// Logs a message at the `Warn` level. See [`log!`](macro.log.html).
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::types::LogLevel::Warn, $($arg)+)
+    };
+}
+
+/// Logs a message at the `Error` level. See [`log!`](macro.log.html).
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::types::LogLevel::Error, $($arg)+)
+    };
+}
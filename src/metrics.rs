@@ -0,0 +1,456 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed handles for metrics.
+//!
+//! Each handle wraps the id returned by the host, so it is `Copy` and can be stored
+//! in as many contexts as needed.
+
+use crate::error::{MetricsBatchError, Result};
+use crate::hostcalls;
+use crate::types::MetricType;
+use std::collections::{btree_map, BTreeMap};
+use std::convert::TryFrom;
+
+/// A metric that can only be incremented.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::metrics::Counter;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let requests = Counter::define("my_filter.requests")?;
+/// requests.increment(1)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Counter {
+    id: u32,
+}
+
+impl Counter {
+    /// Defines a counter with a given name, see [`hostcalls::define_metric`].
+    ///
+    /// [`hostcalls::define_metric`]: ../hostcalls/fn.define_metric.html
+    pub fn define(name: &str) -> Result<Self> {
+        hostcalls::define_metric(MetricType::Counter, name).map(|id| Counter { id })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Adds a given offset to the current value.
+    ///
+    /// Returns an error without calling into the host if `offset` exceeds `i64::MAX`,
+    /// the largest offset the host accepts.
+    pub fn increment(&self, offset: u64) -> Result<()> {
+        let offset = i64::try_from(offset)
+            .map_err(|_| format!("counter offset {} exceeds i64::MAX", offset))?;
+        hostcalls::increment_metric(self.id, offset)
+    }
+
+    pub fn value(&self) -> Result<u64> {
+        hostcalls::get_metric(self.id)
+    }
+}
+
+/// A metric that can be set to an arbitrary value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Gauge {
+    id: u32,
+}
+
+impl Gauge {
+    /// Defines a gauge with a given name, see [`hostcalls::define_metric`].
+    ///
+    /// [`hostcalls::define_metric`]: ../hostcalls/fn.define_metric.html
+    pub fn define(name: &str) -> Result<Self> {
+        hostcalls::define_metric(MetricType::Gauge, name).map(|id| Gauge { id })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn set(&self, value: u64) -> Result<()> {
+        hostcalls::record_metric(self.id, value)
+    }
+
+    /// Adds a given offset, which can be negative, to the current value.
+    pub fn increment(&self, offset: i64) -> Result<()> {
+        hostcalls::increment_metric(self.id, offset)
+    }
+
+    pub fn value(&self) -> Result<u64> {
+        hostcalls::get_metric(self.id)
+    }
+}
+
+/// A metric that records a distribution of values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Histogram {
+    id: u32,
+}
+
+impl Histogram {
+    /// Defines a histogram with a given name, see [`hostcalls::define_metric`].
+    ///
+    /// [`hostcalls::define_metric`]: ../hostcalls/fn.define_metric.html
+    pub fn define(name: &str) -> Result<Self> {
+        hostcalls::define_metric(MetricType::Histogram, name).map(|id| Histogram { id })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn record(&self, value: u64) -> Result<()> {
+        hostcalls::record_metric(self.id, value)
+    }
+}
+
+/// Caches ids of defined metrics by name, so that defining a metric again, e.g. on every
+/// `on_configure`, returns the id it was first defined with instead of calling into the host.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::metrics::MetricsRegistry;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let mut registry = MetricsRegistry::new();
+/// let requests = registry.counter("my_filter.requests")?;
+/// let in_flight = registry.gauge("my_filter.in_flight")?;
+///
+/// for (name, id, metric_type) in registry.iter() {
+///     proxy_wasm::hostcalls::log(
+///         proxy_wasm::types::LogLevel::Debug,
+///         &format!("{} = {} ({:?})", name, id, metric_type),
+///     )?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MetricsRegistry {
+    metrics: BTreeMap<String, (u32, MetricType)>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        MetricsRegistry::default()
+    }
+
+    /// Returns the id of a metric with a given name, defining it first if needed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the name has already been defined with a different type.
+    pub fn define(&mut self, metric_type: MetricType, name: &str) -> Result<u32> {
+        self.define_with(metric_type, name, hostcalls::define_metric)
+    }
+
+    pub fn counter(&mut self, name: &str) -> Result<Counter> {
+        self.define(MetricType::Counter, name)
+            .map(|id| Counter { id })
+    }
+
+    pub fn gauge(&mut self, name: &str) -> Result<Gauge> {
+        self.define(MetricType::Gauge, name).map(|id| Gauge { id })
+    }
+
+    pub fn histogram(&mut self, name: &str) -> Result<Histogram> {
+        self.define(MetricType::Histogram, name)
+            .map(|id| Histogram { id })
+    }
+
+    /// Returns the id and the type of a metric with a given name, if it has been defined.
+    pub fn get(&self, name: &str) -> Option<(u32, MetricType)> {
+        self.metrics.get(name).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
+    /// Returns an iterator over the name, the id and the type of each defined metric,
+    /// ordered by name.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            inner: self.metrics.iter(),
+        }
+    }
+
+    fn define_with<D>(&mut self, metric_type: MetricType, name: &str, define: D) -> Result<u32>
+    where
+        D: FnOnce(MetricType, &str) -> Result<u32>,
+    {
+        if let Some(&(id, defined_type)) = self.metrics.get(name) {
+            if defined_type != metric_type {
+                return Err(format!(
+                    "metric {:?} is already defined as {:?}, not {:?}",
+                    name, defined_type, metric_type
+                )
+                .into());
+            }
+            return Ok(id);
+        }
+        let id = define(metric_type, name)?;
+        self.metrics.insert(name.to_owned(), (id, metric_type));
+        Ok(id)
+    }
+}
+
+impl<'a> IntoIterator for &'a MetricsRegistry {
+    type Item = (&'a str, u32, MetricType);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An iterator over the metrics of a [`MetricsRegistry`].
+///
+/// [`MetricsRegistry`]: struct.MetricsRegistry.html
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    inner: btree_map::Iter<'a, String, (u32, MetricType)>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a str, u32, MetricType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next()
+            .map(|(name, &(id, metric_type))| (name.as_str(), id, metric_type))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+/// Collects metric updates, e.g. during `on_tick`, and applies them all at once.
+///
+/// ABI 0.2.0 has no batch call, so [`flush`] still makes one host call per update,
+/// but it keeps going past failed updates and reports all of them together.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::metrics::{Gauge, MetricsBatch};
+///
+/// # fn action(gauges: &[(Gauge, u64)]) -> proxy_wasm::error::Result<()> {
+/// let mut batch = MetricsBatch::new();
+/// for (gauge, value) in gauges {
+///     batch.record(gauge.id(), *value);
+/// }
+/// batch.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`flush`]: #method.flush
+#[derive(Debug, Clone, Default)]
+pub struct MetricsBatch {
+    updates: Vec<MetricUpdate>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum MetricUpdate {
+    Record(u32, u64),
+    Increment(u32, i64),
+}
+
+impl MetricsBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stages setting a gauge or recording a histogram value.
+    pub fn record(&mut self, metric_id: u32, value: u64) -> &mut Self {
+        self.updates.push(MetricUpdate::Record(metric_id, value));
+        self
+    }
+
+    /// Stages incrementing a counter or a gauge.
+    pub fn increment(&mut self, metric_id: u32, offset: i64) -> &mut Self {
+        self.updates
+            .push(MetricUpdate::Increment(metric_id, offset));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Applies all staged updates in order and clears the batch.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MetricsBatchError`] listing the failed updates. The other updates
+    /// are still applied.
+    ///
+    /// [`MetricsBatchError`]: ../error/struct.MetricsBatchError.html
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_with(hostcalls::record_metric, hostcalls::increment_metric)
+    }
+
+    fn flush_with<R, I>(&mut self, mut record: R, mut increment: I) -> Result<()>
+    where
+        R: FnMut(u32, u64) -> Result<()>,
+        I: FnMut(u32, i64) -> Result<()>,
+    {
+        let total = self.updates.len();
+        let mut failures = Vec::new();
+        for update in self.updates.drain(..) {
+            let (metric_id, result) = match update {
+                MetricUpdate::Record(metric_id, value) => (metric_id, record(metric_id, value)),
+                MetricUpdate::Increment(metric_id, offset) => {
+                    (metric_id, increment(metric_id, offset))
+                }
+            };
+            if let Err(err) = result {
+                failures.push((metric_id, err));
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(MetricsBatchError::new(total, failures).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_increment_overflow() {
+        let counter = Counter { id: 1 };
+        let err = counter.increment(u64::MAX).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "counter offset 18446744073709551615 exceeds i64::MAX"
+        );
+        let err = counter.increment(1 << 63).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "counter offset 9223372036854775808 exceeds i64::MAX"
+        );
+    }
+
+    #[test]
+    fn test_metrics_registry() {
+        let mut registry = MetricsRegistry::new();
+        let mut next_id = 10;
+        let mut define = |_: MetricType, _: &str| {
+            next_id += 1;
+            Ok(next_id)
+        };
+        assert_eq!(
+            registry
+                .define_with(MetricType::Gauge, "b.gauge", &mut define)
+                .unwrap(),
+            11
+        );
+        assert_eq!(
+            registry
+                .define_with(MetricType::Counter, "a.counter", &mut define)
+                .unwrap(),
+            12
+        );
+        // cached
+        assert_eq!(
+            registry
+                .define_with(MetricType::Gauge, "b.gauge", &mut define)
+                .unwrap(),
+            11
+        );
+        let err = registry
+            .define_with(MetricType::Histogram, "b.gauge", &mut define)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "metric \"b.gauge\" is already defined as Gauge, not Histogram"
+        );
+
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.get("a.counter"), Some((12, MetricType::Counter)));
+        assert_eq!(registry.get("c"), None);
+        assert_eq!(
+            registry.iter().collect::<Vec<_>>(),
+            vec![
+                ("a.counter", 12, MetricType::Counter),
+                ("b.gauge", 11, MetricType::Gauge),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metrics_batch_flush() {
+        let mut batch = MetricsBatch::new();
+        batch.record(1, 10).increment(2, -1).record(3, 30);
+        assert_eq!(batch.len(), 3);
+
+        let mut applied = Vec::new();
+        let err = batch
+            .flush_with(
+                |metric_id, value| {
+                    if metric_id == 1 {
+                        return Err("unknown metric".into());
+                    }
+                    applied.push((metric_id, value as i64));
+                    Ok(())
+                },
+                |metric_id, offset| {
+                    assert_eq!((metric_id, offset), (2, -1));
+                    Ok(())
+                },
+            )
+            .unwrap_err();
+        assert_eq!(applied, vec![(3, 30)]);
+        assert!(batch.is_empty());
+
+        let err = err.downcast_ref::<MetricsBatchError>().unwrap();
+        assert_eq!(err.total(), 3);
+        assert_eq!(err.failures().len(), 1);
+        assert_eq!(err.failures()[0].0, 1);
+        assert_eq!(
+            err.to_string(),
+            "1 of 3 metric updates have failed, first for metric 1: unknown metric"
+        );
+
+        assert!(batch.flush_with(|_, _| Ok(()), |_, _| Ok(())).is_ok());
+    }
+}
@@ -0,0 +1,549 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed accessors for well-known host properties.
+
+use crate::error::Result;
+use crate::hostcalls;
+use crate::types::ByteString;
+use std::time::Duration;
+
+/// Paths of well-known host properties, for use with [`hostcalls::get_property`].
+///
+/// The spellings follow the attributes exposed by Envoy. Not every host supports every path,
+/// and some are only available in certain callbacks, e.g. [`RESPONSE_CODE`] once the response
+/// headers have been received.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::hostcalls;
+/// use proxy_wasm::properties::paths;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let code = hostcalls::get_property(paths::RESPONSE_CODE)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`hostcalls::get_property`]: ../../hostcalls/fn.get_property.html
+/// [`RESPONSE_CODE`]: constant.RESPONSE_CODE.html
+pub mod paths {
+    pub const REQUEST_PATH: &[&str] = &["request", "path"];
+    pub const REQUEST_URL_PATH: &[&str] = &["request", "url_path"];
+    pub const REQUEST_HOST: &[&str] = &["request", "host"];
+    pub const REQUEST_SCHEME: &[&str] = &["request", "scheme"];
+    pub const REQUEST_METHOD: &[&str] = &["request", "method"];
+    pub const REQUEST_REFERER: &[&str] = &["request", "referer"];
+    pub const REQUEST_USERAGENT: &[&str] = &["request", "useragent"];
+    pub const REQUEST_TIME: &[&str] = &["request", "time"];
+    pub const REQUEST_ID: &[&str] = &["request", "id"];
+    pub const REQUEST_PROTOCOL: &[&str] = &["request", "protocol"];
+    pub const REQUEST_DURATION: &[&str] = &["request", "duration"];
+    pub const REQUEST_SIZE: &[&str] = &["request", "size"];
+    pub const REQUEST_TOTAL_SIZE: &[&str] = &["request", "total_size"];
+
+    pub const RESPONSE_CODE: &[&str] = &["response", "code"];
+    pub const RESPONSE_CODE_DETAILS: &[&str] = &["response", "code_details"];
+    pub const RESPONSE_FLAGS: &[&str] = &["response", "flags"];
+    pub const RESPONSE_GRPC_STATUS: &[&str] = &["response", "grpc_status"];
+    pub const RESPONSE_SIZE: &[&str] = &["response", "size"];
+    pub const RESPONSE_TOTAL_SIZE: &[&str] = &["response", "total_size"];
+
+    pub const SOURCE_ADDRESS: &[&str] = &["source", "address"];
+    pub const SOURCE_PORT: &[&str] = &["source", "port"];
+    pub const DESTINATION_ADDRESS: &[&str] = &["destination", "address"];
+    pub const DESTINATION_PORT: &[&str] = &["destination", "port"];
+
+    pub const CONNECTION_ID: &[&str] = &["connection", "id"];
+    pub const CONNECTION_MTLS: &[&str] = &["connection", "mtls"];
+    pub const CONNECTION_REQUESTED_SERVER_NAME: &[&str] = &["connection", "requested_server_name"];
+    pub const CONNECTION_TLS_VERSION: &[&str] = &["connection", "tls_version"];
+    pub const CONNECTION_SUBJECT_LOCAL_CERTIFICATE: &[&str] =
+        &["connection", "subject_local_certificate"];
+    pub const CONNECTION_SUBJECT_PEER_CERTIFICATE: &[&str] =
+        &["connection", "subject_peer_certificate"];
+    pub const CONNECTION_URI_SAN_PEER_CERTIFICATE: &[&str] =
+        &["connection", "uri_san_peer_certificate"];
+    pub const CONNECTION_DNS_SAN_PEER_CERTIFICATE: &[&str] =
+        &["connection", "dns_san_peer_certificate"];
+    pub const CONNECTION_SHA256_PEER_CERTIFICATE_DIGEST: &[&str] =
+        &["connection", "sha256_peer_certificate_digest"];
+    pub const CONNECTION_TERMINATION_DETAILS: &[&str] = &["connection", "termination_details"];
+
+    pub const UPSTREAM_ADDRESS: &[&str] = &["upstream", "address"];
+    pub const UPSTREAM_PORT: &[&str] = &["upstream", "port"];
+    pub const UPSTREAM_TRANSPORT_FAILURE_REASON: &[&str] =
+        &["upstream", "transport_failure_reason"];
+
+    pub const PLUGIN_NAME: &[&str] = &["plugin_name"];
+    pub const PLUGIN_ROOT_ID: &[&str] = &["plugin_root_id"];
+    pub const PLUGIN_VM_ID: &[&str] = &["plugin_vm_id"];
+    pub const CLUSTER_NAME: &[&str] = &["cluster_name"];
+    pub const ROUTE_NAME: &[&str] = &["route_name"];
+    pub const LISTENER_DIRECTION: &[&str] = &["listener_direction"];
+}
+
+/// Represents properties of the current HTTP request.
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    id: Option<ByteString>,
+    protocol: Option<ByteString>,
+    scheme: Option<ByteString>,
+    size: Option<i64>,
+}
+
+impl RequestInfo {
+    /// Returns the request ID, i.e. `request.id`.
+    pub fn id(&self) -> Option<&ByteString> {
+        self.id.as_ref()
+    }
+
+    /// Returns the request protocol, e.g. `HTTP/2`, i.e. `request.protocol`.
+    pub fn protocol(&self) -> Option<&ByteString> {
+        self.protocol.as_ref()
+    }
+
+    /// Returns the request scheme, e.g. `https`, i.e. `request.scheme`.
+    pub fn scheme(&self) -> Option<&ByteString> {
+        self.scheme.as_ref()
+    }
+
+    /// Returns the size of the request body, i.e. `request.size`.
+    pub fn size(&self) -> Option<i64> {
+        self.size
+    }
+}
+
+/// Returns properties of the current HTTP request in one shot.
+///
+/// String properties are returned as raw bytes, `request.size` is decoded
+/// from an 8-byte little-endian integer. Properties unknown to the host are `None`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let info = properties::request_info()?;
+/// if let Some(id) = info.id() {
+///     // correlate logs with the request
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn request_info() -> Result<RequestInfo> {
+    Ok(RequestInfo {
+        id: hostcalls::get_property(paths::REQUEST_ID)?,
+        protocol: hostcalls::get_property(paths::REQUEST_PROTOCOL)?,
+        scheme: hostcalls::get_property(paths::REQUEST_SCHEME)?,
+        size: get_i64(paths::REQUEST_SIZE)?,
+    })
+}
+
+/// Represents final properties of an HTTP stream, as seen from `on_log`.
+#[derive(Debug, Clone, Default)]
+pub struct LogInfo {
+    response_code: Option<i64>,
+    response_total_size: Option<i64>,
+    request_total_size: Option<i64>,
+    duration: Option<Duration>,
+}
+
+impl LogInfo {
+    /// Returns the response status code, i.e. `response.code`.
+    pub fn response_code(&self) -> Option<i64> {
+        self.response_code
+    }
+
+    /// Returns the size of the response including headers, i.e. `response.total_size`.
+    pub fn response_total_size(&self) -> Option<i64> {
+        self.response_total_size
+    }
+
+    /// Returns the size of the request including headers, i.e. `request.total_size`.
+    pub fn request_total_size(&self) -> Option<i64> {
+        self.request_total_size
+    }
+
+    /// Returns the total duration of the request, i.e. `request.duration`.
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+}
+
+/// Returns final properties of the current HTTP stream in one shot.
+///
+/// Meant to be called from `on_log`, once the response has been sent.
+/// Properties unknown to the host are `None`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let info = properties::log_info()?;
+/// if let (Some(code), Some(duration)) = (info.response_code(), info.duration()) {
+///     // write an access log entry
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn log_info() -> Result<LogInfo> {
+    Ok(LogInfo {
+        response_code: get_i64(paths::RESPONSE_CODE)?,
+        response_total_size: get_i64(paths::RESPONSE_TOTAL_SIZE)?,
+        request_total_size: get_i64(paths::REQUEST_TOTAL_SIZE)?,
+        duration: get_i64(paths::REQUEST_DURATION)?
+            .map(|nanos| Duration::from_nanos(nanos.max(0) as u64)),
+    })
+}
+
+/// Represents TLS properties of the downstream connection.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    version: ByteString,
+    peer_subject: Option<ByteString>,
+    peer_uri_sans: Vec<ByteString>,
+    peer_dns_sans: Vec<ByteString>,
+    peer_certificate_digest: Option<ByteString>,
+}
+
+impl TlsInfo {
+    /// Returns the TLS version, e.g. `TLSv1.3`, i.e. `connection.tls_version`.
+    pub fn version(&self) -> &ByteString {
+        &self.version
+    }
+
+    /// Returns the subject of the peer certificate, i.e. `connection.subject_peer_certificate`.
+    ///
+    /// `None` unless the peer has presented a certificate, i.e. the connection is mTLS.
+    pub fn peer_subject(&self) -> Option<&ByteString> {
+        self.peer_subject.as_ref()
+    }
+
+    /// Returns URI SANs of the peer certificate, i.e. `connection.uri_san_peer_certificate`.
+    pub fn peer_uri_sans(&self) -> &[ByteString] {
+        &self.peer_uri_sans
+    }
+
+    /// Returns DNS SANs of the peer certificate, i.e. `connection.dns_san_peer_certificate`.
+    pub fn peer_dns_sans(&self) -> &[ByteString] {
+        &self.peer_dns_sans
+    }
+
+    /// Returns the hex-encoded SHA-256 digest of the peer certificate,
+    /// i.e. `connection.sha256_peer_certificate_digest`.
+    pub fn peer_certificate_digest(&self) -> Option<&ByteString> {
+        self.peer_certificate_digest.as_ref()
+    }
+}
+
+/// Returns TLS properties of the downstream connection in one shot,
+/// or `None` if the connection is plaintext.
+///
+/// SAN properties hold comma-separated lists, which are split into individual SANs.
+/// Properties unknown to the host, or empty, are `None` or empty lists.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// if let Some(tls) = properties::tls_info()? {
+///     for san in tls.peer_uri_sans() {
+///         // authorize the SPIFFE id of the peer
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn tls_info() -> Result<Option<TlsInfo>> {
+    let version = match get_non_empty(paths::CONNECTION_TLS_VERSION)? {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    Ok(Some(TlsInfo {
+        version,
+        peer_subject: get_non_empty(paths::CONNECTION_SUBJECT_PEER_CERTIFICATE)?,
+        peer_uri_sans: split_list(get_non_empty(paths::CONNECTION_URI_SAN_PEER_CERTIFICATE)?),
+        peer_dns_sans: split_list(get_non_empty(paths::CONNECTION_DNS_SAN_PEER_CERTIFICATE)?),
+        peer_certificate_digest: get_non_empty(paths::CONNECTION_SHA256_PEER_CERTIFICATE_DIGEST)?,
+    }))
+}
+
+/// Returns why the downstream connection was terminated, if known,
+/// i.e. `connection.termination_details`.
+pub fn connection_termination_details() -> Result<Option<ByteString>> {
+    hostcalls::get_property(paths::CONNECTION_TERMINATION_DETAILS)
+}
+
+/// Returns why the connection to the upstream failed, if it did,
+/// i.e. `upstream.transport_failure_reason`.
+pub fn upstream_transport_failure_reason() -> Result<Option<ByteString>> {
+    hostcalls::get_property(paths::UPSTREAM_TRANSPORT_FAILURE_REASON)
+}
+
+/// Returns the name of the plugin that is running, i.e. `plugin_name`.
+///
+/// Useful to tell apart log messages of several plugins that share a VM.
+/// Invalid UTF-8 is replaced with `U+FFFD`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let plugin = properties::plugin_name()?.unwrap_or_default();
+/// log::info!("[{}] configured", plugin);
+/// # Ok(())
+/// # }
+/// ```
+pub fn plugin_name() -> Result<Option<String>> {
+    get_string(paths::PLUGIN_NAME)
+}
+
+/// Returns the root id of the plugin that is running, i.e. `plugin_root_id`.
+///
+/// Invalid UTF-8 is replaced with `U+FFFD`.
+pub fn plugin_root_id() -> Result<Option<String>> {
+    get_string(paths::PLUGIN_ROOT_ID)
+}
+
+/// Returns the value of a given property decoded as a protobuf message,
+/// e.g. filter state set by another filter.
+///
+/// Returns an error if the property value is not a valid encoding of `M`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// fn filter_state<M>() -> proxy_wasm::error::Result<Option<M>>
+/// where
+///     M: prost::Message + Default,
+/// {
+///     properties::get_property_message(&["filter_state", "my.filter.state"])
+/// }
+/// ```
+#[cfg(feature = "prost")]
+pub fn get_property_message<M>(path: &[&str]) -> Result<Option<M>>
+where
+    M: prost::Message + Default,
+{
+    match hostcalls::get_property(path)? {
+        Some(value) => Ok(Some(M::decode(value.as_bytes())?)),
+        None => Ok(None),
+    }
+}
+
+fn get_non_empty(path: &[&str]) -> Result<Option<ByteString>> {
+    Ok(hostcalls::get_property(path)?.filter(|value| !value.is_empty()))
+}
+
+fn split_list(value: Option<ByteString>) -> Vec<ByteString> {
+    value.map_or_else(Vec::new, |value| {
+        value
+            .split(|&b| b == b',')
+            .map(|item| item.trim_ascii())
+            .filter(|item| !item.is_empty())
+            .map(ByteString::from)
+            .collect()
+    })
+}
+
+fn get_string(path: &[&str]) -> Result<Option<String>> {
+    Ok(hostcalls::get_property(path)?.map(|value| value.to_string_lossy()))
+}
+
+fn get_i64(path: &[&str]) -> Result<Option<i64>> {
+    match hostcalls::get_property(path)? {
+        Some(value) => value.to_i64_le().map(Some).ok_or_else(|| {
+            format!(
+                "property \"{}\" of {} bytes is not an 8-byte integer",
+                path.join("."),
+                value.len()
+            )
+            .into()
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Scalar value of a metadata key, decoded from the encoding the host uses for it.
+///
+/// Envoy returns strings as raw UTF-8 bytes, numbers as 8-byte little-endian `f64`
+/// and booleans as a single byte, `0` or `1`. The encoding does not record which of these
+/// a value is, so the caller picks the type to decode it as. [`ByteString`] keeps
+/// the value as is.
+///
+/// [`ByteString`]: ../types/struct.ByteString.html
+pub trait MetadataValue: Sized {
+    /// Decodes a value returned by the host, or returns an error if it is not
+    /// a valid encoding of `Self`.
+    fn decode(value: ByteString) -> Result<Self>;
+}
+
+impl MetadataValue for ByteString {
+    fn decode(value: ByteString) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl MetadataValue for String {
+    fn decode(value: ByteString) -> Result<Self> {
+        value
+            .into_string()
+            .map_err(|_| "metadata value is not valid UTF-8".into())
+    }
+}
+
+impl MetadataValue for f64 {
+    fn decode(value: ByteString) -> Result<Self> {
+        match value.to_u64_le() {
+            Some(bits) => Ok(f64::from_bits(bits)),
+            None => Err(format!(
+                "metadata value of {} bytes is not an 8-byte number",
+                value.len()
+            )
+            .into()),
+        }
+    }
+}
+
+impl MetadataValue for bool {
+    fn decode(value: ByteString) -> Result<Self> {
+        match value.as_bytes() {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(format!("metadata value of {} bytes is not a boolean", value.len()).into()),
+        }
+    }
+}
+
+fn get_metadata<T>(path: &[&str]) -> Result<Option<T>>
+where
+    T: MetadataValue,
+{
+    hostcalls::get_property(path)?.map(T::decode).transpose()
+}
+
+/// Returns the value of a given key in the node metadata, i.e. `node.metadata.<key>`,
+/// decoded as `T`, see [`MetadataValue`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// if let Some(version) = properties::node_metadata::<String>("version")? {
+///     // route based on the deployment label
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`MetadataValue`]: trait.MetadataValue.html
+pub fn node_metadata<T>(key: &str) -> Result<Option<T>>
+where
+    T: MetadataValue,
+{
+    get_metadata(&["node", "metadata", key])
+}
+
+/// Returns the value of a given key in the dynamic metadata of a given filter,
+/// i.e. `metadata.filter_metadata.<filter>.<key>`, decoded as `T`, see [`MetadataValue`].
+///
+/// [`MetadataValue`]: trait.MetadataValue.html
+pub fn filter_metadata<T>(filter: &str, key: &str) -> Result<Option<T>>
+where
+    T: MetadataValue,
+{
+    get_metadata(&["metadata", "filter_metadata", filter, key])
+}
+
+/// Returns the value of a given key in the route metadata of a given filter,
+/// i.e. `route_metadata.filter_metadata.<filter>.<key>`, decoded as `T`, see [`MetadataValue`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::properties;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// if let Some(limit) = properties::route_filter_metadata::<f64>("my_filter", "rate_limit")? {
+///     // override the default rate limit for this route
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`MetadataValue`]: trait.MetadataValue.html
+pub fn route_filter_metadata<T>(filter: &str, key: &str) -> Result<Option<T>>
+where
+    T: MetadataValue,
+{
+    get_metadata(&["route_metadata", "filter_metadata", filter, key])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_list() {
+        assert!(split_list(None).is_empty());
+        let sans = split_list(Some("spiffe://a/ns/x, spiffe://a/ns/y,,".into()));
+        assert_eq!(sans, vec!["spiffe://a/ns/x", "spiffe://a/ns/y"]);
+    }
+
+    #[test]
+    fn test_metadata_value() {
+        assert_eq!(String::decode("canary".into()).unwrap(), "canary");
+        assert!(String::decode(vec![0xff].into()).is_err());
+
+        assert_eq!(
+            f64::decode(0.5f64.to_le_bytes().to_vec().into()).unwrap(),
+            0.5
+        );
+        assert_eq!(
+            f64::decode("1".into()).unwrap_err().to_string(),
+            "metadata value of 1 bytes is not an 8-byte number"
+        );
+
+        assert!(bool::decode(vec![1].into()).unwrap());
+        assert!(!bool::decode(vec![0].into()).unwrap());
+        assert_eq!(
+            bool::decode("true".into()).unwrap_err().to_string(),
+            "metadata value of 4 bytes is not a boolean"
+        );
+
+        let raw: ByteString = vec![0xff, 0].into();
+        assert_eq!(ByteString::decode(raw.clone()).unwrap(), raw);
+    }
+}
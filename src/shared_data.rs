@@ -0,0 +1,582 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for working with shared data.
+
+use crate::error::{HostCallError, Result};
+use crate::hostcalls;
+use crate::types::{ByteString, Status};
+use std::convert::TryInto;
+use std::time::Duration;
+
+const MAX_CAS_ATTEMPTS: usize = 16;
+
+/// Tokens are kept in thousandths, so that partial refills are not lost between requests.
+const TOKEN_SCALE: u64 = 1000;
+
+/// Keeps track of a set of shared data keys under a well-known index key.
+///
+/// ABI 0.2.0 has no way to enumerate shared data keys, so this type relies on
+/// a convention instead: keys written through the index are also recorded,
+/// newline-separated, in the value of the index key itself. The index key is created by
+/// [`init`], and from then on only updated with compare-and-swap, so it stays consistent
+/// across VMs that use the same index.
+///
+/// Keys written directly via [`hostcalls::set_shared_data`] are not recorded.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::shared_data::SharedDataIndex;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// let tenants = SharedDataIndex::new("tenants.index");
+/// tenants.init()?;
+/// tenants.set("tenants.acme", b"{\"quota\":100}")?;
+///
+/// for key in tenants.keys()? {
+///     tenants.remove(&key)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`init`]: #method.init
+/// [`hostcalls::set_shared_data`]: ../hostcalls/fn.set_shared_data.html
+#[derive(Debug, Clone)]
+pub struct SharedDataIndex {
+    index_key: String,
+}
+
+impl SharedDataIndex {
+    pub fn new<K: Into<String>>(index_key: K) -> Self {
+        SharedDataIndex {
+            index_key: index_key.into(),
+        }
+    }
+
+    pub fn index_key(&self) -> &str {
+        &self.index_key
+    }
+
+    /// Creates the index key if it does not exist yet.
+    ///
+    /// Call it from `on_vm_start` of every VM that uses the index, before any of them records
+    /// keys. ABI 0.2.0 cannot create a key conditionally, so this is the only write to the
+    /// index that is not a compare-and-swap. [`set`] and [`remove`] return an error while
+    /// the index key does not exist.
+    ///
+    /// [`set`]: #method.set
+    /// [`remove`]: #method.remove
+    pub fn init(&self) -> Result<()> {
+        let (index, cas) = hostcalls::get_shared_data(&self.index_key)?;
+        if index.is_some() || cas.is_some() {
+            return Ok(());
+        }
+        hostcalls::set_shared_data(&self.index_key, Some(b""), None)
+    }
+
+    /// Returns all keys recorded in the index.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let (index, _) = hostcalls::get_shared_data(&self.index_key)?;
+        Ok(index.as_ref().map(decode_index).unwrap_or_default())
+    }
+
+    /// Sets the value of a given key and records the key in the index.
+    ///
+    /// Keys must not contain a newline.
+    pub fn set<V: AsRef<[u8]>>(&self, key: &str, value: V) -> Result<()> {
+        if key.contains('\n') {
+            return Err(format!("shared data key {:?} contains a newline", key).into());
+        }
+        hostcalls::set_shared_data(key, Some(value), None)?;
+        self.update(|keys| {
+            if keys.iter().any(|k| k == key) {
+                return false;
+            }
+            keys.push(key.to_owned());
+            true
+        })
+    }
+
+    /// Clears the value of a given key and removes the key from the index.
+    ///
+    /// ABI 0.2.0 has no way to delete shared data, so the value is set to empty.
+    pub fn remove(&self, key: &str) -> Result<()> {
+        hostcalls::set_shared_data(key, Some(b""), None)?;
+        self.update(|keys| {
+            let len = keys.len();
+            keys.retain(|k| k != key);
+            keys.len() != len
+        })
+    }
+
+    fn update<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut Vec<String>) -> bool,
+    {
+        let mut attempt = 0;
+        loop {
+            let (index, cas) = hostcalls::get_shared_data(&self.index_key)?;
+            let mut keys = index.as_ref().map(decode_index).unwrap_or_default();
+            if !f(&mut keys) {
+                return Ok(());
+            }
+            // without a CAS value, the write could overwrite keys recorded by another VM
+            if cas.is_none() {
+                return Err(format!(
+                    "shared data index {:?} does not exist, call init first",
+                    self.index_key
+                )
+                .into());
+            }
+            attempt += 1;
+            match hostcalls::set_shared_data(&self.index_key, Some(encode_index(&keys)), cas) {
+                Err(err) if attempt < MAX_CAS_ATTEMPTS && is_cas_mismatch(&*err) => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A token bucket rate limiter that keeps its state in shared data, so that
+/// the limit holds across all VMs on the host.
+///
+/// Each key gets its own bucket under `key_prefix` followed by the key, e.g. a client
+/// address. A bucket starts full with `burst` tokens and refills at `rate` tokens per
+/// `window`, up to `burst`. Each allowed request takes one token.
+///
+/// Buckets are updated with compare-and-swap against the time reported by the host.
+/// ABI 0.2.0 cannot create a key conditionally, so a new bucket is read back after it has
+/// been written, and the token is taken again if another VM has replaced it meanwhile.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::shared_data::RateLimiter;
+/// use std::time::Duration;
+///
+/// # fn action(client: &str) -> proxy_wasm::error::Result<()> {
+/// // 10 requests per second, with bursts of up to 20
+/// let limiter = RateLimiter::new("ratelimit.", 10, Duration::from_secs(1), 20);
+/// if !limiter.allow(client)? {
+///     // respond with 429
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    key_prefix: String,
+    rate: u32,
+    window: Duration,
+    burst: u32,
+}
+
+impl RateLimiter {
+    pub fn new<K: Into<String>>(key_prefix: K, rate: u32, window: Duration, burst: u32) -> Self {
+        RateLimiter {
+            key_prefix: key_prefix.into(),
+            rate,
+            window,
+            burst,
+        }
+    }
+
+    pub fn key_prefix(&self) -> &str {
+        &self.key_prefix
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Takes a token from the bucket of a given key.
+    ///
+    /// Returns `true` if the request is allowed and `false` if the bucket is empty.
+    pub fn allow(&self, key: &str) -> Result<bool> {
+        let shared_key = format!("{}{}", self.key_prefix, key);
+        let mut attempt = 0;
+        loop {
+            let now = hostcalls::get_current_time_nanos()?;
+            let (data, cas) = hostcalls::get_shared_data(&shared_key)?;
+            let bucket = data.as_ref().and_then(|data| Bucket::decode(data));
+            let bucket = match self.take(bucket, now) {
+                Some(bucket) => bucket,
+                None => return Ok(false),
+            };
+            attempt += 1;
+            let encoded = bucket.encode();
+            match hostcalls::set_shared_data(&shared_key, Some(encoded), cas) {
+                Ok(()) if cas.is_none() && attempt < MAX_CAS_ATTEMPTS => {
+                    let (data, _) = hostcalls::get_shared_data(&shared_key)?;
+                    if data.as_ref().map(|data| data.as_bytes()) == Some(&encoded[..]) {
+                        return Ok(true);
+                    }
+                }
+                Err(err) if attempt < MAX_CAS_ATTEMPTS && is_cas_mismatch(&*err) => continue,
+                result => return result.map(|_| true),
+            }
+        }
+    }
+
+    /// Refills a given bucket up to `now` and takes a token from it.
+    ///
+    /// Returns `None` if there is no token to take.
+    fn take(&self, bucket: Option<Bucket>, now: u64) -> Option<Bucket> {
+        let capacity = u64::from(self.burst) * TOKEN_SCALE;
+        let mut bucket = bucket.unwrap_or(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        // the host clock is not monotonic, so a clock going backwards refills nothing
+        let elapsed = now.saturating_sub(bucket.updated);
+        let window = self.window.as_nanos().max(1);
+        let per_window = u128::from(self.rate) * u128::from(TOKEN_SCALE);
+        let refill = u128::from(elapsed) * per_window / window;
+        let tokens = u128::from(bucket.tokens) + refill;
+        if tokens >= u128::from(capacity) {
+            bucket.tokens = capacity;
+            bucket.updated = bucket.updated.max(now);
+        } else if refill > 0 {
+            bucket.tokens = tokens as u64;
+            // only advance by the time that was converted into tokens, so that
+            // the remainder still counts towards the next refill
+            let converted = refill * window / per_window;
+            bucket.updated += converted.try_into().unwrap_or(elapsed);
+        }
+        bucket.tokens = bucket.tokens.checked_sub(TOKEN_SCALE)?;
+        Some(bucket)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bucket {
+    tokens: u64,
+    updated: u64,
+}
+
+impl Bucket {
+    fn decode(data: &[u8]) -> Option<Bucket> {
+        if data.len() != 16 {
+            return None;
+        }
+        Some(Bucket {
+            tokens: u64::from_le_bytes(data[..8].try_into().unwrap()),
+            updated: u64::from_le_bytes(data[8..].try_into().unwrap()),
+        })
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut data = [0; 16];
+        data[..8].copy_from_slice(&self.tokens.to_le_bytes());
+        data[8..].copy_from_slice(&self.updated.to_le_bytes());
+        data
+    }
+}
+
+fn is_cas_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<HostCallError>()
+        .is_some_and(|err| err.status() == Status::CasMismatch)
+}
+
+fn decode_index(index: &ByteString) -> Vec<String> {
+    index
+        .split(|&b| b == b'\n')
+        .filter(|key| !key.is_empty())
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .collect()
+}
+
+fn encode_index(keys: &[String]) -> String {
+    keys.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RawStatus;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, VecDeque};
+
+    type Hook = Box<dyn FnOnce()>;
+
+    thread_local! {
+        static SHARED_DATA: RefCell<HashMap<String, (Vec<u8>, u32)>> = RefCell::new(HashMap::new());
+        static NEXT_CAS: Cell<u32> = const { Cell::new(0) };
+        static NOW: Cell<u64> = const { Cell::new(0) };
+        // run one by one before each shared data call, to simulate other VMs
+        static HOOKS: RefCell<VecDeque<Option<Hook>>> = RefCell::new(VecDeque::new());
+    }
+
+    /// Writes shared data the way another VM would, bypassing the hooks.
+    fn store(key: &str, value: &[u8]) {
+        NEXT_CAS.with(|next| next.set(next.get() + 1));
+        let cas = NEXT_CAS.with(Cell::get);
+        SHARED_DATA.with(|data| {
+            data.borrow_mut()
+                .insert(key.to_owned(), (value.to_vec(), cas))
+        });
+    }
+
+    fn run_hook() {
+        if let Some(Some(hook)) = HOOKS.with(|hooks| hooks.borrow_mut().pop_front()) {
+            hook();
+        }
+    }
+
+    fn hooks(hooks: Vec<Option<Hook>>) {
+        HOOKS.with(|queue| queue.borrow_mut().extend(hooks));
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_get_shared_data(
+        key_data: *const u8,
+        key_size: usize,
+        return_value_data: *mut *mut u8,
+        return_value_size: *mut usize,
+        return_cas: *mut u32,
+    ) -> RawStatus {
+        run_hook();
+        let key = unsafe { std::slice::from_raw_parts(key_data, key_size) };
+        let key = String::from_utf8_lossy(key);
+        match SHARED_DATA.with(|data| data.borrow().get(&*key).cloned()) {
+            Some((value, cas)) => unsafe {
+                *return_value_data = crate::allocator::proxy_on_memory_allocate(value.len());
+                std::ptr::copy_nonoverlapping(value.as_ptr(), *return_value_data, value.len());
+                *return_value_size = value.len();
+                *return_cas = cas;
+                Status::Ok.into()
+            },
+            None => Status::NotFound.into(),
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_set_shared_data(
+        key_data: *const u8,
+        key_size: usize,
+        value_data: *const u8,
+        value_size: usize,
+        cas: u32,
+    ) -> RawStatus {
+        run_hook();
+        let key = unsafe { std::slice::from_raw_parts(key_data, key_size) };
+        let key = String::from_utf8_lossy(key);
+        let value = if value_data.is_null() {
+            &[][..]
+        } else {
+            unsafe { std::slice::from_raw_parts(value_data, value_size) }
+        };
+        // like Envoy, a CAS value is only checked against an existing key
+        let current = SHARED_DATA.with(|data| data.borrow().get(&*key).map(|(_, cas)| *cas));
+        if matches!(current, Some(current) if cas != 0 && cas != current) {
+            return Status::CasMismatch.into();
+        }
+        store(&key, value);
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> RawStatus {
+        unsafe { *return_time = NOW.with(Cell::get) };
+        Status::Ok.into()
+    }
+
+    fn shared_data(key: &str) -> Option<Vec<u8>> {
+        SHARED_DATA.with(|data| data.borrow().get(key).map(|(value, _)| value.clone()))
+    }
+
+    #[test]
+    fn test_index_requires_init() {
+        let index = SharedDataIndex::new("index");
+        assert_eq!(
+            index.set("a", b"1").unwrap_err().to_string(),
+            "shared data index \"index\" does not exist, call init first"
+        );
+        // nothing to remove, so nothing to write either
+        index.remove("a").unwrap();
+
+        index.init().unwrap();
+        index.set("a", b"1").unwrap();
+        // a later init must not reset the index
+        index.init().unwrap();
+        assert_eq!(index.keys().unwrap(), vec!["a".to_owned()]);
+        assert_eq!(shared_data("a"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_index_concurrent_set() {
+        let index = SharedDataIndex::new("index");
+        index.init().unwrap();
+
+        // another VM records its key between our read and our write of the index
+        hooks(vec![None, None, Some(Box::new(|| store("index", b"b")))]);
+        index.set("a", b"1").unwrap();
+        assert_eq!(index.keys().unwrap(), vec!["b".to_owned(), "a".to_owned()]);
+
+        index.remove("b").unwrap();
+        assert_eq!(index.keys().unwrap(), vec!["a".to_owned()]);
+        assert_eq!(shared_data("b"), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_index_roundtrip() {
+        let keys = vec!["a".to_owned(), "tenant.b".to_owned()];
+        let encoded = encode_index(&keys);
+        assert_eq!(encoded, "a\ntenant.b");
+        assert_eq!(decode_index(&encoded.into()), keys);
+    }
+
+    #[test]
+    fn test_rate_limiter_take() {
+        const SECOND: u64 = 1_000_000_000;
+        let limiter = RateLimiter::new("rl.", 2, Duration::from_secs(1), 3);
+
+        // a new bucket starts full
+        let mut bucket = None;
+        for _ in 0..3 {
+            bucket = Some(limiter.take(bucket, 10 * SECOND).unwrap());
+        }
+        assert_eq!(limiter.take(bucket, 10 * SECOND), None);
+
+        // half a second refills one token
+        let half = limiter.take(bucket, 10 * SECOND + SECOND / 2).unwrap();
+        assert_eq!(half.tokens, 0);
+        assert_eq!(limiter.take(Some(half), 10 * SECOND + SECOND / 2), None);
+
+        // refills are capped at the burst
+        let later = limiter.take(bucket, 100 * SECOND).unwrap();
+        assert_eq!(later.tokens, 2 * TOKEN_SCALE);
+
+        // a clock going backwards refills nothing
+        assert_eq!(limiter.take(bucket, 5 * SECOND), None);
+    }
+
+    #[test]
+    fn test_rate_limiter_partial_refill() {
+        const SECOND: u64 = 1_000_000_000;
+        let limiter = RateLimiter::new("rl.", 1, Duration::from_secs(3), 1);
+        let bucket = limiter.take(None, 0);
+        assert_eq!(limiter.take(bucket, SECOND), None);
+        assert_eq!(limiter.take(bucket, 2 * SECOND), None);
+        assert!(limiter.take(bucket, 3 * SECOND).is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_keeps_refill_remainder() {
+        const SECOND: u64 = 1_000_000_000;
+        // half a thousandth of a token per second
+        let limiter = RateLimiter::new("rl.", 1, Duration::from_secs(2000), 10);
+        let bucket = limiter.take(None, 0).unwrap();
+        let bucket = limiter.take(Some(bucket), SECOND).unwrap();
+        assert_eq!(
+            bucket,
+            Bucket {
+                tokens: 8000,
+                updated: 0
+            }
+        );
+        let bucket = limiter.take(Some(bucket), 2 * SECOND).unwrap();
+        assert_eq!(
+            bucket,
+            Bucket {
+                tokens: 7001,
+                updated: 2 * SECOND
+            }
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_allow() {
+        let limiter = RateLimiter::new("rl.", 1, Duration::from_secs(1), 2);
+        NOW.with(|now| now.set(1_000));
+        assert!(limiter.allow("client").unwrap());
+
+        // another VM takes the last token between our read and our write
+        hooks(vec![
+            None,
+            Some(Box::new(|| {
+                let taken = Bucket {
+                    tokens: 0,
+                    updated: 1_000,
+                };
+                store("rl.client", &taken.encode())
+            })),
+        ]);
+        assert!(!limiter.allow("client").unwrap());
+        assert_eq!(
+            Bucket::decode(&shared_data("rl.client").unwrap()),
+            Some(Bucket {
+                tokens: 0,
+                updated: 1_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_allow_new_bucket_replaced() {
+        let limiter = RateLimiter::new("rl.", 1, Duration::from_secs(1), 2);
+        NOW.with(|now| now.set(1_000));
+
+        // another VM creates the same bucket right after we do
+        hooks(vec![
+            None,
+            None,
+            Some(Box::new(|| {
+                let other = Bucket {
+                    tokens: TOKEN_SCALE,
+                    updated: 900,
+                };
+                store("rl.client", &other.encode())
+            })),
+        ]);
+        assert!(limiter.allow("client").unwrap());
+        // the token is taken from the bucket of the other VM instead
+        assert_eq!(
+            Bucket::decode(&shared_data("rl.client").unwrap()),
+            Some(Bucket {
+                tokens: 0,
+                updated: 900
+            })
+        );
+        assert!(!limiter.allow("client").unwrap());
+    }
+
+    #[test]
+    fn test_bucket_roundtrip() {
+        let bucket = Bucket {
+            tokens: 1500,
+            updated: 42,
+        };
+        assert_eq!(Bucket::decode(&bucket.encode()), Some(bucket));
+        assert_eq!(Bucket::decode(b"short"), None);
+    }
+
+    #[test]
+    fn test_decode_index_empty() {
+        assert!(decode_index(&ByteString::new()).is_empty());
+        assert_eq!(decode_index(&"\na\n\n".into()), vec!["a".to_owned()]);
+    }
+}
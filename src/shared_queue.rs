@@ -0,0 +1,199 @@
+// Copyright 2020 Tetrate
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A typed handle for shared queues.
+
+use crate::error::Result;
+use crate::hostcalls;
+use crate::types::ByteString;
+
+/// A shared queue registered by this VM or resolved from another one.
+///
+/// Wraps the id returned by the host, so it is `Copy`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::shared_queue::SharedQueue;
+///
+/// # fn action() -> proxy_wasm::error::Result<()> {
+/// if let Some(queue) = SharedQueue::resolve("my_vm", "events")? {
+///     queue.enqueue("user logged in")?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SharedQueue {
+    id: u32,
+}
+
+impl SharedQueue {
+    /// Registers a shared queue with a given name, see [`hostcalls::register_shared_queue`].
+    ///
+    /// [`hostcalls::register_shared_queue`]: ../hostcalls/fn.register_shared_queue.html
+    pub fn register(name: &str) -> Result<Self> {
+        hostcalls::register_shared_queue(name).map(|id| SharedQueue { id })
+    }
+
+    /// Looks up a shared queue registered by a given VM, returning `None` if it does not exist.
+    pub fn resolve(vm_id: &str, name: &str) -> Result<Option<Self>> {
+        hostcalls::resolve_shared_queue(vm_id, name).map(|id| id.map(|id| SharedQueue { id }))
+    }
+
+    /// Wraps an id received in `on_queue_ready`.
+    pub fn from_id(id: u32) -> Self {
+        SharedQueue { id }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn enqueue<V: AsRef<[u8]>>(&self, value: V) -> Result<()> {
+        hostcalls::enqueue_shared_queue(self.id, Some(value))
+    }
+
+    /// Returns the next item, or `None` if the queue is empty.
+    pub fn dequeue(&self) -> Result<Option<ByteString>> {
+        hostcalls::dequeue_shared_queue(self.id)
+    }
+
+    /// Enqueues a value encoded as compact JSON, i.e. via `serde_json::to_vec`.
+    ///
+    /// Consumers in other VMs, or written in other languages, can decode items as JSON,
+    /// or use [`dequeue_value`] from this crate.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::shared_queue::SharedQueue;
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Event {
+    ///     user: String,
+    ///     action: String,
+    /// }
+    ///
+    /// # fn action(queue: SharedQueue) -> proxy_wasm::error::Result<()> {
+    /// queue.enqueue_value(&Event {
+    ///     user: "alice".to_owned(),
+    ///     action: "login".to_owned(),
+    /// })?;
+    ///
+    /// // in `on_queue_ready` of the consumer
+    /// while let Some(event) = queue.dequeue_value::<Event>()? {
+    ///     // handle the event
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`dequeue_value`]: #method.dequeue_value
+    #[cfg(feature = "serde_json")]
+    pub fn enqueue_value<T>(&self, value: &T) -> Result<()>
+    where
+        T: serde::Serialize + ?Sized,
+    {
+        self.enqueue(serde_json::to_vec(value)?)
+    }
+
+    /// Dequeues the next item and decodes it from JSON, see [`enqueue_value`].
+    ///
+    /// Returns `None` if the queue is empty. An item that fails to decode is consumed
+    /// nevertheless, so that it doesn't block the items behind it.
+    ///
+    /// [`enqueue_value`]: #method.enqueue_value
+    #[cfg(feature = "serde_json")]
+    pub fn dequeue_value<T>(&self) -> Result<Option<T>>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        match self.dequeue()? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "serde_json")]
+mod tests {
+    use super::*;
+    use crate::types::{RawStatus, Status};
+    use serde::{Deserialize, Serialize};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    thread_local! {
+        static QUEUE: RefCell<VecDeque<Vec<u8>>> = const { RefCell::new(VecDeque::new()) };
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_enqueue_shared_queue(
+        _queue_id: u32,
+        value_data: *const u8,
+        value_size: usize,
+    ) -> RawStatus {
+        let value = unsafe { std::slice::from_raw_parts(value_data, value_size) };
+        QUEUE.with(|queue| queue.borrow_mut().push_back(value.to_vec()));
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_dequeue_shared_queue(
+        _queue_id: u32,
+        return_value_data: *mut *mut u8,
+        return_value_size: *mut usize,
+    ) -> RawStatus {
+        match QUEUE.with(|queue| queue.borrow_mut().pop_front()) {
+            Some(value) => {
+                unsafe {
+                    *return_value_data = crate::allocator::proxy_on_memory_allocate(value.len());
+                    std::ptr::copy_nonoverlapping(value.as_ptr(), *return_value_data, value.len());
+                    *return_value_size = value.len();
+                }
+                Status::Ok.into()
+            }
+            None => Status::Empty.into(),
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        user: String,
+        count: u32,
+    }
+
+    #[test]
+    fn test_enqueue_dequeue_value() {
+        let queue = SharedQueue::from_id(1);
+        let event = Event {
+            user: "alice".to_owned(),
+            count: 2,
+        };
+        queue.enqueue_value(&event).unwrap();
+        queue.enqueue("not json").unwrap();
+        queue.enqueue_value(&event).unwrap();
+
+        QUEUE.with(|items| assert_eq!(items.borrow()[0], br#"{"user":"alice","count":2}"#));
+        assert_eq!(queue.dequeue_value::<Event>().unwrap(), Some(event));
+        assert!(queue.dequeue_value::<Event>().is_err());
+        assert!(queue.dequeue_value::<Event>().unwrap().is_some());
+        assert_eq!(queue.dequeue_value::<Event>().unwrap(), None);
+    }
+}
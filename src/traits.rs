@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::body;
+use crate::dispatcher;
 use crate::hostcalls;
+use crate::http::{self, PseudoHeader};
 use crate::types::*;
 use std::time::{Duration, SystemTime};
 
@@ -79,14 +82,28 @@ pub trait Context {
         hostcalls::get_map(MapType::HttpCallResponseHeaders).unwrap()
     }
 
+    fn get_http_call_response_header(&self, name: &str) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpCallResponseHeaders, name).unwrap()
+    }
+
     fn get_http_call_response_body(&self, start: usize, max_size: usize) -> Option<ByteString> {
         hostcalls::get_buffer(BufferType::HttpCallResponseBody, start, max_size).unwrap()
     }
 
+    /// Returns trailers of the HTTP call response, e.g. `grpc-status`.
+    ///
+    /// Only valid inside `on_http_call_response`.
     fn get_http_call_response_trailers(&self) -> Vec<(ByteString, ByteString)> {
         hostcalls::get_map(MapType::HttpCallResponseTrailers).unwrap()
     }
 
+    /// Returns a single trailer of the HTTP call response.
+    ///
+    /// Only valid inside `on_http_call_response`.
+    fn get_http_call_response_trailer(&self, name: &str) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpCallResponseTrailers, name).unwrap()
+    }
+
     fn on_done(&mut self) -> bool {
         true
     }
@@ -105,6 +122,120 @@ pub trait RootContext: Context {
         true
     }
 
+    /// Parses plugin configuration as JSON.
+    ///
+    /// Returns `Ok(None)` if plugin configuration is empty.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::traits::*;
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Config {
+    ///     header_content: String,
+    /// }
+    ///
+    /// struct ConfigRoot {
+    ///     config: Option<Config>,
+    /// }
+    ///
+    /// impl Context for ConfigRoot {}
+    ///
+    /// impl RootContext for ConfigRoot {
+    ///     fn on_configure(&mut self, _: usize) -> bool {
+    ///         match self.parse_plugin_configuration() {
+    ///             Ok(config) => {
+    ///                 self.config = config;
+    ///                 true
+    ///             }
+    ///             Err(_) => false,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    #[cfg(feature = "serde_json")]
+    fn parse_plugin_configuration<T>(&self) -> Result<Option<T>>
+    where
+        Self: Sized,
+        T: serde::de::DeserializeOwned,
+    {
+        match hostcalls::get_buffer(BufferType::PluginConfiguration, 0, usize::MAX)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses VM and plugin configuration as JSON and merges them into a single value.
+    ///
+    /// Objects are merged key by key, recursively, with plugin configuration taking precedence
+    /// over VM configuration. Any other plugin value, including arrays and `null`, replaces
+    /// the VM value as a whole. If only one of the configurations is present, it is used as is.
+    ///
+    /// Returns `Ok(None)` if both configurations are empty.
+    ///
+    /// Meant to be called from `on_configure`.
+    ///
+    /// # Examples
+    ///
+    /// With VM configuration `{"upstream": {"cluster": "authz", "timeout_ms": 500}}` and
+    /// plugin configuration `{"upstream": {"timeout_ms": 100}}`, the following yields
+    /// a cluster of `"authz"` and a timeout of `100`.
+    ///
+    /// ```no_run
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::traits::*;
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Upstream {
+    ///     cluster: String,
+    ///     timeout_ms: u64,
+    /// }
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Config {
+    ///     upstream: Upstream,
+    /// }
+    ///
+    /// struct ConfigRoot {
+    ///     config: Option<Config>,
+    /// }
+    ///
+    /// impl Context for ConfigRoot {}
+    ///
+    /// impl RootContext for ConfigRoot {
+    ///     fn on_configure(&mut self, _: usize) -> bool {
+    ///         match self.effective_configuration() {
+    ///             Ok(config) => {
+    ///                 self.config = config;
+    ///                 true
+    ///             }
+    ///             Err(_) => false,
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    #[cfg(feature = "serde_json")]
+    fn effective_configuration<T>(&self) -> Result<Option<T>>
+    where
+        Self: Sized,
+        T: serde::de::DeserializeOwned,
+    {
+        let vm_configuration = hostcalls::get_buffer(BufferType::VmConfiguration, 0, usize::MAX)?;
+        let plugin_configuration =
+            hostcalls::get_buffer(BufferType::PluginConfiguration, 0, usize::MAX)?;
+        merge_configurations(
+            vm_configuration.as_ref().map(|bytes| bytes.as_ref()),
+            plugin_configuration.as_ref().map(|bytes| bytes.as_ref()),
+        )
+    }
+
+    /// Sets the period of `on_tick`, see [`hostcalls::set_tick_period`] for its resolution.
+    ///
+    /// [`hostcalls::set_tick_period`]: ../hostcalls/fn.set_tick_period.html
     fn set_tick_period(&self, period: Duration) {
         hostcalls::set_tick_period(period).unwrap()
     }
@@ -128,6 +259,41 @@ pub trait RootContext: Context {
     }
 }
 
+#[cfg(feature = "serde_json")]
+fn merge_configurations<T>(vm: Option<&[u8]>, plugin: Option<&[u8]>) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut merged = vm
+        .map(serde_json::from_slice::<serde_json::Value>)
+        .transpose()?;
+    if let Some(plugin) = plugin {
+        let plugin = serde_json::from_slice(plugin)?;
+        match merged.as_mut() {
+            Some(merged) => merge_json(merged, plugin),
+            None => merged = Some(plugin),
+        }
+    }
+    Ok(merged.map(serde_json::from_value).transpose()?)
+}
+
+#[cfg(feature = "serde_json")]
+fn merge_json(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(base) => merge_json(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
 pub trait StreamContext: Context {
     fn on_new_connection(&mut self) -> Action {
         Action::Continue
@@ -145,6 +311,23 @@ pub trait StreamContext: Context {
         hostcalls::set_buffer(BufferType::DownstreamData, start, size, value).unwrap()
     }
 
+    /// Resumes processing of data received from the downstream peer.
+    ///
+    /// Only the downstream direction is affected; it must have been paused
+    /// by returning [`Action::Pause`] from `on_new_connection` or
+    /// `on_downstream_data`.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
+    fn resume_downstream(&self) {
+        hostcalls::continue_stream(StreamType::Downstream).unwrap()
+    }
+
+    /// Called when the downstream connection is closed.
+    ///
+    /// ABI 0.2.0 only reports which peer closed the connection, not why.
+    /// The reason can be looked up via [`properties::connection_termination_details`].
+    ///
+    /// [`properties::connection_termination_details`]: ../properties/fn.connection_termination_details.html
     fn on_downstream_close(&mut self, _peer_type: PeerType) {}
 
     fn on_upstream_data(&mut self, _data_size: usize, _end_of_stream: bool) -> Action {
@@ -159,16 +342,74 @@ pub trait StreamContext: Context {
         hostcalls::set_buffer(BufferType::UpstreamData, start, size, value).unwrap()
     }
 
+    /// Resumes processing of data received from the upstream peer.
+    ///
+    /// Only the upstream direction is affected; it must have been paused
+    /// by returning [`Action::Pause`] from `on_upstream_data`.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
+    fn resume_upstream(&self) {
+        hostcalls::continue_stream(StreamType::Upstream).unwrap()
+    }
+
+    /// Called when the upstream connection is closed.
+    ///
+    /// ABI 0.2.0 only reports which peer closed the connection, not why.
+    /// The reason can be looked up via [`properties::upstream_transport_failure_reason`].
+    ///
+    /// [`properties::upstream_transport_failure_reason`]: ../properties/fn.upstream_transport_failure_reason.html
     fn on_upstream_close(&mut self, _peer_type: PeerType) {}
 
+    /// Appends `data` to the stream data being processed and closes the stream.
+    ///
+    /// The data goes the same way as the data of the running callback: called from
+    /// `on_upstream_data`, it is sent to the downstream peer, e.g. a goodbye frame for the client;
+    /// called from `on_downstream_data`, it is sent to the upstream peer. Whether pending data is
+    /// flushed before the connection closes is up to the host; Envoy flushes writes to the
+    /// downstream peer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of the downstream and upstream callbacks.
+    fn close_with_data(&self, data: &[u8]) {
+        let (buffer_type, stream_type) = match dispatcher::current_stream_data() {
+            Some(StreamType::Downstream) => (BufferType::DownstreamData, StreamType::Downstream),
+            Some(StreamType::Upstream) => (BufferType::UpstreamData, StreamType::Upstream),
+            _ => {
+                panic!("close_with_data must be called from on_downstream_data or on_upstream_data")
+            }
+        };
+        hostcalls::set_buffer(buffer_type, usize::MAX, 0, data).unwrap();
+        hostcalls::close_stream(stream_type).unwrap()
+    }
+
     fn on_log(&mut self) {}
 }
 
 pub trait HttpContext: Context {
+    /// Called when HTTP request headers are received.
+    ///
+    /// By default, calls [`inspect_http_request_headers`] and continues processing.
+    /// Override this method instead if the filter needs to pause the request.
+    ///
+    /// [`inspect_http_request_headers`]: #method.inspect_http_request_headers
     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        self.inspect_http_request_headers();
         Action::Continue
     }
 
+    /// Called by the default [`on_http_request_headers`] for filters that only
+    /// need to look at or modify the request headers.
+    ///
+    /// [`on_http_request_headers`]: #method.on_http_request_headers
+    fn inspect_http_request_headers(&mut self) {}
+
+    /// Returns `true` once the end of the HTTP request body has been received, i.e. a request
+    /// callback has seen `end_of_stream` or the request trailers arrived.
+    fn http_request_body_complete(&self) -> bool {
+        dispatcher::http_body_complete(StreamType::Request)
+    }
+
     fn get_http_request_headers(&self) -> Vec<(ByteString, ByteString)> {
         hostcalls::get_map(MapType::HttpRequestHeaders).unwrap()
     }
@@ -178,15 +419,94 @@ pub trait HttpContext: Context {
     }
 
     fn get_http_request_header(&self, name: &str) -> Option<ByteString> {
-        hostcalls::get_map_value(MapType::HttpRequestHeaders, &name).unwrap()
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, name).unwrap()
+    }
+
+    /// Returns all values of a given HTTP request header, e.g. every `set-cookie` line.
+    fn get_http_request_header_values(&self, name: &str) -> Vec<ByteString> {
+        hostcalls::get_map_values(MapType::HttpRequestHeaders, name).unwrap()
     }
 
     fn set_http_request_header(&self, name: &str, value: Option<&str>) {
-        hostcalls::set_map_value(MapType::HttpRequestHeaders, &name, value).unwrap()
+        hostcalls::set_map_value(MapType::HttpRequestHeaders, name, value).unwrap()
     }
 
     fn add_http_request_header(&self, name: &str, value: &str) {
-        hostcalls::add_map_value(MapType::HttpRequestHeaders, &name, value).unwrap()
+        hostcalls::add_map_value(MapType::HttpRequestHeaders, name, value).unwrap()
+    }
+
+    /// Returns the `:method` pseudo-header of the HTTP request.
+    fn get_http_request_method(&self) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, PseudoHeader::Method).unwrap()
+    }
+
+    /// Returns the `:scheme` pseudo-header of the HTTP request.
+    fn get_http_request_scheme(&self) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, PseudoHeader::Scheme).unwrap()
+    }
+
+    /// Returns the `:authority` pseudo-header of the HTTP request.
+    fn get_http_request_authority(&self) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, PseudoHeader::Authority).unwrap()
+    }
+
+    /// Returns the `:path` pseudo-header of the HTTP request.
+    fn get_http_request_path(&self) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpRequestHeaders, PseudoHeader::Path).unwrap()
+    }
+
+    /// Returns the scheme and the credentials of the `authorization` header
+    /// of the HTTP request, e.g. `("Bearer", "abc.def")`.
+    ///
+    /// See [`http::parse_authorization`] for parsing rules.
+    ///
+    /// [`http::parse_authorization`]: ../http/fn.parse_authorization.html
+    fn get_authorization(&self) -> Option<(ByteString, ByteString)> {
+        self.get_http_request_header("authorization")
+            .and_then(|value| http::parse_authorization(&value))
+    }
+
+    /// Returns the value of a given cookie sent with the HTTP request.
+    ///
+    /// See [`http::parse_cookies`] for parsing rules.
+    ///
+    /// [`http::parse_cookies`]: ../http/fn.parse_cookies.html
+    fn get_cookie(&self, name: &str) -> Option<ByteString> {
+        self.get_http_request_header("cookie").and_then(|value| {
+            http::parse_cookies(&value)
+                .into_iter()
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, cookie_value)| cookie_value)
+        })
+    }
+
+    /// Replaces the `:method` pseudo-header of the HTTP request.
+    ///
+    /// Note that the host may have already selected a route based on the original method.
+    /// ABI 0.2.0 provides no way to clear the route cache, so the method should be rewritten
+    /// by a filter that runs before routing takes place, or the route must not depend on it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `method` is not a valid HTTP token.
+    fn set_http_request_method(&self, method: &str) {
+        assert!(
+            http::is_token(method.as_bytes()),
+            "invalid HTTP method: {:?}",
+            method
+        );
+        hostcalls::set_map_value(
+            MapType::HttpRequestHeaders,
+            PseudoHeader::Method,
+            Some(method),
+        )
+        .unwrap()
+    }
+
+    /// Replaces the `:path` pseudo-header of the HTTP request.
+    fn set_http_request_path(&self, path: &str) {
+        hostcalls::set_map_value(MapType::HttpRequestHeaders, PseudoHeader::Path, Some(path))
+            .unwrap()
     }
 
     fn on_http_request_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Action {
@@ -197,10 +517,45 @@ pub trait HttpContext: Context {
         hostcalls::get_buffer(BufferType::HttpRequestBody, start, max_size).unwrap()
     }
 
+    /// Returns whatever part of the HTTP request body the host has buffered so far.
+    ///
+    /// Unlike waiting for `on_http_request_body`, this can be called from
+    /// `on_http_request_headers`, e.g. to make an early decision on a small body
+    /// that arrived together with the headers. Returns `None` if no part of the body
+    /// has arrived yet, which does not mean the request has no body; check
+    /// [`http_request_body_complete`] for that.
+    ///
+    /// Returns a [`BufferLimitError`] if the buffered part exceeds [`body::max_body_size`].
+    ///
+    /// [`http_request_body_complete`]: #method.http_request_body_complete
+    /// [`BufferLimitError`]: ../error/struct.BufferLimitError.html
+    /// [`body::max_body_size`]: ../body/fn.max_body_size.html
+    fn try_get_buffered_request_body(&self) -> Result<Option<ByteString>> {
+        body::get_whole_buffer_capped(BufferType::HttpRequestBody)
+    }
+
     fn set_http_request_body(&self, start: usize, size: usize, value: &[u8]) {
         hostcalls::set_buffer(BufferType::HttpRequestBody, start, size, value).unwrap()
     }
 
+    /// Replaces the whole buffered HTTP request body with a given value.
+    ///
+    /// Use [`set_http_request_body`] for partial edits.
+    ///
+    /// [`set_http_request_body`]: #method.set_http_request_body
+    fn replace_http_request_body(&self, value: &[u8]) {
+        hostcalls::set_buffer(BufferType::HttpRequestBody, 0, usize::MAX, value).unwrap()
+    }
+
+    /// Replaces the whole buffered HTTP request body and sets `content-length` to match.
+    ///
+    /// The header can only change while the request headers are still held back by the filter,
+    /// i.e. `on_http_request_headers` has paused the request and the body is being buffered.
+    fn set_http_request_body_and_content_length(&self, value: &[u8]) {
+        self.replace_http_request_body(value);
+        self.set_http_request_header("content-length", Some(&value.len().to_string()))
+    }
+
     fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
         Action::Continue
     }
@@ -214,25 +569,105 @@ pub trait HttpContext: Context {
     }
 
     fn get_http_request_trailer(&self, name: &str) -> Option<ByteString> {
-        hostcalls::get_map_value(MapType::HttpRequestTrailers, &name).unwrap()
+        hostcalls::get_map_value(MapType::HttpRequestTrailers, name).unwrap()
     }
 
     fn set_http_request_trailer(&self, name: &str, value: Option<&str>) {
-        hostcalls::set_map_value(MapType::HttpRequestTrailers, &name, value).unwrap()
+        hostcalls::set_map_value(MapType::HttpRequestTrailers, name, value).unwrap()
     }
 
     fn add_http_request_trailer(&self, name: &str, value: &str) {
-        hostcalls::add_map_value(MapType::HttpRequestTrailers, &name, value).unwrap()
+        hostcalls::add_map_value(MapType::HttpRequestTrailers, name, value).unwrap()
     }
 
+    /// Resumes processing of the HTTP request.
+    ///
+    /// The request must have been paused by returning [`Action::Pause`] from one
+    /// of the `on_http_request_*` callbacks. The response direction is not affected,
+    /// so e.g. the request can be resumed from `on_http_response_headers`.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
     fn resume_http_request(&self) {
         hostcalls::continue_stream(StreamType::Request).unwrap()
     }
 
+    /// Called instead of [`on_http_call_response`] for HTTP calls dispatched by this context.
+    ///
+    /// Returning [`HttpCallAction::Resume`] makes the dispatcher continue the request or
+    /// the response, whichever was being processed when the call was dispatched, so that
+    /// the common "pause, dispatch, resume" pattern cannot leave the stream hanging.
+    /// A call dispatched while handling the response of another call resumes the same
+    /// direction as that call. Calls dispatched from other callbacks, e.g. `on_log`,
+    /// have nothing to resume, so [`HttpCallAction::Resume`] only logs a warning for them.
+    ///
+    /// By default, calls [`on_http_call_response`] and keeps the stream paused.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::traits::*;
+    /// use proxy_wasm::types::*;
+    ///
+    /// struct Authz;
+    ///
+    /// impl Context for Authz {}
+    ///
+    /// impl HttpContext for Authz {
+    ///     fn on_http_call_response_resumable(
+    ///         &mut self,
+    ///         _token_id: u32,
+    ///         _num_headers: usize,
+    ///         _body_size: usize,
+    ///         _num_trailers: usize,
+    ///     ) -> HttpCallAction {
+    ///         match self.get_http_call_response_header(":status") {
+    ///             Some(status) if status == "200" => HttpCallAction::Resume,
+    ///             _ => {
+    ///                 self.send_http_response(403, vec![], None);
+    ///                 HttpCallAction::KeepPaused
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// [`on_http_call_response`]: trait.Context.html#method.on_http_call_response
+    /// [`HttpCallAction::Resume`]: ../types/enum.HttpCallAction.html#variant.Resume
+    fn on_http_call_response_resumable(
+        &mut self,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    ) -> HttpCallAction {
+        self.on_http_call_response(token_id, num_headers, body_size, num_trailers);
+        HttpCallAction::KeepPaused
+    }
+
+    /// Called when HTTP response headers are received.
+    ///
+    /// By default, calls [`inspect_http_response_headers`] and continues processing.
+    /// Override this method instead if the filter needs to pause the response.
+    ///
+    /// [`inspect_http_response_headers`]: #method.inspect_http_response_headers
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        self.inspect_http_response_headers();
         Action::Continue
     }
 
+    /// Called by the default [`on_http_response_headers`] for filters that only
+    /// need to look at or modify the response headers.
+    ///
+    /// [`on_http_response_headers`]: #method.on_http_response_headers
+    fn inspect_http_response_headers(&mut self) {}
+
+    /// Returns `true` once the end of the HTTP response body has been received, i.e. a response
+    /// callback has seen `end_of_stream` or the response trailers arrived.
+    fn http_response_body_complete(&self) -> bool {
+        dispatcher::http_body_complete(StreamType::Response)
+    }
+
     fn get_http_response_headers(&self) -> Vec<(ByteString, ByteString)> {
         hostcalls::get_map(MapType::HttpResponseHeaders).unwrap()
     }
@@ -242,15 +677,44 @@ pub trait HttpContext: Context {
     }
 
     fn get_http_response_header(&self, name: &str) -> Option<ByteString> {
-        hostcalls::get_map_value(MapType::HttpResponseHeaders, &name).unwrap()
+        hostcalls::get_map_value(MapType::HttpResponseHeaders, name).unwrap()
+    }
+
+    /// Returns all values of a given HTTP response header, e.g. every `set-cookie` line.
+    fn get_http_response_header_values(&self, name: &str) -> Vec<ByteString> {
+        hostcalls::get_map_values(MapType::HttpResponseHeaders, name).unwrap()
     }
 
     fn set_http_response_header(&self, name: &str, value: Option<&str>) {
-        hostcalls::set_map_value(MapType::HttpResponseHeaders, &name, value).unwrap()
+        hostcalls::set_map_value(MapType::HttpResponseHeaders, name, value).unwrap()
     }
 
     fn add_http_response_header(&self, name: &str, value: &str) {
-        hostcalls::add_map_value(MapType::HttpResponseHeaders, &name, value).unwrap()
+        hostcalls::add_map_value(MapType::HttpResponseHeaders, name, value).unwrap()
+    }
+
+    /// Returns the `:status` pseudo-header of the HTTP response.
+    fn get_http_response_status(&self) -> Option<ByteString> {
+        hostcalls::get_map_value(MapType::HttpResponseHeaders, PseudoHeader::Status).unwrap()
+    }
+
+    /// Replaces the `:status` pseudo-header of the HTTP response.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `code` is not in the `100..=599` range.
+    fn set_http_response_status(&self, code: u16) {
+        assert!(
+            (100..=599).contains(&code),
+            "invalid HTTP status code: {}",
+            code
+        );
+        hostcalls::set_map_value(
+            MapType::HttpResponseHeaders,
+            PseudoHeader::Status,
+            Some(code.to_string()),
+        )
+        .unwrap()
     }
 
     fn on_http_response_body(&mut self, _body_size: usize, _end_of_stream: bool) -> Action {
@@ -265,6 +729,26 @@ pub trait HttpContext: Context {
         hostcalls::set_buffer(BufferType::HttpResponseBody, start, size, value).unwrap()
     }
 
+    /// Replaces the whole buffered HTTP response body with a given value.
+    ///
+    /// Use [`set_http_response_body`] for partial edits.
+    ///
+    /// [`set_http_response_body`]: #method.set_http_response_body
+    fn replace_http_response_body(&self, value: &[u8]) {
+        hostcalls::set_buffer(BufferType::HttpResponseBody, 0, usize::MAX, value).unwrap()
+    }
+
+    /// Replaces the whole buffered HTTP response body and sets `content-length` to match.
+    ///
+    /// The header can only change while the response headers are still held back by the filter,
+    /// i.e. `on_http_response_headers` has paused the response and the body is being buffered.
+    /// Otherwise the client receives the original length and either waits for more data
+    /// or gets a truncated body.
+    fn set_http_response_body_and_content_length(&self, value: &[u8]) {
+        self.replace_http_response_body(value);
+        self.set_http_response_header("content-length", Some(&value.len().to_string()))
+    }
+
     fn on_http_response_trailers(&mut self, _num_trailers: usize) -> Action {
         Action::Continue
     }
@@ -278,21 +762,48 @@ pub trait HttpContext: Context {
     }
 
     fn get_http_response_trailer(&self, name: &str) -> Option<ByteString> {
-        hostcalls::get_map_value(MapType::HttpResponseTrailers, &name).unwrap()
+        hostcalls::get_map_value(MapType::HttpResponseTrailers, name).unwrap()
     }
 
+    /// Sets or, if `value` is `None`, removes an HTTP response trailer.
+    ///
+    /// Trailers can be modified from [`on_http_response_trailers`]. If the response
+    /// has no trailers, hosts such as Envoy let the filter add them from
+    /// [`on_http_response_body`] once `end_of_stream` is `true`, e.g. to append `grpc-status`.
+    ///
+    /// [`on_http_response_trailers`]: #method.on_http_response_trailers
+    /// [`on_http_response_body`]: #method.on_http_response_body
     fn set_http_response_trailer(&self, name: &str, value: Option<&str>) {
-        hostcalls::set_map_value(MapType::HttpResponseTrailers, &name, value).unwrap()
+        hostcalls::set_map_value(MapType::HttpResponseTrailers, name, value).unwrap()
     }
 
+    /// Adds an HTTP response trailer, keeping existing values of the same name.
+    ///
+    /// See [`set_http_response_trailer`] for when trailers can be modified.
+    ///
+    /// [`set_http_response_trailer`]: #method.set_http_response_trailer
     fn add_http_response_trailer(&self, name: &str, value: &str) {
-        hostcalls::add_map_value(MapType::HttpResponseTrailers, &name, value).unwrap()
+        hostcalls::add_map_value(MapType::HttpResponseTrailers, name, value).unwrap()
     }
 
+    /// Resumes processing of the HTTP response.
+    ///
+    /// The response must have been paused by returning [`Action::Pause`] from one
+    /// of the `on_http_response_*` callbacks. The request direction is not affected.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
     fn resume_http_response(&self) {
         hostcalls::continue_stream(StreamType::Response).unwrap()
     }
 
+    /// Sends a local HTTP response instead of proxying the stream further.
+    ///
+    /// The callback that sends a local response must stop iteration by returning
+    /// [`Action::Pause`], otherwise some hosts keep proxying the original stream and fail
+    /// on a second response. Prefer [`deny`], which does both.
+    ///
+    /// [`Action::Pause`]: ../types/enum.Action.html#variant.Pause
+    /// [`deny`]: #method.deny
     fn send_http_response(
         &self,
         status_code: u32,
@@ -302,5 +813,268 @@ pub trait HttpContext: Context {
         hostcalls::send_http_response(status_code, &headers, body).unwrap()
     }
 
+    /// Sends a local HTTP response and returns the [`Action`] to return from the callback.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use proxy_wasm_experimental as proxy_wasm;
+    /// use proxy_wasm::traits::*;
+    /// use proxy_wasm::types::*;
+    ///
+    /// struct Authz;
+    ///
+    /// impl Context for Authz {}
+    ///
+    /// impl HttpContext for Authz {
+    ///     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+    ///         match self.get_http_request_header("authorization") {
+    ///             Some(_) => Action::Continue,
+    ///             None => self.deny(401, vec![("www-authenticate", "Bearer")], None),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// [`Action`]: ../types/enum.Action.html
+    fn deny(&self, status_code: u32, headers: Vec<(&str, &str)>, body: Option<&[u8]>) -> Action {
+        self.send_http_response(status_code, headers, body);
+        Action::Pause
+    }
+
+    /// Sends a redirect to a given location and returns the [`Action`] to return
+    /// from the callback, like [`deny`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `status_code` is not in the `300..=399` range.
+    ///
+    /// [`Action`]: ../types/enum.Action.html
+    /// [`deny`]: #method.deny
+    fn send_redirect(&self, status_code: u16, location: &str) -> Action {
+        assert!(
+            (300..=399).contains(&status_code),
+            "invalid HTTP redirect status code: {}",
+            status_code
+        );
+        self.deny(status_code as u32, vec![("location", location)], None)
+    }
+
     fn on_log(&mut self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static WRITTEN: RefCell<Vec<(BufferType, usize, Vec<u8>)>> = const { RefCell::new(Vec::new()) };
+        static CLOSED: RefCell<Vec<StreamType>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_set_buffer_bytes(
+        buffer_type: BufferType,
+        start: usize,
+        _size: usize,
+        buffer_data: *const u8,
+        buffer_size: usize,
+    ) -> RawStatus {
+        let data = unsafe { std::slice::from_raw_parts(buffer_data, buffer_size) };
+        WRITTEN.with(|written| {
+            written
+                .borrow_mut()
+                .push((buffer_type, start, data.to_vec()))
+        });
+        Status::Ok.into()
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_close_stream(stream_type: StreamType) -> RawStatus {
+        CLOSED.with(|closed| closed.borrow_mut().push(stream_type));
+        Status::Ok.into()
+    }
+
+    #[derive(Default)]
+    struct Inspector {
+        requests: usize,
+        responses: usize,
+    }
+
+    impl Context for Inspector {}
+    impl HttpContext for Inspector {
+        fn inspect_http_request_headers(&mut self) {
+            self.requests += 1;
+        }
+
+        fn inspect_http_response_headers(&mut self) {
+            self.responses += 1;
+        }
+    }
+
+    struct GoodbyeStream;
+
+    impl Context for GoodbyeStream {}
+    impl StreamContext for GoodbyeStream {
+        fn on_upstream_data(&mut self, _data_size: usize, _end_of_stream: bool) -> Action {
+            self.close_with_data(b"bye");
+            Action::Continue
+        }
+    }
+
+    struct RewritingHttp;
+
+    impl Context for RewritingHttp {}
+    impl HttpContext for RewritingHttp {
+        fn on_http_response_headers(
+            &mut self,
+            _num_headers: usize,
+            _end_of_stream: bool,
+        ) -> Action {
+            // hold the headers back until the new content-length is known
+            Action::Pause
+        }
+
+        fn on_http_response_body(&mut self, _body_size: usize, end_of_stream: bool) -> Action {
+            if !end_of_stream {
+                return Action::PauseAndBuffer;
+            }
+            self.set_http_response_body_and_content_length(b"rewritten");
+            Action::Continue
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "serde_json")]
+    fn test_merge_configurations() {
+        use serde_json::{json, Value};
+
+        let vm = br#"{"upstream": {"cluster": "authz", "timeout_ms": 500}, "tags": ["a"]}"#;
+        let plugin = br#"{"upstream": {"timeout_ms": 100}, "tags": ["b"], "debug": true}"#;
+        let merged: Value = merge_configurations(Some(vm), Some(plugin))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            merged,
+            json!({
+                "upstream": {"cluster": "authz", "timeout_ms": 100},
+                "tags": ["b"],
+                "debug": true,
+            })
+        );
+
+        let merged: Value = merge_configurations(Some(vm), None).unwrap().unwrap();
+        assert_eq!(merged["upstream"]["timeout_ms"], 500);
+        let merged: Value = merge_configurations(None, Some(plugin)).unwrap().unwrap();
+        assert_eq!(merged["upstream"], json!({"timeout_ms": 100}));
+        assert!(merge_configurations::<Value>(None, None).unwrap().is_none());
+        assert!(merge_configurations::<Value>(Some(b"{"), Some(plugin)).is_err());
+    }
+
+    #[test]
+    fn test_inspect_http_headers() {
+        let mut inspector = Inspector::default();
+        assert_eq!(
+            inspector.on_http_request_headers(0, false),
+            Action::Continue
+        );
+        assert_eq!(
+            inspector.on_http_response_headers(0, true),
+            Action::Continue
+        );
+        assert_eq!(inspector.requests, 1);
+        assert_eq!(inspector.responses, 1);
+    }
+
+    #[no_mangle]
+    extern "C" fn proxy_send_local_response(
+        _status_code: u32,
+        _status_code_details_data: *const u8,
+        _status_code_details_size: usize,
+        _body_data: *const u8,
+        _body_size: usize,
+        _headers_data: *const u8,
+        _headers_size: usize,
+        _grpc_status: i32,
+    ) -> RawStatus {
+        Status::Ok.into()
+    }
+
+    #[test]
+    fn test_deny_pauses() {
+        let inspector = Inspector::default();
+        assert_eq!(inspector.deny(403, vec![], Some(b"denied")), Action::Pause);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid HTTP method: \"GET /\"")]
+    fn test_set_http_request_method_invalid() {
+        Inspector::default().set_http_request_method("GET /");
+    }
+
+    #[test]
+    fn test_send_redirect() {
+        let inspector = Inspector::default();
+        assert_eq!(inspector.send_redirect(302, "/login"), Action::Pause);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid HTTP redirect status code: 200")]
+    fn test_send_redirect_invalid_status() {
+        Inspector::default().send_redirect(200, "/login");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid HTTP status code: 600")]
+    fn test_set_http_response_status_out_of_range() {
+        Inspector::default().set_http_response_status(600);
+    }
+
+    #[test]
+    fn test_close_with_data() {
+        crate::set_stream_context(|_, _| Box::new(GoodbyeStream));
+        dispatcher::proxy_on_context_create(1, 0);
+        dispatcher::proxy_on_context_create(2, 1);
+        assert_eq!(dispatcher::current_stream_data(), None);
+
+        dispatcher::proxy_on_upstream_data(2, 10, false);
+        assert_eq!(dispatcher::current_stream_data(), None);
+        WRITTEN.with(|written| {
+            assert_eq!(
+                *written.borrow(),
+                vec![(BufferType::UpstreamData, usize::MAX, b"bye".to_vec())]
+            )
+        });
+        CLOSED.with(|closed| assert_eq!(*closed.borrow(), vec![StreamType::Upstream]));
+    }
+
+    #[test]
+    fn test_set_http_response_body_and_content_length() {
+        crate::set_http_context(|_, _| Box::new(RewritingHttp));
+        dispatcher::proxy_on_context_create(1, 0);
+        dispatcher::proxy_on_context_create(2, 1);
+
+        assert_eq!(
+            dispatcher::proxy_on_response_headers(2, 0, false),
+            Action::Pause.headers_status()
+        );
+        dispatcher::proxy_on_response_body(2, 100, true);
+        WRITTEN.with(|written| {
+            assert_eq!(
+                *written.borrow(),
+                vec![(BufferType::HttpResponseBody, 0, b"rewritten".to_vec())]
+            )
+        });
+        hostcalls::tests::REPLACED.with(|replaced| {
+            assert_eq!(
+                *replaced.borrow(),
+                vec![(
+                    MapType::HttpResponseHeaders,
+                    "content-length".to_owned(),
+                    "9".to_owned()
+                )]
+            )
+        });
+    }
+}
@@ -12,12 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "std")]
 use crate::traits::*;
+#[cfg(feature = "std")]
+use alloc::boxed::Box;
+use core::fmt;
 
-pub use crate::bytestring::ByteString;
+#[cfg(feature = "percent-encoding")]
+pub use crate::bytestring::EncodeSet;
+pub use crate::bytestring::{ByteStr, ByteString};
+pub use crate::codec::{decode_map, encode_map};
 
+/// A [`hashbrown::HashMap`] keyed by [`ByteString`], e.g. for storing headers.
+///
+/// Entries can be looked up by `&ByteStr` or `&[u8]` without allocating.
+///
+/// # Examples
+///
+/// ```
+/// # use proxy_wasm_experimental as proxy_wasm;
+/// use proxy_wasm::types::{ByteString, ByteStringMap};
+///
+/// let mut headers = ByteStringMap::default();
+/// headers.insert(ByteString::from("x-tenant"), ByteString::from("acme"));
+/// assert_eq!(headers.get(&b"x-tenant"[..]).unwrap(), "acme");
+/// ```
+///
+/// [`hashbrown::HashMap`]: https://docs.rs/hashbrown/0.11/hashbrown/hash_map/struct.HashMap.html
+/// [`ByteString`]: struct.ByteString.html
+pub type ByteStringMap<V> = hashbrown::HashMap<ByteString, V>;
+
+#[cfg(feature = "std")]
 pub type NewRootContext = fn(context_id: u32) -> Box<dyn RootContext>;
+#[cfg(feature = "std")]
 pub type NewStreamContext = fn(context_id: u32, root_context_id: u32) -> Box<dyn StreamContext>;
+#[cfg(feature = "std")]
 pub type NewHttpContext = fn(context_id: u32, root_context_id: u32) -> Box<dyn HttpContext>;
 
 #[repr(u32)]
@@ -31,24 +60,202 @@ pub enum LogLevel {
     Critical = 5,
 }
 
+/// Tells the host whether to keep processing the current direction of a stream.
+///
+/// Returning [`Action::Pause`] from a callback pauses only the direction that
+/// callback belongs to, e.g. pausing in `on_http_request_headers` holds back the
+/// request while the response, once available, keeps flowing. A paused direction
+/// stays paused until it's resumed explicitly via [`hostcalls::continue_stream`].
+///
+/// Besides plain [`Action::Pause`], hosts support finer-grained variants.
+/// Each callback only understands some of them; the others are mapped to the
+/// closest supported behavior:
+///
+/// | Variant                | headers                        | body                        | trailers, connection, data |
+/// |------------------------|--------------------------------|-----------------------------|----------------------------|
+/// | `Continue`             | `Continue`                     | `Continue`                  | `Continue`                 |
+/// | `Pause`                | `StopIteration`                | `StopIterationAndBuffer`    | `StopIteration`            |
+/// | `ContinueAndEndStream` | `ContinueAndEndStream`         | `Continue`                  | `Continue`                 |
+/// | `PauseAndBuffer`       | `StopAllIterationAndBuffer`    | `StopIterationAndBuffer`    | `StopIteration`            |
+/// | `PauseAndWatermark`    | `StopAllIterationAndWatermark` | `StopIterationAndWatermark` | `StopIteration`            |
+/// | `PauseNoBuffer`        | `StopIteration`                | `StopIterationNoBuffer`     | `StopIteration`            |
+///
+/// Here, connection and data refer to the stream callbacks, e.g. `on_downstream_data`,
+/// which the host treats as a network filter that can only continue or stop iteration.
+/// With the `strict` feature, returning a variant that a callback maps to a different
+/// behavior, e.g. `ContinueAndEndStream` from a body callback, fails a debug assertion.
+///
+/// [`Action::Pause`]: #variant.Pause
+/// [`hostcalls::continue_stream`]: ../hostcalls/fn.continue_stream.html
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Action {
     Continue = 0,
     Pause = 1,
+    /// Continues processing, but ends the stream after the headers, dropping any body.
+    ContinueAndEndStream = 2,
+    /// Pauses processing and buffers the rest of the stream, up to the host's buffer limit.
+    PauseAndBuffer = 3,
+    /// Pauses processing and buffers the rest of the stream, applying flow control
+    /// once the host's buffer limit is reached.
+    PauseAndWatermark = 4,
+    /// Pauses processing without buffering the current chunk of data.
+    PauseNoBuffer = 5,
 }
 
-#[repr(u32)]
+#[cfg(feature = "std")]
+impl Action {
+    pub(crate) fn headers_status(self) -> u32 {
+        match self {
+            Action::Continue => 0,
+            Action::Pause | Action::PauseNoBuffer => 1,
+            Action::ContinueAndEndStream => 2,
+            Action::PauseAndBuffer => 3,
+            Action::PauseAndWatermark => 4,
+        }
+    }
+
+    pub(crate) fn data_status(self) -> u32 {
+        match self {
+            Action::Continue | Action::ContinueAndEndStream => 0,
+            Action::Pause | Action::PauseAndBuffer => 1,
+            Action::PauseAndWatermark => 2,
+            Action::PauseNoBuffer => 3,
+        }
+    }
+
+    pub(crate) fn trailers_status(self) -> u32 {
+        match self {
+            Action::Continue | Action::ContinueAndEndStream => 0,
+            Action::Pause
+            | Action::PauseAndBuffer
+            | Action::PauseAndWatermark
+            | Action::PauseNoBuffer => 1,
+        }
+    }
+
+    /// Maps to the filter status of a network (connection-level) callback.
+    pub(crate) fn network_status(self) -> u32 {
+        match self {
+            Action::Continue | Action::ContinueAndEndStream => 0,
+            Action::Pause
+            | Action::PauseAndBuffer
+            | Action::PauseAndWatermark
+            | Action::PauseNoBuffer => 1,
+        }
+    }
+}
+
+/// Tells the dispatcher what to do with the stream that dispatched an HTTP call
+/// once its response has been handled.
+///
+/// See [`HttpContext::on_http_call_response_resumable`].
+///
+/// [`HttpContext::on_http_call_response_resumable`]: ../traits/trait.HttpContext.html#method.on_http_call_response_resumable
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum HttpCallAction {
+    /// Continues the direction of the stream, request or response, that dispatched the call.
+    Resume,
+    /// Leaves the stream as it is, e.g. because it has been resumed or closed already.
+    KeepPaused,
+}
+
+/// Status of a Host ABI call.
+///
+/// Codes the crate doesn't know about, e.g. ones introduced by a newer host,
+/// are kept as [`Unknown`] rather than trusted to be valid enum values.
+///
+/// [`Unknown`]: #variant.Unknown
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum Status {
-    Ok = 0,
-    NotFound = 1,
-    BadArgument = 2,
-    Empty = 7,
-    CasMismatch = 8,
-    InternalFailure = 10,
+    Ok,
+    NotFound,
+    BadArgument,
+    Empty,
+    CasMismatch,
+    InternalFailure,
+    /// A status code that is not one of the above.
+    Unknown(u32),
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Ok => "Ok",
+            Status::NotFound => "NotFound",
+            Status::BadArgument => "BadArgument",
+            Status::Empty => "Empty",
+            Status::CasMismatch => "CasMismatch",
+            Status::InternalFailure => "InternalFailure",
+            Status::Unknown(_) => "Unknown",
+        }
+    }
+
+    /// Returns the status code as defined by the Host ABI.
+    pub fn code(&self) -> u32 {
+        match *self {
+            Status::Ok => 0,
+            Status::NotFound => 1,
+            Status::BadArgument => 2,
+            Status::Empty => 7,
+            Status::CasMismatch => 8,
+            Status::InternalFailure => 10,
+            Status::Unknown(code) => code,
+        }
+    }
+}
+
+impl From<u32> for Status {
+    /// Converts a status code as defined by the Host ABI, falling back to
+    /// [`Status::Unknown`] for codes the crate doesn't know about.
+    ///
+    /// [`Status::Unknown`]: enum.Status.html#variant.Unknown
+    fn from(code: u32) -> Self {
+        match code {
+            0 => Status::Ok,
+            1 => Status::NotFound,
+            2 => Status::BadArgument,
+            7 => Status::Empty,
+            8 => Status::CasMismatch,
+            10 => Status::InternalFailure,
+            code => Status::Unknown(code),
+        }
+    }
+}
+
+impl From<Status> for u32 {
+    fn from(status: Status) -> Self {
+        status.code()
+    }
+}
+
+/// A status code exactly as returned by the host, before it is converted into [`Status`].
+///
+/// [`Status`]: enum.Status.html
+#[repr(transparent)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct RawStatus(u32);
+
+impl From<RawStatus> for Status {
+    fn from(status: RawStatus) -> Self {
+        Status::from(status.0)
+    }
+}
+
+impl From<Status> for RawStatus {
+    fn from(status: Status) -> Self {
+        RawStatus(status.code())
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
+impl core::error::Error for Status {}
+
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum ContextType {
@@ -56,6 +263,22 @@ pub enum ContextType {
     StreamContext = 1,
 }
 
+/// Buffers exposed by the host.
+///
+/// Each buffer is only populated while the host dispatches a matching callback:
+///
+/// | Buffer                 | Callback                                                            |
+/// |------------------------|---------------------------------------------------------------------|
+/// | `HttpRequestBody`      | `on_http_request_{headers,body,trailers}`, `on_http_call_response`  |
+/// | `HttpResponseBody`     | `on_http_response_{headers,body,trailers}`, `on_http_call_response` |
+/// | `DownstreamData`       | `on_new_connection`, `on_downstream_{data,close}`                   |
+/// | `UpstreamData`         | `on_upstream_{data,close}`                                          |
+/// | `HttpCallResponseBody` | `on_http_call_response`                                             |
+/// | `VmConfiguration`      | `on_vm_start`, `on_configure`                                       |
+/// | `PluginConfiguration`  | `on_configure`                                                      |
+///
+/// Reading a buffer from any other callback yields no data rather than an error.
+/// With the `strict` feature enabled, debug builds assert on such mismatches instead.
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum BufferType {
@@ -70,6 +293,49 @@ pub enum BufferType {
     CallData = 8,             // Immutable
 }
 
+impl BufferType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BufferType::HttpRequestBody => "HttpRequestBody",
+            BufferType::HttpResponseBody => "HttpResponseBody",
+            BufferType::DownstreamData => "DownstreamData",
+            BufferType::UpstreamData => "UpstreamData",
+            BufferType::HttpCallResponseBody => "HttpCallResponseBody",
+            BufferType::GrpcReceiveBuffer => "GrpcReceiveBuffer",
+            BufferType::VmConfiguration => "VmConfiguration",
+            BufferType::PluginConfiguration => "PluginConfiguration",
+            BufferType::CallData => "CallData",
+        }
+    }
+
+    /// Returns `true` for the body of an HTTP request or response.
+    pub fn is_http_body(&self) -> bool {
+        matches!(
+            self,
+            BufferType::HttpRequestBody | BufferType::HttpResponseBody
+        )
+    }
+
+    /// Returns `true` for the data of a downstream or upstream connection.
+    pub fn is_stream_data(&self) -> bool {
+        matches!(self, BufferType::DownstreamData | BufferType::UpstreamData)
+    }
+
+    /// Returns `true` for the configuration of a VM or plugin.
+    pub fn is_configuration(&self) -> bool {
+        matches!(
+            self,
+            BufferType::VmConfiguration | BufferType::PluginConfiguration
+        )
+    }
+}
+
+impl fmt::Display for BufferType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum MapType {
@@ -83,6 +349,58 @@ pub enum MapType {
     HttpCallResponseTrailers = 7,    // Immutable
 }
 
+impl MapType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapType::HttpRequestHeaders => "HttpRequestHeaders",
+            MapType::HttpRequestTrailers => "HttpRequestTrailers",
+            MapType::HttpResponseHeaders => "HttpResponseHeaders",
+            MapType::HttpResponseTrailers => "HttpResponseTrailers",
+            MapType::GrpcReceiveInitialMetadata => "GrpcReceiveInitialMetadata",
+            MapType::GrpcReceiveTrailingMetadata => "GrpcReceiveTrailingMetadata",
+            MapType::HttpCallResponseHeaders => "HttpCallResponseHeaders",
+            MapType::HttpCallResponseTrailers => "HttpCallResponseTrailers",
+        }
+    }
+
+    /// Returns `true` for the headers and trailers of the HTTP request.
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            MapType::HttpRequestHeaders | MapType::HttpRequestTrailers
+        )
+    }
+
+    /// Returns `true` for the headers and trailers of the HTTP response
+    /// or of the response to an HTTP call.
+    pub fn is_response(&self) -> bool {
+        matches!(
+            self,
+            MapType::HttpResponseHeaders
+                | MapType::HttpResponseTrailers
+                | MapType::HttpCallResponseHeaders
+                | MapType::HttpCallResponseTrailers
+        )
+    }
+
+    /// Returns `true` for trailers, including gRPC trailing metadata.
+    pub fn is_trailers(&self) -> bool {
+        matches!(
+            self,
+            MapType::HttpRequestTrailers
+                | MapType::HttpResponseTrailers
+                | MapType::HttpCallResponseTrailers
+                | MapType::GrpcReceiveTrailingMetadata
+        )
+    }
+}
+
+impl fmt::Display for MapType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum PeerType {
@@ -91,11 +409,46 @@ pub enum PeerType {
     Remote = 2,
 }
 
+impl PeerType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PeerType::Unknown => "Unknown",
+            PeerType::Local => "Local",
+            PeerType::Remote => "Remote",
+        }
+    }
+}
+
+impl fmt::Display for PeerType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[repr(u32)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum StreamType {
     Request = 0,
     Response = 1,
+    Downstream = 2,
+    Upstream = 3,
+}
+
+impl StreamType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamType::Request => "Request",
+            StreamType::Response => "Response",
+            StreamType::Downstream => "Downstream",
+            StreamType::Upstream => "Upstream",
+        }
+    }
+}
+
+impl fmt::Display for StreamType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
 }
 
 #[repr(u32)]
@@ -105,3 +458,56 @@ pub enum MetricType {
     Gauge = 1,
     Histogram = 2,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        for code in 0..16 {
+            assert_eq!(Status::from(code).code(), code);
+        }
+        assert_eq!(Status::from(8), Status::CasMismatch);
+        assert_eq!(Status::from(12), Status::Unknown(12));
+        assert_eq!(Status::from(12).name(), "Unknown");
+        assert_eq!(u32::from(Status::InternalFailure), 10);
+        assert_eq!(Status::from(RawStatus(u32::MAX)), Status::Unknown(u32::MAX));
+    }
+
+    #[test]
+    fn test_map_type_predicates() {
+        assert!(MapType::HttpRequestTrailers.is_request());
+        assert!(MapType::HttpRequestTrailers.is_trailers());
+        assert!(!MapType::HttpRequestHeaders.is_response());
+        assert!(MapType::HttpCallResponseHeaders.is_response());
+        assert!(!MapType::HttpCallResponseHeaders.is_trailers());
+        assert!(!MapType::GrpcReceiveInitialMetadata.is_request());
+        assert!(!MapType::GrpcReceiveInitialMetadata.is_response());
+        assert!(MapType::GrpcReceiveTrailingMetadata.is_trailers());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_action_statuses() {
+        assert_eq!(Action::Continue.headers_status(), 0);
+        assert_eq!(Action::Pause.headers_status(), 1);
+        assert_eq!(Action::ContinueAndEndStream.headers_status(), 2);
+        assert_eq!(Action::PauseAndBuffer.headers_status(), 3);
+        assert_eq!(Action::PauseAndWatermark.headers_status(), 4);
+
+        assert_eq!(Action::ContinueAndEndStream.data_status(), 0);
+        assert_eq!(Action::Pause.data_status(), 1);
+        assert_eq!(Action::PauseAndBuffer.data_status(), 1);
+        assert_eq!(Action::PauseAndWatermark.data_status(), 2);
+        assert_eq!(Action::PauseNoBuffer.data_status(), 3);
+
+        assert_eq!(Action::ContinueAndEndStream.trailers_status(), 0);
+        assert_eq!(Action::PauseAndWatermark.trailers_status(), 1);
+
+        assert_eq!(Action::Continue.network_status(), 0);
+        assert_eq!(Action::Pause.network_status(), 1);
+        assert_eq!(Action::PauseAndWatermark.network_status(), 1);
+        assert_eq!(Action::PauseNoBuffer.network_status(), 1);
+    }
+}